@@ -0,0 +1,212 @@
+//Compares the inline `Value` representation against plain `BigInt` on the arithmetic the example scripts do,
+//then runs the scripts themselves with each representation, and through the interpreter. Run with `cargo bench`
+use std::{
+    fmt::Display,
+    io::{self, Write},
+    ops::{Add, Sub},
+    time::{Duration, Instant},
+};
+
+use num::{bigint::BigInt, ToPrimitive};
+use staq_lang_parser::{
+    interpreter::{run_program, tokenize, RunOptions},
+    optimize::optimize,
+    token::{Program, TokenType},
+    value::{NumericModel, Value},
+    vfs::VirtualFileSystem,
+};
+
+///A stack value the benchmarks can run with. `BigInt` is the baseline, which is how the interpreter stored values before `Value`
+trait Number:
+    Clone + Display + From<i64> + Add<Output = Self> + Sub<Output = Self> + PartialOrd
+{
+    fn constant(n: &BigInt) -> Self;
+    fn to_byte(&self) -> Option<u8>;
+}
+
+impl Number for BigInt {
+    fn constant(n: &BigInt) -> Self {
+        n.clone()
+    }
+
+    fn to_byte(&self) -> Option<u8> {
+        self.to_u8()
+    }
+}
+
+impl Number for Value {
+    fn constant(n: &BigInt) -> Self {
+        Value::from(n)
+    }
+
+    fn to_byte(&self) -> Option<u8> {
+        self.to_u8()
+    }
+}
+
+///The per-iteration work of fibonnaci.stq: add the two newest values, then decrement and test the loop counter
+fn fibonacci_kernel<T: Number>(iterations: i64) -> T {
+    let (mut older, mut newer) = (T::from(1), T::from(1));
+    let mut counter = T::from(iterations);
+    while counter > T::from(0) {
+        let next = older + newer.clone();
+        older = newer;
+        newer = next;
+        counter = counter - T::from(1);
+    }
+    newer
+}
+
+///The per-iteration work of tribonacci.stq's loop counter, which stays small for all 500000 iterations
+fn counter_kernel<T: Number>(iterations: i64) -> T {
+    let mut counter = T::from(iterations);
+    let mut sum = T::from(0);
+    while counter.clone() > T::from(0) {
+        sum = sum + counter.clone();
+        counter = counter - T::from(1);
+    }
+    sum
+}
+
+///Runs a resolved program like the interpreter does, but without its tracing, profiling and files, so only the stack values differ.
+/// Only the tokens the benchmarked scripts use are supported
+fn run_tokens<T: Number>(program: &Program, output: &mut impl Write) {
+    let tokens = &program.tokens;
+    let mut stacks: [Vec<T>; 3] = [Vec::new(), Vec::new(), Vec::new()];
+    //Like the interpreter, popping an empty stack gives 0
+    let pop = |stack: &mut Vec<T>| stack.pop().unwrap_or_else(|| T::from(0));
+
+    let mut i = 0;
+    while i < tokens.len() {
+        match &tokens[i] {
+            TokenType::Exit => break,
+            TokenType::Clear => stacks[2].clear(),
+            TokenType::Label { .. } => (),
+            TokenType::Push { arg } => stacks[2].push(T::constant(arg)),
+            TokenType::Pop { arg } => {
+                pop(&mut stacks[*arg as usize]);
+            }
+            TokenType::Move { arg } => {
+                let n = pop(&mut stacks[arg[0] as usize]);
+                stacks[arg[1] as usize].push(n);
+            }
+            TokenType::Copy { arg } => {
+                let n = pop(&mut stacks[arg[0] as usize]);
+                stacks[arg[0] as usize].push(n.clone());
+                stacks[arg[1] as usize].push(n);
+            }
+            TokenType::Add | TokenType::Subtract => {
+                let a = pop(&mut stacks[0]);
+                let b = pop(&mut stacks[1]);
+                stacks[2].push(if tokens[i] == TokenType::Add {
+                    a + b
+                } else {
+                    a - b
+                });
+            }
+            TokenType::Jump { arg } => {
+                if pop(&mut stacks[2]) > T::from(0) && *arg < tokens.len() {
+                    i = *arg;
+                }
+            }
+            TokenType::Print => {
+                let s: String = stacks[2]
+                    .drain(..)
+                    .rev()
+                    .map(|n| n.to_byte().unwrap() as char)
+                    .collect();
+                write!(output, "{}", s).unwrap();
+            }
+            TokenType::PrintNum => {
+                for n in stacks[2].drain(..).rev() {
+                    write!(output, "{}", n).unwrap();
+                }
+            }
+            token => unimplemented!("{} isn't used by the benchmarked scripts", token),
+        }
+        i += 1;
+    }
+}
+
+fn time<T>(f: impl Fn() -> T) -> Duration {
+    //Warm up once, then take the best of a few runs
+    f();
+    (0..5)
+        .map(|_| {
+            let start = Instant::now();
+            std::hint::black_box(f());
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
+
+fn compare(name: &str, big: Duration, value: Duration) {
+    println!(
+        "{:<28} BigInt {:>10}μs   Value {:>10}μs   speedup {:.2}x",
+        name,
+        big.as_micros(),
+        value.as_micros(),
+        big.as_secs_f64() / value.as_secs_f64()
+    );
+}
+
+fn main() {
+    let mut results = Vec::new();
+    let mut interpreted = Vec::new();
+
+    results.push((
+        "fibonnaci.stq kernel (x200)",
+        time(|| {
+            (0..200).for_each(|_| {
+                std::hint::black_box(fibonacci_kernel::<BigInt>(500));
+            })
+        }),
+        time(|| {
+            (0..200).for_each(|_| {
+                std::hint::black_box(fibonacci_kernel::<Value>(500));
+            })
+        }),
+    ));
+    results.push((
+        "tribonacci.stq loop counter",
+        time(|| counter_kernel::<BigInt>(500000)),
+        time(|| counter_kernel::<Value>(500000)),
+    ));
+
+    //The scripts are tokenized once, so only running them is timed. Their output is thrown away
+    for (name, source) in [
+        ("fibonnaci.stq", include_str!("../examples/fibonnaci.stq")),
+        ("tribonacci.stq", include_str!("../examples/tribonacci.stq")),
+    ] {
+        let mut program = tokenize(source);
+        optimize(&mut program, 0, NumericModel::BigInt);
+        results.push((
+            name,
+            time(|| run_tokens::<BigInt>(&program, &mut io::sink())),
+            time(|| run_tokens::<Value>(&program, &mut io::sink())),
+        ));
+        interpreted.push((
+            name,
+            time(|| {
+                run_program(
+                    program.clone(),
+                    Box::new(VirtualFileSystem::new()),
+                    RunOptions {
+                        output: Some(Box::new(io::sink())),
+                        ..Default::default()
+                    },
+                )
+            }),
+        ));
+    }
+
+    println!("\n\nBenchmark results:");
+    for (name, big, value) in results {
+        compare(name, big, value);
+    }
+    println!("\nThrough the interpreter (`run_program`, with Value):");
+    for (name, time) in interpreted {
+        println!("{:<28} {:>10}μs", name, time.as_micros());
+    }
+}
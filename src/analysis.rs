@@ -0,0 +1,726 @@
+use std::fmt::Write;
+
+use crate::{
+    emit::{dispatch_blocks, jump_entry},
+    token::{Handle, Program, TokenType},
+};
+
+pub const STACK_NAMES: [char; 3] = ['A', 'B', 'C'];
+
+///A single change a token makes to the height of one stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackOp {
+    ///Pops one value. Popping an empty stack leaves it empty
+    Pop(usize),
+    ///Pushes anywhere from `.1` to `.2` values. An upper bound of `None` means any number of values may be pushed
+    Push(usize, usize, Option<usize>),
+    ///Empties the stack and then pushes `.1` values
+    Set(usize, usize),
+}
+
+///Whether a token pops the top values of stacks A and B and pushes its result to stack C
+pub fn is_binary_op(token: &TokenType) -> bool {
+    token.binary_op().is_some()
+}
+
+///Adds the pop of a stream command's handle from stack C, if it isn't an argument
+fn popped_handle(handle: &Handle, mut ops: Vec<StackOp>) -> Vec<StackOp> {
+    if *handle == Handle::Popped {
+        ops.insert(0, StackOp::Pop(2));
+    }
+    ops
+}
+
+///Lists the changes a token makes to the stack heights, in the order the interpreter makes them
+pub fn stack_ops(token: &TokenType) -> Vec<StackOp> {
+    const A: usize = 0;
+    const B: usize = 1;
+    const C: usize = 2;
+
+    match token {
+        TokenType::Print | TokenType::PrintNum | TokenType::Clear => vec![StackOp::Set(C, 0)],
+        TokenType::GetNextIn => vec![StackOp::Push(C, 0, Some(1))],
+        //The new handle is only pushed on success
+        TokenType::CreateFileStream {
+            arg,
+            new_handle: true,
+        }
+        | TokenType::OpenFileStream {
+            arg,
+            new_handle: true,
+        }
+        | TokenType::AppendFileStream {
+            arg,
+            new_handle: true,
+        }
+        | TokenType::ReadWriteFileStream {
+            arg,
+            new_handle: true,
+        } => {
+            if arg.is_empty() {
+                vec![StackOp::Set(C, 0), StackOp::Push(C, 1, Some(2))]
+            } else {
+                vec![StackOp::Push(C, 1, Some(2))]
+            }
+        }
+        TokenType::CreateFile { arg }
+        | TokenType::CreateFileStream { arg, .. }
+        | TokenType::OpenFileStream { arg, .. }
+        | TokenType::AppendFileStream { arg, .. }
+        | TokenType::ReadWriteFileStream { arg, .. }
+        | TokenType::MakeDir { arg }
+        | TokenType::RemoveDir { arg }
+        | TokenType::RemoveFile { arg }
+        | TokenType::Exists { arg } => {
+            //An empty argument means the path is read from all of stack C
+            if arg.is_empty() {
+                vec![StackOp::Set(C, 1)]
+            } else {
+                vec![StackOp::Push(C, 1, Some(1))]
+            }
+        }
+        //Whichever path is read last takes the rest of stack C
+        TokenType::Rename { from, to } => {
+            if from.is_empty() || to.is_empty() {
+                vec![StackOp::Set(C, 1)]
+            } else {
+                vec![StackOp::Push(C, 1, Some(1))]
+            }
+        }
+        //The size is only pushed on success
+        TokenType::FileSize { arg } => {
+            if arg.is_empty() {
+                vec![StackOp::Set(C, 0), StackOp::Push(C, 1, Some(2))]
+            } else {
+                vec![StackOp::Push(C, 1, Some(2))]
+            }
+        }
+        //The names and their count are only pushed on success
+        TokenType::ListDir { arg } => {
+            if arg.is_empty() {
+                vec![StackOp::Set(C, 0), StackOp::Push(C, 1, None)]
+            } else {
+                vec![StackOp::Push(C, 1, None)]
+            }
+        }
+        //The byte read is only pushed on success
+        TokenType::ReadFileStream { handle } => {
+            popped_handle(handle, vec![StackOp::Push(C, 1, Some(2))])
+        }
+        TokenType::WriteFileStream { .. } => vec![StackOp::Set(C, 1)],
+        TokenType::SeekFileStream { handle, .. } => {
+            popped_handle(handle, vec![StackOp::Pop(C), StackOp::Push(C, 1, Some(1))])
+        }
+        //The position is only pushed on success
+        TokenType::TellFileStream { handle } => {
+            popped_handle(handle, vec![StackOp::Push(C, 1, Some(2))])
+        }
+        TokenType::CloseFileStream { handle } => {
+            popped_handle(handle, vec![StackOp::Push(C, 1, Some(1))])
+        }
+        TokenType::Push { .. } => vec![StackOp::Push(C, 1, Some(1))],
+        TokenType::Pop { arg } if *arg < 3 => vec![StackOp::Pop(*arg as usize)],
+        TokenType::Move { arg } if arg[0] < 3 && arg[1] < 3 => vec![
+            StackOp::Pop(arg[0] as usize),
+            StackOp::Push(arg[1] as usize, 1, Some(1)),
+        ],
+        TokenType::Copy { arg } if arg[0] < 3 && arg[1] < 3 => vec![
+            StackOp::Pop(arg[0] as usize),
+            StackOp::Push(arg[0] as usize, 1, Some(1)),
+            StackOp::Push(arg[1] as usize, 1, Some(1)),
+        ],
+        TokenType::Jump { .. } | TokenType::PreComputeJump { .. } => vec![StackOp::Pop(C)],
+        TokenType::PushTo { stack, .. } if *stack < 3 => {
+            vec![StackOp::Push(*stack as usize, 1, Some(1))]
+        }
+        TokenType::DecA => vec![StackOp::Pop(A), StackOp::Push(A, 1, Some(1))],
+        TokenType::JumpIfTopPositive { stack, .. } if *stack < 3 => vec![
+            StackOp::Pop(*stack as usize),
+            StackOp::Push(*stack as usize, 1, Some(1)),
+        ],
+        _ if is_binary_op(token) => vec![
+            StackOp::Pop(A),
+            StackOp::Pop(B),
+            StackOp::Push(C, 1, Some(1)),
+        ],
+        _ => Vec::new(),
+    }
+}
+
+//Token level stack heights
+
+///The range of heights a stack may have at some point in the program. A `max` of `None` means there is no known upper bound
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Height {
+    pub min: usize,
+    pub max: Option<usize>,
+}
+
+impl Height {
+    pub fn exact(n: usize) -> Height {
+        Height {
+            min: n,
+            max: Some(n),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.max == Some(0)
+    }
+
+    fn apply(self, op: StackOp) -> Height {
+        match op {
+            StackOp::Pop(_) => Height {
+                min: self.min.saturating_sub(1),
+                max: self.max.map(|m| m.saturating_sub(1)),
+            },
+            StackOp::Push(_, lo, hi) => Height {
+                min: self.min + lo,
+                max: self.max.zip(hi).map(|(m, hi)| m + hi),
+            },
+            StackOp::Set(_, n) => Height::exact(n),
+        }
+    }
+
+    ///Joins two heights reaching the same token. Any growth of the upper bound is widened to infinity so that loops converge
+    fn widen(self, other: Height) -> Height {
+        let max = match (self.max, other.max) {
+            (Some(a), Some(b)) if b <= a => Some(a),
+            _ => None,
+        };
+        Height {
+            min: self.min.min(other.min),
+            max,
+        }
+    }
+}
+
+pub type HeightState = [Height; 3];
+
+fn stack_of(op: StackOp) -> usize {
+    match op {
+        StackOp::Pop(s) | StackOp::Push(s, ..) | StackOp::Set(s, _) => s,
+    }
+}
+
+///Applies a single token to the stack heights, returning the heights afterwards
+pub fn transfer(token: &TokenType, mut state: HeightState) -> HeightState {
+    for op in stack_ops(token) {
+        let s = stack_of(op);
+        state[s] = state[s].apply(op);
+    }
+    state
+}
+
+///Computes the possible stack heights before each token by abstract interpretation from the start of the program.
+/// Jumps must already be resolved. Tokens which can never be reached have a state of `None`
+pub fn token_heights(tokens: &[TokenType]) -> Vec<Option<HeightState>> {
+    let mut states: Vec<Option<HeightState>> = vec![None; tokens.len()];
+    let mut worklist: Vec<usize> = Vec::new();
+
+    if !tokens.is_empty() {
+        states[0] = Some([Height::exact(0); 3]);
+        worklist.push(0);
+    }
+
+    while let Some(i) = worklist.pop() {
+        let before = states[i].unwrap();
+        let after = transfer(&tokens[i], before);
+
+        let mut successors: Vec<usize> = Vec::with_capacity(2);
+        match &tokens[i] {
+            TokenType::Exit => (),
+            TokenType::Jump { arg } => {
+                successors.push(i + 1);
+                //Execution continues after the label, and a jump with an empty C stack is never taken
+                if *arg != usize::MAX && !before[2].is_empty() {
+                    successors.push(arg + 1);
+                }
+            }
+            TokenType::JumpIfTopPositive { stack, target } => {
+                successors.push(i + 1);
+                if *target != usize::MAX
+                    && !before.get(*stack as usize).is_some_and(|h| h.is_empty())
+                {
+                    successors.push(target + 1);
+                }
+            }
+            _ => successors.push(i + 1),
+        }
+
+        for s in successors {
+            if s >= tokens.len() {
+                continue;
+            }
+            let joined = match states[s] {
+                Some(old) => [
+                    old[0].widen(after[0]),
+                    old[1].widen(after[1]),
+                    old[2].widen(after[2]),
+                ],
+                None => after,
+            };
+            if states[s] != Some(joined) {
+                states[s] = Some(joined);
+                worklist.push(s);
+            }
+        }
+    }
+
+    states
+}
+
+//Basic blocks
+
+///The net effect a basic block has on the height of one stack
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEffect {
+    ///The minimum height the stack must have on entry for the block to never pop it while empty
+    pub required: usize,
+    ///Whether the block empties the stack, making the height on exit independent of the height on entry
+    pub resets: bool,
+    ///The smallest and largest change in height over the block, assuming at least `required` values on entry.
+    /// If `resets` is true, these are the height on exit instead. A `max_delta` of `None` means there is no upper bound
+    pub min_delta: i64,
+    pub max_delta: Option<i64>,
+}
+
+impl Default for StackEffect {
+    fn default() -> Self {
+        StackEffect {
+            required: 0,
+            resets: false,
+            min_delta: 0,
+            max_delta: Some(0),
+        }
+    }
+}
+
+impl StackEffect {
+    fn apply(&mut self, op: StackOp) {
+        match op {
+            StackOp::Pop(_) => {
+                if self.resets {
+                    self.min_delta = (self.min_delta - 1).max(0);
+                    self.max_delta = self.max_delta.map(|d| (d - 1).max(0));
+                } else {
+                    self.required = self.required.max((1 - self.min_delta).max(0) as usize);
+                    self.min_delta -= 1;
+                    self.max_delta = self.max_delta.map(|d| d - 1);
+                }
+            }
+            StackOp::Push(_, lo, hi) => {
+                self.min_delta += lo as i64;
+                self.max_delta = self.max_delta.zip(hi).map(|(d, hi)| d + hi as i64);
+            }
+            StackOp::Set(_, n) => {
+                self.resets = true;
+                self.min_delta = n as i64;
+                self.max_delta = Some(n as i64);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EdgeKind {
+    ///Execution continues into the next block
+    Fallthrough,
+    ///A jump at the end of the block is taken
+    Taken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    ///The index of the destination block
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+///A run of tokens which is only ever entered at its first token and only ever left after its last.
+/// Blocks start wherever a jump can land and end after jumps and exits
+#[derive(Debug, Clone, PartialEq)]
+pub struct BasicBlock {
+    ///The index of the first token in the block
+    pub start: usize,
+    ///One past the index of the last token in the block
+    pub end: usize,
+    pub successors: Vec<Edge>,
+    pub effects: [StackEffect; 3],
+}
+
+///Splits a token stream into basic blocks, which are the same blocks the backends dispatch between. Jumps must already be resolved
+pub fn basic_blocks(tokens: &[TokenType]) -> Vec<BasicBlock> {
+    let leaders = dispatch_blocks(tokens);
+
+    //Maps a token index to the index of the block containing it
+    let block_of = |i: usize| leaders.partition_point(|l| *l <= i) - 1;
+
+    let mut blocks = Vec::with_capacity(leaders.len());
+    for (b, start) in leaders.iter().enumerate() {
+        let end = leaders.get(b + 1).copied().unwrap_or(tokens.len());
+
+        let mut effects = [StackEffect::default(); 3];
+        for token in &tokens[*start..end] {
+            for op in stack_ops(token) {
+                effects[stack_of(op)].apply(op);
+            }
+        }
+
+        let mut successors = Vec::with_capacity(2);
+        let falls_through = end < tokens.len();
+        match &tokens[end - 1] {
+            TokenType::Exit => (),
+            token => {
+                if falls_through {
+                    successors.push(Edge {
+                        to: b + 1,
+                        kind: EdgeKind::Fallthrough,
+                    });
+                }
+                //Jumps to undefined labels are never taken, and a jump to the last token goes to the end of the program
+                if let Some(entry) = token
+                    .jump_target()
+                    .and_then(|t| jump_entry(tokens, t))
+                    .filter(|e| *e < tokens.len())
+                {
+                    successors.push(Edge {
+                        to: block_of(entry),
+                        kind: EdgeKind::Taken,
+                    });
+                }
+            }
+        }
+
+        blocks.push(BasicBlock {
+            start: *start,
+            end,
+            successors,
+            effects,
+        });
+    }
+
+    blocks
+}
+
+///A strongly connected group of blocks, any of which can be repeated
+#[derive(Debug, Clone, PartialEq)]
+pub struct Loop {
+    ///The indices of the blocks in the loop, in ascending order
+    pub blocks: Vec<usize>,
+    ///For each stack, whether some path around the loop leaves it taller than before, so it can grow without bound
+    pub unbounded: [bool; 3],
+}
+
+///Finds the strongly connected components of the block graph with Tarjan's algorithm.
+/// Only components which contain a cycle are returned
+fn cycles(blocks: &[BasicBlock]) -> Vec<Vec<usize>> {
+    struct Tarjan<'a> {
+        blocks: &'a [BasicBlock],
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        next_index: usize,
+        components: Vec<Vec<usize>>,
+    }
+
+    impl Tarjan<'_> {
+        fn visit(&mut self, v: usize) {
+            self.index[v] = Some(self.next_index);
+            self.low[v] = self.next_index;
+            self.next_index += 1;
+            self.stack.push(v);
+            self.on_stack[v] = true;
+
+            for edge in &self.blocks[v].successors {
+                let w = edge.to;
+                match self.index[w] {
+                    None => {
+                        self.visit(w);
+                        self.low[v] = self.low[v].min(self.low[w]);
+                    }
+                    Some(i) if self.on_stack[w] => self.low[v] = self.low[v].min(i),
+                    _ => (),
+                }
+            }
+
+            if Some(self.low[v]) == self.index[v] {
+                let mut component = Vec::new();
+                loop {
+                    let w = self.stack.pop().unwrap();
+                    self.on_stack[w] = false;
+                    component.push(w);
+                    if w == v {
+                        break;
+                    }
+                }
+                component.sort_unstable();
+                self.components.push(component);
+            }
+        }
+    }
+
+    let mut t = Tarjan {
+        blocks,
+        index: vec![None; blocks.len()],
+        low: vec![0; blocks.len()],
+        on_stack: vec![false; blocks.len()],
+        stack: Vec::new(),
+        next_index: 0,
+        components: Vec::new(),
+    };
+    for v in 0..blocks.len() {
+        if t.index[v].is_none() {
+            t.visit(v);
+        }
+    }
+
+    let mut components: Vec<Vec<usize>> = t
+        .components
+        .into_iter()
+        .filter(|c| c.len() > 1 || blocks[c[0]].successors.iter().any(|e| e.to == c[0]))
+        .collect();
+    components.sort();
+    components
+}
+
+///Whether there is a cycle within `component` whose total `max_delta` on `stack` is positive.
+/// Blocks which reset the stack break any cycle through them. Uses Bellman-Ford with negated weights
+fn has_growing_cycle(blocks: &[BasicBlock], component: &[usize], stack: usize) -> bool {
+    let nodes: Vec<usize> = component
+        .iter()
+        .copied()
+        .filter(|b| !blocks[*b].effects[stack].resets)
+        .collect();
+    let mut dist: Vec<i64> = vec![0; nodes.len()];
+    //A block with no upper bound outweighs all of the other blocks together, so any cycle through it grows
+    let unbounded: i64 = 1 + nodes
+        .iter()
+        .filter_map(|b| blocks[*b].effects[stack].max_delta)
+        .map(|d| d.abs())
+        .sum::<i64>();
+
+    for round in 0..=nodes.len() {
+        let mut changed = false;
+        for (u_i, u) in nodes.iter().enumerate() {
+            let weight = -blocks[*u].effects[stack].max_delta.unwrap_or(unbounded);
+            for edge in &blocks[*u].successors {
+                if let Some(v_i) = nodes.iter().position(|n| *n == edge.to) {
+                    if dist[u_i] + weight < dist[v_i] {
+                        dist[v_i] = dist[u_i] + weight;
+                        changed = true;
+                    }
+                }
+            }
+        }
+        if !changed {
+            return false;
+        }
+        //Still relaxing after |nodes| rounds means there is a negative (growing) cycle
+        if round == nodes.len() {
+            return true;
+        }
+    }
+
+    false
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Analysis {
+    pub blocks: Vec<BasicBlock>,
+    pub loops: Vec<Loop>,
+}
+
+///Splits a program into basic blocks, computes their effects on the stacks and finds loops which grow a stack without bound.
+/// Jumps must already be resolved
+pub fn analyze(tokens: &[TokenType]) -> Analysis {
+    if tokens.is_empty() {
+        return Analysis {
+            blocks: Vec::new(),
+            loops: Vec::new(),
+        };
+    }
+
+    let blocks = basic_blocks(tokens);
+    let loops = cycles(&blocks)
+        .into_iter()
+        .map(|component| {
+            let unbounded = [0, 1, 2].map(|s| has_growing_cycle(&blocks, &component, s));
+            Loop {
+                blocks: component,
+                unbounded,
+            }
+        })
+        .collect();
+
+    Analysis { blocks, loops }
+}
+
+impl Analysis {
+    ///Writes a human readable report of the analysis. `program` must be the program which was analyzed
+    pub fn report(&self, program: &Program) -> String {
+        let mut s = String::new();
+
+        for (b, block) in self.blocks.iter().enumerate() {
+            let first_line = program.spans[block.start].line;
+            let last_line = program.spans[block.end - 1].line;
+            writeln!(
+                s,
+                "block {} (tokens {}..{}, lines {}-{})",
+                b, block.start, block.end, first_line, last_line
+            )
+            .unwrap();
+
+            for (stack, effect) in block.effects.iter().enumerate() {
+                let change = match effect.max_delta {
+                    Some(max) if max == effect.min_delta => format!("{:+}", max),
+                    Some(max) => format!("{:+}..{:+}", effect.min_delta, max),
+                    None => format!("{:+} or more", effect.min_delta),
+                };
+                let change = if effect.resets {
+                    format!("reset to {}", change.trim_start_matches('+'))
+                } else {
+                    change
+                };
+                writeln!(
+                    s,
+                    "    {}: {}, requires {} on entry",
+                    STACK_NAMES[stack], change, effect.required
+                )
+                .unwrap();
+            }
+
+            let successors: Vec<String> = block
+                .successors
+                .iter()
+                .map(|e| match e.kind {
+                    EdgeKind::Fallthrough => format!("{}", e.to),
+                    EdgeKind::Taken => format!("{} (jump)", e.to),
+                })
+                .collect();
+            if successors.is_empty() {
+                writeln!(s, "    -> end").unwrap();
+            } else {
+                writeln!(s, "    -> {}", successors.join(", ")).unwrap();
+            }
+        }
+
+        for l in &self.loops {
+            let blocks: Vec<String> = l.blocks.iter().map(|b| b.to_string()).collect();
+            write!(s, "loop over blocks [{}]", blocks.join(", ")).unwrap();
+
+            let growing: Vec<String> = (0..3)
+                .filter(|stack| l.unbounded[*stack])
+                .map(|stack| STACK_NAMES[stack].to_string())
+                .collect();
+            if growing.is_empty() {
+                writeln!(s, ": stack heights are bounded").unwrap();
+            } else {
+                writeln!(s, ": may grow stack {} without bound", growing.join(", ")).unwrap();
+            }
+        }
+
+        s
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{analyze, stack_ops, EdgeKind, StackEffect, StackOp};
+    use crate::{
+        interpreter::tokenize,
+        optimize::{optimize, resolve_jumps},
+        token::{Handle, TokenType},
+        value::NumericModel,
+    };
+
+    fn analyze_source(source: &str) -> super::Analysis {
+        let mut program = tokenize(source);
+        resolve_jumps(&mut program.tokens);
+        analyze(&program.tokens)
+    }
+
+    #[test]
+    fn block_effects() {
+        let analysis = analyze_source("move:A:B move:A:C pop:B push:1 push:2 move:C:A");
+        assert_eq!(analysis.blocks.len(), 1);
+
+        let effects = analysis.blocks[0].effects;
+        assert_eq!(
+            effects[0],
+            StackEffect {
+                required: 2,
+                resets: false,
+                min_delta: -1,
+                max_delta: Some(-1)
+            }
+        );
+        assert_eq!(effects[1].required, 0);
+        assert_eq!(effects[1].min_delta, 0);
+        assert!(effects[2].resets);
+
+        //A handle taken from stack C is popped before the byte and status are pushed
+        assert_eq!(
+            stack_ops(&TokenType::ReadFileStream {
+                handle: Handle::Popped
+            }),
+            [StackOp::Pop(2), StackOp::Push(2, 1, Some(2))]
+        );
+        assert_eq!(
+            stack_ops(&TokenType::ReadFileStream {
+                handle: Handle::READ
+            }),
+            [StackOp::Push(2, 1, Some(2))]
+        );
+    }
+
+    #[test]
+    fn loops() {
+        //Pushes a value to B on every iteration
+        let analysis = analyze_source("push:5 move:C:A\nlabel:l\npush:1 move:C:B\ncopy:A:C jump:l");
+        assert_eq!(analysis.blocks.len(), 3);
+        assert_eq!(analysis.blocks[1].successors[1].kind, EdgeKind::Taken);
+        assert_eq!(analysis.loops.len(), 1);
+        assert_eq!(analysis.loops[0].unbounded, [false, true, false]);
+
+        //The fibonacci loop keeps the same stack heights every iteration
+        let analysis = analyze_source(include_str!("../examples/fibonnaci.stq"));
+        assert_eq!(analysis.loops.len(), 1);
+        assert_eq!(analysis.loops[0].unbounded, [false, false, false]);
+
+        //A listing can push any number of values, so popping a fixed number doesn't keep C bounded
+        let analysis = analyze_source("label:l listdir:d pop:C pop:C push:1 jump:l");
+        assert_eq!(analysis.blocks[1].effects[2].max_delta, None);
+        assert_eq!(analysis.loops[0].unbounded, [false, false, true]);
+        let analysis = analyze_source("label:l readfilestream pop:C pop:C push:1 jump:l");
+        assert_eq!(analysis.loops[0].unbounded, [false, false, false]);
+    }
+
+    #[test]
+    fn blocks_start_at_jump_targets() {
+        //Once labels are stripped, a jump can land on any token
+        let mut program = tokenize("push:5 move:C:A\nlabel:l\npush:1 move:C:B\ncopy:A:C jump:l");
+        optimize(&mut program, 1, NumericModel::default());
+        assert!(!program
+            .tokens
+            .iter()
+            .any(|t| matches!(t, TokenType::Label { .. })));
+
+        let analysis = analyze(&program.tokens);
+        let (b, target) = analysis
+            .blocks
+            .iter()
+            .enumerate()
+            .find_map(|(b, block)| Some((b, program.tokens[block.end - 1].jump_target()?)))
+            .unwrap();
+        let taken = analysis.blocks[b]
+            .successors
+            .iter()
+            .find(|e| e.kind == EdgeKind::Taken)
+            .unwrap();
+        assert_eq!(analysis.blocks[taken.to].start, target + 1);
+        assert_eq!(analysis.loops.len(), 1);
+        assert_eq!(analysis.loops[0].unbounded, [false, true, false]);
+    }
+}
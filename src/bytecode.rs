@@ -0,0 +1,663 @@
+use std::{collections::HashMap, fmt::Display};
+
+use num::bigint::BigInt;
+
+use crate::token::{Handle, Program, Span, TokenType, Whence};
+
+///The first bytes of every compiled StaqLang file
+pub const MAGIC: &[u8; 4] = b"STQC";
+///Incremented whenever the encoding changes. Files from other versions are rejected
+pub const VERSION: u16 = 2;
+
+///Set in the header flags when the file has a debug info section
+const FLAG_DEBUG_INFO: u8 = 1;
+
+const TAG_INT: u8 = 0;
+const TAG_STR: u8 = 1;
+
+///Jumps to labels which don't exist are stored with this target, and are `usize::MAX` once decoded
+const UNDEFINED_TARGET: u64 = u64::MAX;
+
+///An entry of the constant pool. Instructions refer to constants by their index in the pool
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Constant {
+    Int(BigInt),
+    Str(String),
+}
+
+impl Display for Constant {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Constant::Int(n) => write!(f, "{}", n),
+            Constant::Str(s) => write!(f, "{:?}", s),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BytecodeError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnexpectedEnd,
+    BadOpcode(u8),
+    BadConstantTag(u8),
+    ///A constant index which is out of range, or refers to a constant of the wrong type
+    BadConstant(u64),
+    ///A jump target which is neither an instruction index nor undefined
+    BadJumpTarget(u64),
+    ///A `SeekFileStream` origin other than start, current or end
+    BadWhence(u8),
+    ///A stack index other than 0, 1 or 2 (A, B or C)
+    BadStack(u8),
+    ///A file stream handle which doesn't fit in a `u32`
+    BadHandle(u64),
+    ///A byte which should be 0 or 1
+    BadFlag(u8),
+    InvalidUtf8,
+    TrailingBytes,
+}
+
+impl Display for BytecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BytecodeError::BadMagic => write!(f, "not a compiled StaqLang file"),
+            BytecodeError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported bytecode version {} (expected {})",
+                v, VERSION
+            ),
+            BytecodeError::UnexpectedEnd => write!(f, "unexpected end of file"),
+            BytecodeError::BadOpcode(op) => write!(f, "unknown opcode {}", op),
+            BytecodeError::BadConstantTag(tag) => write!(f, "unknown constant tag {}", tag),
+            BytecodeError::BadConstant(i) => write!(f, "bad constant index {}", i),
+            BytecodeError::BadJumpTarget(t) => write!(f, "bad jump target {}", t),
+            BytecodeError::BadWhence(w) => write!(f, "unknown seek origin {}", w),
+            BytecodeError::BadStack(s) => write!(f, "bad stack index {}", s),
+            BytecodeError::BadHandle(h) => write!(f, "bad file stream handle {}", h),
+            BytecodeError::BadFlag(b) => write!(f, "bad flag byte {}", b),
+            BytecodeError::InvalidUtf8 => write!(f, "string constant is not valid UTF-8"),
+            BytecodeError::TrailingBytes => write!(f, "unexpected bytes after the end of the file"),
+        }
+    }
+}
+
+///Whether `bytes` start like a compiled StaqLang file
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+///The operands of a token which are stored in the constant pool, in the order they're written
+fn pooled(token: &TokenType) -> Vec<Constant> {
+    match token {
+        TokenType::Push { arg } | TokenType::PushTo { value: arg, .. } => {
+            vec![Constant::Int(arg.clone())]
+        }
+        TokenType::CreateFile { arg }
+        | TokenType::CreateFileStream { arg, .. }
+        | TokenType::OpenFileStream { arg, .. }
+        | TokenType::AppendFileStream { arg, .. }
+        | TokenType::ReadWriteFileStream { arg, .. }
+        | TokenType::MakeDir { arg }
+        | TokenType::RemoveDir { arg }
+        | TokenType::RemoveFile { arg }
+        | TokenType::Exists { arg }
+        | TokenType::FileSize { arg }
+        | TokenType::ListDir { arg }
+        | TokenType::PreComputeJump { arg }
+        | TokenType::Label { arg } => vec![Constant::Str(arg.clone())],
+        TokenType::Rename { from, to } => {
+            vec![Constant::Str(from.clone()), Constant::Str(to.clone())]
+        }
+        _ => Vec::new(),
+    }
+}
+
+///Builds the constant pool of a program: every distinct integer and string operand, in order of first use
+pub fn constant_pool(program: &Program) -> Vec<Constant> {
+    let mut pool: Vec<Constant> = Vec::new();
+    for token in &program.tokens {
+        for c in pooled(token) {
+            if !pool.contains(&c) {
+                pool.push(c);
+            }
+        }
+    }
+    pool
+}
+
+fn opcode(token: &TokenType) -> u8 {
+    match token {
+        TokenType::Exit => 0,
+        TokenType::Print => 1,
+        TokenType::PrintNum => 2,
+        TokenType::GetNextIn => 3,
+        TokenType::CreateFile { .. } => 4,
+        TokenType::CreateFileStream { .. } => 5,
+        TokenType::OpenFileStream { .. } => 6,
+        TokenType::ReadFileStream { .. } => 7,
+        TokenType::WriteFileStream { .. } => 8,
+        TokenType::Clear => 9,
+        TokenType::Push { .. } => 10,
+        TokenType::Pop { .. } => 11,
+        TokenType::Add => 12,
+        TokenType::Subtract => 13,
+        TokenType::Multiply => 14,
+        TokenType::Divide => 15,
+        TokenType::Modulo => 16,
+        TokenType::Move { .. } => 17,
+        TokenType::Copy { .. } => 18,
+        TokenType::PreComputeJump { .. } => 19,
+        TokenType::Jump { .. } => 20,
+        TokenType::Label { .. } => 21,
+        TokenType::Equal => 22,
+        TokenType::LessThan => 23,
+        TokenType::LessThanOrEqual => 24,
+        TokenType::GreaterThan => 25,
+        TokenType::GreaterThanOrEqual => 26,
+        TokenType::BitAnd => 27,
+        TokenType::BitOr => 28,
+        TokenType::BitXor => 29,
+        TokenType::BitRightShift => 30,
+        TokenType::BitLeftShift => 31,
+        TokenType::PushTo { .. } => 32,
+        TokenType::DecA => 33,
+        TokenType::JumpIfTopPositive { .. } => 34,
+        TokenType::MakeDir { .. } => 35,
+        TokenType::RemoveDir { .. } => 36,
+        TokenType::Rename { .. } => 37,
+        TokenType::RemoveFile { .. } => 38,
+        TokenType::Exists { .. } => 39,
+        TokenType::FileSize { .. } => 40,
+        TokenType::ListDir { .. } => 41,
+        TokenType::AppendFileStream { .. } => 42,
+        TokenType::ReadWriteFileStream { .. } => 43,
+        TokenType::SeekFileStream { .. } => 44,
+        TokenType::TellFileStream { .. } => 45,
+        TokenType::CloseFileStream { .. } => 46,
+    }
+}
+
+///Writes an unsigned LEB128 integer
+fn write_varint(out: &mut Vec<u8>, mut n: u64) {
+    loop {
+        let byte = (n & 0x7f) as u8;
+        n >>= 7;
+        if n == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+///Handles are stored one higher than their number, leaving 0 for a handle popped from stack C
+fn write_handle(out: &mut Vec<u8>, handle: &Handle) {
+    write_varint(
+        out,
+        match handle {
+            Handle::Popped => 0,
+            Handle::Number(n) => *n as u64 + 1,
+        },
+    );
+}
+
+///Encodes a program as bytecode. Jumps should already be resolved, and the spans are only written if `debug_info` is set.
+///
+/// The layout is: the magic bytes, a little endian `u16` version, a flags byte, the constant pool,
+/// the instructions, and then (if flagged) one span per instruction. Counts, indices and jump targets are LEB128 varints.
+/// Jump targets are instruction indices
+pub fn encode(program: &Program, debug_info: bool) -> Vec<u8> {
+    let pool = constant_pool(program);
+    let indices: HashMap<&Constant, u64> = pool
+        .iter()
+        .enumerate()
+        .map(|(i, c)| (c, i as u64))
+        .collect();
+
+    let mut out: Vec<u8> = Vec::new();
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.push(if debug_info { FLAG_DEBUG_INFO } else { 0 });
+
+    write_varint(&mut out, pool.len() as u64);
+    for constant in &pool {
+        match constant {
+            Constant::Int(n) => {
+                out.push(TAG_INT);
+                write_bytes(&mut out, &n.to_signed_bytes_le());
+            }
+            Constant::Str(s) => {
+                out.push(TAG_STR);
+                write_bytes(&mut out, s.as_bytes());
+            }
+        }
+    }
+
+    write_varint(&mut out, program.tokens.len() as u64);
+    for token in &program.tokens {
+        out.push(opcode(token));
+        for c in pooled(token) {
+            write_varint(&mut out, indices[&c]);
+        }
+        match token {
+            TokenType::Pop { arg } | TokenType::PushTo { stack: arg, .. } => out.push(*arg),
+            TokenType::Move { arg } | TokenType::Copy { arg } => out.extend_from_slice(arg),
+            TokenType::JumpIfTopPositive { stack, .. } => out.push(*stack),
+            TokenType::CreateFileStream { new_handle, .. }
+            | TokenType::OpenFileStream { new_handle, .. }
+            | TokenType::AppendFileStream { new_handle, .. }
+            | TokenType::ReadWriteFileStream { new_handle, .. } => out.push(*new_handle as u8),
+            TokenType::ReadFileStream { handle }
+            | TokenType::WriteFileStream { handle }
+            | TokenType::TellFileStream { handle }
+            | TokenType::CloseFileStream { handle } => write_handle(&mut out, handle),
+            TokenType::SeekFileStream { whence, handle } => {
+                out.push(match whence {
+                    Whence::Start => 0,
+                    Whence::Current => 1,
+                    Whence::End => 2,
+                });
+                write_handle(&mut out, handle);
+            }
+            _ => (),
+        }
+        if let Some(target) = token.jump_target() {
+            write_varint(
+                &mut out,
+                if target >= program.tokens.len() {
+                    UNDEFINED_TARGET
+                } else {
+                    target as u64
+                },
+            );
+        }
+    }
+
+    if debug_info {
+        for i in 0..program.tokens.len() {
+            let span = program.spans.get(i).copied().unwrap_or_default();
+            write_varint(&mut out, span.line as u64);
+            write_varint(&mut out, span.col as u64);
+            write_varint(&mut out, span.len as u64);
+        }
+    }
+
+    out
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn byte(&mut self) -> Result<u8, BytecodeError> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn slice(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        let slice = self
+            .bytes
+            .get(self.pos..end)
+            .ok_or(BytecodeError::UnexpectedEnd)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    fn varint(&mut self) -> Result<u64, BytecodeError> {
+        let mut n: u64 = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            n |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+        Err(BytecodeError::UnexpectedEnd)
+    }
+
+    ///Reads a varint which is used as a length or count
+    fn len(&mut self) -> Result<usize, BytecodeError> {
+        let n = self.varint()?;
+        //Anything longer than the remaining bytes is certainly truncated
+        if n > (self.bytes.len() - self.pos) as u64 {
+            return Err(BytecodeError::UnexpectedEnd);
+        }
+        Ok(n as usize)
+    }
+}
+
+///Decodes bytecode made by `encode`. The spans are empty if the file has no debug info
+pub fn decode(bytes: &[u8]) -> Result<Program, BytecodeError> {
+    if !is_bytecode(bytes) {
+        return Err(BytecodeError::BadMagic);
+    }
+    let mut r = Reader {
+        bytes,
+        pos: MAGIC.len(),
+    };
+
+    let version = u16::from_le_bytes([r.byte()?, r.byte()?]);
+    if version != VERSION {
+        return Err(BytecodeError::UnsupportedVersion(version));
+    }
+    let flags = r.byte()?;
+
+    let pool_len = r.len()?;
+    let mut pool: Vec<Constant> = Vec::with_capacity(pool_len);
+    for _ in 0..pool_len {
+        let tag = r.byte()?;
+        let len = r.len()?;
+        let data = r.slice(len)?;
+        pool.push(match tag {
+            TAG_INT => Constant::Int(BigInt::from_signed_bytes_le(data)),
+            TAG_STR => Constant::Str(
+                String::from_utf8(data.to_vec()).map_err(|_| BytecodeError::InvalidUtf8)?,
+            ),
+            _ => return Err(BytecodeError::BadConstantTag(tag)),
+        });
+    }
+
+    let int = |r: &mut Reader| -> Result<BigInt, BytecodeError> {
+        let i = r.varint()?;
+        match pool.get(i as usize) {
+            Some(Constant::Int(n)) => Ok(n.clone()),
+            _ => Err(BytecodeError::BadConstant(i)),
+        }
+    };
+    let string = |r: &mut Reader| -> Result<String, BytecodeError> {
+        let i = r.varint()?;
+        match pool.get(i as usize) {
+            Some(Constant::Str(s)) => Ok(s.clone()),
+            _ => Err(BytecodeError::BadConstant(i)),
+        }
+    };
+
+    let handle = |r: &mut Reader| -> Result<Handle, BytecodeError> {
+        match r.varint()? {
+            0 => Ok(Handle::Popped),
+            h => u32::try_from(h - 1)
+                .map(Handle::Number)
+                .map_err(|_| BytecodeError::BadHandle(h)),
+        }
+    };
+    let stack = |r: &mut Reader| -> Result<u8, BytecodeError> {
+        match r.byte()? {
+            s @ 0..=2 => Ok(s),
+            s => Err(BytecodeError::BadStack(s)),
+        }
+    };
+    let flag = |r: &mut Reader| -> Result<bool, BytecodeError> {
+        match r.byte()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            b => Err(BytecodeError::BadFlag(b)),
+        }
+    };
+
+    let token_count = r.len()?;
+    let jump_target = |r: &mut Reader| -> Result<usize, BytecodeError> {
+        let target = r.varint()?;
+        if target == UNDEFINED_TARGET {
+            Ok(usize::MAX)
+        } else if target < token_count as u64 {
+            Ok(target as usize)
+        } else {
+            Err(BytecodeError::BadJumpTarget(target))
+        }
+    };
+    let mut tokens: Vec<TokenType> = Vec::with_capacity(token_count);
+    for _ in 0..token_count {
+        let op = r.byte()?;
+        tokens.push(match op {
+            0 => TokenType::Exit,
+            1 => TokenType::Print,
+            2 => TokenType::PrintNum,
+            3 => TokenType::GetNextIn,
+            4 => TokenType::CreateFile {
+                arg: string(&mut r)?,
+            },
+            5 => TokenType::CreateFileStream {
+                arg: string(&mut r)?,
+                new_handle: flag(&mut r)?,
+            },
+            6 => TokenType::OpenFileStream {
+                arg: string(&mut r)?,
+                new_handle: flag(&mut r)?,
+            },
+            7 => TokenType::ReadFileStream {
+                handle: handle(&mut r)?,
+            },
+            8 => TokenType::WriteFileStream {
+                handle: handle(&mut r)?,
+            },
+            9 => TokenType::Clear,
+            10 => TokenType::Push { arg: int(&mut r)? },
+            11 => TokenType::Pop {
+                arg: stack(&mut r)?,
+            },
+            12 => TokenType::Add,
+            13 => TokenType::Subtract,
+            14 => TokenType::Multiply,
+            15 => TokenType::Divide,
+            16 => TokenType::Modulo,
+            17 => TokenType::Move {
+                arg: [stack(&mut r)?, stack(&mut r)?],
+            },
+            18 => TokenType::Copy {
+                arg: [stack(&mut r)?, stack(&mut r)?],
+            },
+            19 => TokenType::PreComputeJump {
+                arg: string(&mut r)?,
+            },
+            20 => TokenType::Jump {
+                arg: jump_target(&mut r)?,
+            },
+            21 => TokenType::Label {
+                arg: string(&mut r)?,
+            },
+            22 => TokenType::Equal,
+            23 => TokenType::LessThan,
+            24 => TokenType::LessThanOrEqual,
+            25 => TokenType::GreaterThan,
+            26 => TokenType::GreaterThanOrEqual,
+            27 => TokenType::BitAnd,
+            28 => TokenType::BitOr,
+            29 => TokenType::BitXor,
+            30 => TokenType::BitRightShift,
+            31 => TokenType::BitLeftShift,
+            32 => {
+                let value = int(&mut r)?;
+                TokenType::PushTo {
+                    stack: stack(&mut r)?,
+                    value,
+                }
+            }
+            33 => TokenType::DecA,
+            34 => TokenType::JumpIfTopPositive {
+                stack: stack(&mut r)?,
+                target: jump_target(&mut r)?,
+            },
+            35 => TokenType::MakeDir {
+                arg: string(&mut r)?,
+            },
+            36 => TokenType::RemoveDir {
+                arg: string(&mut r)?,
+            },
+            37 => TokenType::Rename {
+                from: string(&mut r)?,
+                to: string(&mut r)?,
+            },
+            38 => TokenType::RemoveFile {
+                arg: string(&mut r)?,
+            },
+            39 => TokenType::Exists {
+                arg: string(&mut r)?,
+            },
+            40 => TokenType::FileSize {
+                arg: string(&mut r)?,
+            },
+            41 => TokenType::ListDir {
+                arg: string(&mut r)?,
+            },
+            42 => TokenType::AppendFileStream {
+                arg: string(&mut r)?,
+                new_handle: flag(&mut r)?,
+            },
+            43 => TokenType::ReadWriteFileStream {
+                arg: string(&mut r)?,
+                new_handle: flag(&mut r)?,
+            },
+            44 => TokenType::SeekFileStream {
+                whence: match r.byte()? {
+                    0 => Whence::Start,
+                    1 => Whence::Current,
+                    2 => Whence::End,
+                    w => return Err(BytecodeError::BadWhence(w)),
+                },
+                handle: handle(&mut r)?,
+            },
+            45 => TokenType::TellFileStream {
+                handle: handle(&mut r)?,
+            },
+            46 => TokenType::CloseFileStream {
+                handle: handle(&mut r)?,
+            },
+            _ => return Err(BytecodeError::BadOpcode(op)),
+        });
+    }
+
+    let mut spans: Vec<Span> = Vec::new();
+    if flags & FLAG_DEBUG_INFO != 0 {
+        spans.reserve(token_count);
+        for _ in 0..token_count {
+            spans.push(Span {
+                line: r.varint()? as usize,
+                col: r.varint()? as usize,
+                len: r.varint()? as usize,
+            });
+        }
+    }
+
+    if r.pos != bytes.len() {
+        return Err(BytecodeError::TrailingBytes);
+    }
+
+    Ok(Program { tokens, spans })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, BytecodeError, VERSION};
+    use crate::{
+        interpreter::tokenize,
+        optimize::optimize,
+        token::{Handle, Program, TokenType, Whence},
+        value::NumericModel,
+    };
+
+    const EXAMPLES: [&str; 6] = [
+        include_str!("../examples/echo.stq"),
+        include_str!("../examples/fibonnaci.stq"),
+        include_str!("../examples/hello_file.stq"),
+        include_str!("../examples/hello_world.stq"),
+        include_str!("../examples/infinite_counter.stq"),
+        include_str!("../examples/tribonacci.stq"),
+    ];
+
+    fn compile(source: &str, level: usize) -> Program {
+        let mut program = tokenize(source);
+        optimize(&mut program, level, NumericModel::default());
+        program
+    }
+
+    #[test]
+    fn round_trip() {
+        let extra = "push:-99999999999999999999999 createfile:a.txt jump:nowhere\npush:0 push:-1 pop:B copy:A:C\nlabel:x move:C:A jump:x copy:B:C jump:nowhere\nmkdir:d rename:d:e rename::e rename:e:e rmdir exists:e filesize removefile:x listdir:e listdir\nopenfilestream:a:append openfilestream::readwrite seekfilestream:end seekfilestream tellfilestream\ncreatefilestream:b:new openfilestream:a::new openfilestream::append:new readfilestream:C readfilestream:7 writefilestream:2 seekfilestream::C tellfilestream:3 closefilestream closefilestream:4294967295";
+        for (source, level) in EXAMPLES
+            .iter()
+            .chain([&extra])
+            .flat_map(|s| (0..=2).map(move |level| (s, level)))
+        {
+            let program = compile(source, level);
+
+            let decoded = decode(&encode(&program, true)).unwrap();
+            assert_eq!(decoded, program);
+
+            let decoded = decode(&encode(&program, false)).unwrap();
+            assert_eq!(decoded.tokens, program.tokens);
+            assert!(decoded.spans.is_empty());
+        }
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = encode(&compile("push:1 printnum\njump:x label:x", 0), true);
+
+        assert_eq!(decode(b"push:1"), Err(BytecodeError::BadMagic));
+
+        let mut wrong_version = bytes.clone();
+        wrong_version[4..6].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert_eq!(
+            decode(&wrong_version),
+            Err(BytecodeError::UnsupportedVersion(VERSION + 1))
+        );
+
+        for len in 4..bytes.len() {
+            assert!(decode(&bytes[..len]).is_err(), "truncated to {}", len);
+        }
+
+        let mut trailing = bytes;
+        trailing.push(0);
+        assert_eq!(decode(&trailing), Err(BytecodeError::TrailingBytes));
+
+        //The seek origin comes just before the one byte handle, since there's no debug info
+        let seek = Program {
+            tokens: vec![TokenType::SeekFileStream {
+                whence: Whence::End,
+                handle: Handle::READ,
+            }],
+            spans: Vec::new(),
+        };
+        let mut bad_whence = encode(&seek, false);
+        let at = bad_whence.len() - 2;
+        bad_whence[at] = 3;
+        assert_eq!(decode(&bad_whence), Err(BytecodeError::BadWhence(3)));
+
+        //A stack is one byte, which ends the pop
+        let mut bad_stack = encode(&compile("push:1 pop:B", 0), false);
+        let at = bad_stack.len() - 2;
+        assert_eq!(bad_stack[at], 1);
+        bad_stack[at] = 7;
+        assert_eq!(decode(&bad_stack), Err(BytecodeError::BadStack(7)));
+
+        //`u32::MAX` is stored as 2^32, in the last 5 bytes with the lowest bits first
+        let close = Program {
+            tokens: vec![TokenType::CloseFileStream {
+                handle: Handle::Number(u32::MAX),
+            }],
+            spans: Vec::new(),
+        };
+        let mut bad_handle = encode(&close, false);
+        let at = bad_handle.len() - 5;
+        bad_handle[at] += 1;
+        assert_eq!(
+            decode(&bad_handle),
+            Err(BytecodeError::BadHandle(u32::MAX as u64 + 2))
+        );
+    }
+}
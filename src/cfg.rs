@@ -0,0 +1,268 @@
+use std::fmt::Write;
+
+use crate::{
+    analysis::{basic_blocks, BasicBlock, EdgeKind},
+    token::{Program, TokenType},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CfgFormat {
+    ///Graphviz DOT
+    Dot,
+    ///Mermaid flowchart
+    Mermaid,
+}
+
+impl CfgFormat {
+    pub fn from_name(s: &str) -> Option<CfgFormat> {
+        match s {
+            "dot" => Some(CfgFormat::Dot),
+            "mermaid" => Some(CfgFormat::Mermaid),
+            _ => None,
+        }
+    }
+}
+
+///An edge of the rendered graph. Unlike `analysis::Edge`, this can point to the end of the program or to a missing label
+enum Target {
+    Block(usize),
+    ///Falling off the end of the program or calling `exit`
+    End,
+    ///A jump to a label which doesn't exist, named by the source text of the jump
+    Undefined(String),
+}
+
+struct Node {
+    lines: Vec<String>,
+    reachable: bool,
+    edges: Vec<(Target, &'static str)>,
+}
+
+///Gets the source text of a token from its span
+fn source_text<'a>(source: &'a str, program: &Program, i: usize) -> &'a str {
+    let span = program.spans[i];
+    source
+        .lines()
+        .nth(span.line - 1)
+        .and_then(|line| line.get(span.col - 1..span.col - 1 + span.len))
+        .unwrap_or("")
+}
+
+///Builds the text and edges of each block. `program` must have its jumps resolved, and `source` must be the text it was tokenized from
+fn build_nodes(program: &Program, source: &str) -> Vec<Node> {
+    let tokens = &program.tokens;
+    if tokens.is_empty() {
+        return Vec::new();
+    }
+    let blocks: Vec<BasicBlock> = basic_blocks(tokens);
+
+    //Blocks which can't be reached from the start of the program (such as code after `exit`)
+    let mut reachable = vec![false; blocks.len()];
+    let mut worklist = vec![0];
+    while let Some(b) = worklist.pop() {
+        if !reachable[b] {
+            reachable[b] = true;
+            worklist.extend(blocks[b].successors.iter().map(|e| e.to));
+        }
+    }
+
+    let mut nodes = Vec::with_capacity(blocks.len());
+    for (b, block) in blocks.iter().enumerate() {
+        let mut lines = vec![format!(
+            "block {} (lines {}-{})",
+            b,
+            program.spans[block.start].line,
+            program.spans[block.end - 1].line
+        )];
+
+        //The source commands in the block, grouped by line
+        let mut line = 0;
+        for i in block.start..block.end {
+            let text = source_text(source, program, i);
+            if text.is_empty() {
+                continue;
+            }
+            if program.spans[i].line != line {
+                line = program.spans[i].line;
+                lines.push(format!("{}: {}", line, text));
+            } else {
+                lines.last_mut().unwrap().push_str(&format!(" {}", text));
+            }
+        }
+        for (i, token) in tokens.iter().enumerate().take(block.end).skip(block.start) {
+            lines.push(format!("#{} {}", i, token));
+        }
+
+        let mut edges: Vec<(Target, &'static str)> = block
+            .successors
+            .iter()
+            .map(|e| match e.kind {
+                EdgeKind::Fallthrough => (Target::Block(e.to), "fallthrough"),
+                EdgeKind::Taken => (Target::Block(e.to), "taken"),
+            })
+            .collect();
+        let last = block.end - 1;
+        match &tokens[last] {
+            TokenType::Exit => edges.push((Target::End, "exit")),
+            token => {
+                match token.jump_target() {
+                    Some(t) if t >= tokens.len() => edges.push((
+                        Target::Undefined(source_text(source, program, last).to_string()),
+                        "taken",
+                    )),
+                    //A jump to the last token continues past the end of the program
+                    Some(t) if t + 1 == tokens.len() => edges.push((Target::End, "taken")),
+                    _ => (),
+                }
+                if block.end == tokens.len() {
+                    edges.push((Target::End, "fallthrough"));
+                }
+            }
+        }
+
+        nodes.push(Node {
+            lines,
+            reachable: reachable[b],
+            edges,
+        });
+    }
+
+    nodes
+}
+
+fn escape_dot(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn escape_mermaid(s: &str) -> String {
+    s.replace('&', "#amp;")
+        .replace('"', "#quot;")
+        .replace('<', "#lt;")
+        .replace('>', "#gt;")
+}
+
+///Renders the control flow graph of a program as a Graphviz DOT digraph.
+/// `program` must have its jumps resolved, and `source` must be the text it was tokenized from
+pub fn to_dot(program: &Program, source: &str) -> String {
+    let nodes = build_nodes(program, source);
+    let mut s = String::new();
+
+    writeln!(s, "digraph cfg {{").unwrap();
+    writeln!(s, "    node [shape=box, fontname=\"monospace\"];").unwrap();
+    writeln!(s, "    end [label=\"end\", shape=oval];").unwrap();
+
+    let mut undefined_count = 0;
+    for (b, node) in nodes.iter().enumerate() {
+        let mut label = String::new();
+        for line in &node.lines {
+            label.push_str(&escape_dot(line));
+            label.push_str("\\l");
+        }
+        let style = if node.reachable {
+            ""
+        } else {
+            ", style=dashed, color=gray, fontcolor=gray"
+        };
+        writeln!(s, "    b{} [label=\"{}\"{}];", b, label, style).unwrap();
+
+        for (target, kind) in &node.edges {
+            match target {
+                Target::Block(to) => writeln!(s, "    b{} -> b{} [label=\"{}\"];", b, to, kind),
+                Target::End => writeln!(s, "    b{} -> end [label=\"{}\"];", b, kind),
+                Target::Undefined(text) => {
+                    undefined_count += 1;
+                    writeln!(
+                        s,
+                        "    undefined{} [label=\"undefined label: {}\", shape=octagon, color=red];",
+                        undefined_count,
+                        escape_dot(text)
+                    )
+                    .unwrap();
+                    writeln!(
+                        s,
+                        "    b{} -> undefined{} [label=\"{}\", style=dashed, color=red];",
+                        b, undefined_count, kind
+                    )
+                }
+            }
+            .unwrap();
+        }
+    }
+
+    writeln!(s, "}}").unwrap();
+    s
+}
+
+///Renders the control flow graph of a program as a Mermaid flowchart.
+/// `program` must have its jumps resolved, and `source` must be the text it was tokenized from
+pub fn to_mermaid(program: &Program, source: &str) -> String {
+    let nodes = build_nodes(program, source);
+    let mut s = String::new();
+
+    writeln!(s, "flowchart TD").unwrap();
+    writeln!(s, "    end_node([end])").unwrap();
+
+    let mut undefined_count = 0;
+    for (b, node) in nodes.iter().enumerate() {
+        let label: Vec<String> = node.lines.iter().map(|l| escape_mermaid(l)).collect();
+        writeln!(s, "    b{}[\"{}\"]", b, label.join("<br/>")).unwrap();
+        if !node.reachable {
+            writeln!(s, "    style b{} stroke-dasharray: 5 5,color:gray", b).unwrap();
+        }
+
+        for (target, kind) in &node.edges {
+            match target {
+                Target::Block(to) => writeln!(s, "    b{} -->|{}| b{}", b, kind, to),
+                Target::End => writeln!(s, "    b{} -->|{}| end_node", b, kind),
+                Target::Undefined(text) => {
+                    undefined_count += 1;
+                    writeln!(
+                        s,
+                        "    undefined{}{{{{\"undefined label: {}\"}}}}",
+                        undefined_count,
+                        escape_mermaid(text)
+                    )
+                    .unwrap();
+                    writeln!(s, "    b{} -.->|{}| undefined{}", b, kind, undefined_count)
+                }
+            }
+            .unwrap();
+        }
+    }
+
+    s
+}
+
+///Renders the control flow graph of a program in the given format
+pub fn render(program: &Program, source: &str, format: CfgFormat) -> String {
+    match format {
+        CfgFormat::Dot => to_dot(program, source),
+        CfgFormat::Mermaid => to_mermaid(program, source),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{to_dot, to_mermaid};
+    use crate::{interpreter::tokenize, optimize::resolve_jumps};
+
+    #[test]
+    fn dot() {
+        let source = "push:3 move:C:A\nlabel:l\npush:1 move:C:B - move:C:A\ncopy:A:C jump:l\nexit\nprint jump:nowhere";
+        let mut program = tokenize(source);
+        resolve_jumps(&mut program.tokens);
+
+        let dot = to_dot(&program, source);
+        assert!(dot.starts_with("digraph cfg {"));
+        assert!(dot.contains("b1 -> b1 [label=\"taken\"];"));
+        assert!(dot.contains("b1 -> b2 [label=\"fallthrough\"];"));
+        assert!(dot.contains("b2 -> end [label=\"exit\"];"));
+        assert!(dot.contains("undefined label: jump:nowhere"));
+        //The code after `exit` is unreachable
+        assert!(dot.contains("style=dashed, color=gray"));
+
+        let mermaid = to_mermaid(&program, source);
+        assert!(mermaid.contains("b1 -->|taken| b1"));
+        assert!(mermaid.contains("-.->|taken| undefined1"));
+    }
+}
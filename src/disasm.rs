@@ -0,0 +1,538 @@
+use std::{fmt::Display, fmt::Write, str::FromStr};
+
+use num::bigint::BigInt;
+
+use crate::{
+    analysis::STACK_NAMES,
+    bytecode::constant_pool,
+    token::{Handle, Program, Span, TokenType, Whence},
+};
+
+///An error in assembly text, with the (1-based) line it was found on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn stack_name(stack: u8) -> String {
+    match STACK_NAMES.get(stack as usize) {
+        Some(name) => name.to_string(),
+        None => stack.to_string(),
+    }
+}
+
+///Gets the symbolic name of a jump target: the label's name if it is the first label with that name (the one `resolve_jumps` would pick),
+/// `#index` for any other token, or `undefined`
+fn jump_target(tokens: &[TokenType], target: usize) -> String {
+    match tokens.get(target) {
+        Some(TokenType::Label { arg }) => {
+            let first = tokens
+                .iter()
+                .position(|t| matches!(t, TokenType::Label { arg: a } if a == arg));
+            if first == Some(target) {
+                format!("{:?}", arg)
+            } else {
+                format!("#{}", target)
+            }
+        }
+        Some(_) => format!("#{}", target),
+        None => "undefined".to_string(),
+    }
+}
+
+///Pretty prints a program, one token per line. The output can be turned back into the same program with `assemble`.
+///
+/// Each line is the token index, the token kind and its operands. Strings are quoted, and jump targets are written as label names where possible.
+/// If the program has spans, each line ends with `@line:col+len`. Everything after a `;` is a comment
+pub fn disassemble(program: &Program) -> String {
+    let tokens = &program.tokens;
+    let mut s = String::new();
+
+    let pool = constant_pool(program);
+    writeln!(s, "; {} tokens", tokens.len()).unwrap();
+    writeln!(s, "; constant pool ({} entries):", pool.len()).unwrap();
+    for (i, constant) in pool.iter().enumerate() {
+        writeln!(s, ";   #{:<4} {}", i, constant).unwrap();
+    }
+    writeln!(s).unwrap();
+
+    let with_spans = !program.spans.is_empty();
+    let width = tokens.len().saturating_sub(1).to_string().len();
+    for (i, token) in tokens.iter().enumerate() {
+        let mut line = format!("{:>width$}  {}", i, token.kind(), width = width);
+        let mut comment: Option<String> = None;
+
+        match token {
+            TokenType::Push { arg } => write!(line, " {}", arg).unwrap(),
+            TokenType::Pop { arg } => write!(line, " {}", stack_name(*arg)).unwrap(),
+            TokenType::Move { arg } | TokenType::Copy { arg } => {
+                write!(line, " {} {}", stack_name(arg[0]), stack_name(arg[1])).unwrap()
+            }
+            TokenType::CreateFile { arg }
+            | TokenType::MakeDir { arg }
+            | TokenType::RemoveDir { arg }
+            | TokenType::RemoveFile { arg }
+            | TokenType::Exists { arg }
+            | TokenType::FileSize { arg }
+            | TokenType::ListDir { arg }
+            | TokenType::PreComputeJump { arg }
+            | TokenType::Label { arg } => write!(line, " {:?}", arg).unwrap(),
+            TokenType::Rename { from, to } => write!(line, " {:?} {:?}", from, to).unwrap(),
+            //Streams are opened into a new handle, or into the default handles 0 and 1
+            TokenType::CreateFileStream { arg, new_handle }
+            | TokenType::OpenFileStream { arg, new_handle }
+            | TokenType::AppendFileStream { arg, new_handle }
+            | TokenType::ReadWriteFileStream { arg, new_handle } => write!(
+                line,
+                " {:?} {}",
+                arg,
+                if *new_handle { "new" } else { "default" }
+            )
+            .unwrap(),
+            TokenType::ReadFileStream { handle }
+            | TokenType::WriteFileStream { handle }
+            | TokenType::TellFileStream { handle }
+            | TokenType::CloseFileStream { handle } => write!(line, " {}", handle).unwrap(),
+            TokenType::SeekFileStream { whence, handle } => {
+                write!(line, " {} {}", whence.name(), handle).unwrap()
+            }
+            TokenType::PushTo { stack, value } => {
+                write!(line, " {} {}", stack_name(*stack), value).unwrap()
+            }
+            TokenType::JumpIfTopPositive { stack, .. } => {
+                write!(line, " {}", stack_name(*stack)).unwrap()
+            }
+            _ => (),
+        }
+        if let Some(target) = token.jump_target() {
+            write!(line, " {}", jump_target(tokens, target)).unwrap();
+            if target < tokens.len() {
+                comment = Some(format!("-> {}", target));
+            }
+        }
+
+        if with_spans {
+            let span = program.spans.get(i).copied().unwrap_or_default();
+            write!(line, "  @{}:{}+{}", span.line, span.col, span.len).unwrap();
+        }
+        if let Some(comment) = comment {
+            write!(line, "  ; {}", comment).unwrap();
+        }
+        writeln!(s, "{}", line).unwrap();
+    }
+
+    s
+}
+
+///Splits a line of assembly into words, keeping quoted strings (with their quotes) as single words and dropping the comment
+fn split_words(line: &str) -> Result<Vec<&str>, String> {
+    let mut words = Vec::new();
+    let mut chars = line.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c.is_whitespace() {
+            continue;
+        }
+        if c == ';' {
+            break;
+        }
+
+        let mut end = line.len();
+        if c == '"' {
+            let mut escaped = false;
+            let mut closed = false;
+            for (i, c) in chars.by_ref() {
+                if escaped {
+                    escaped = false;
+                } else if c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    end = i + 1;
+                    closed = true;
+                    break;
+                }
+            }
+            if !closed {
+                return Err("unterminated string".to_string());
+            }
+        } else {
+            while let Some((i, c)) = chars.peek() {
+                if c.is_whitespace() || *c == ';' {
+                    end = *i;
+                    break;
+                }
+                chars.next();
+            }
+        }
+        words.push(&line[start..end]);
+    }
+
+    Ok(words)
+}
+
+///Reverses the escaping of `{:?}` on a quoted string
+fn unquote(word: &str) -> Result<String, String> {
+    let inner = word
+        .strip_prefix('"')
+        .and_then(|w| w.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, found {}", word))?;
+
+    let mut s = String::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            s.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => s.push('\n'),
+            Some('r') => s.push('\r'),
+            Some('t') => s.push('\t'),
+            Some('0') => s.push('\0'),
+            Some(c @ ('\\' | '"' | '\'')) => s.push(c),
+            Some('u') => {
+                let rest: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                let code = rest
+                    .strip_prefix('{')
+                    .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| format!("bad unicode escape in {}", word))?;
+                s.push(code);
+            }
+            _ => return Err(format!("bad escape in {}", word)),
+        }
+    }
+    Ok(s)
+}
+
+fn parse_stack(word: &str) -> Result<u8, String> {
+    match STACK_NAMES
+        .iter()
+        .position(|name| word.len() == 1 && word.starts_with(*name))
+    {
+        Some(i) => Ok(i as u8),
+        None => word
+            .parse()
+            .map_err(|_| format!("expected a stack, found {}", word)),
+    }
+}
+
+///Parses `@line:col+len`
+fn parse_span(word: &str) -> Option<Span> {
+    let (line, rest) = word.strip_prefix('@')?.split_once(':')?;
+    let (col, len) = rest.split_once('+')?;
+    Some(Span {
+        line: line.parse().ok()?,
+        col: col.parse().ok()?,
+        len: len.parse().ok()?,
+    })
+}
+
+///A jump target as written in assembly, resolved once every label is known
+enum Target {
+    Label(String),
+    Index(usize),
+    Undefined,
+}
+
+///Parses the output of `disassemble` back into a program
+pub fn assemble(text: &str) -> Result<Program, AsmError> {
+    let mut program = Program::new();
+    let mut targets: Vec<(usize, usize, Target)> = Vec::new();
+    let mut spans: Vec<Option<Span>> = Vec::new();
+
+    for (line_index, line) in text.lines().enumerate() {
+        let err = |message: String| AsmError {
+            line: line_index + 1,
+            message,
+        };
+        let mut words = split_words(line).map_err(err)?;
+        if words.is_empty() {
+            continue;
+        }
+
+        let index = program.tokens.len();
+        if words[0].chars().all(|c| c.is_ascii_digit()) {
+            if words[0].parse() != Ok(index) {
+                return Err(err(format!(
+                    "expected token index {}, found {}",
+                    index, words[0]
+                )));
+            }
+            words.remove(0);
+        }
+        let span = match words.last().filter(|w| w.starts_with('@')) {
+            Some(w) => Some(parse_span(w).ok_or_else(|| err(format!("bad span {}", w)))?),
+            None => None,
+        };
+        if span.is_some() {
+            words.pop();
+        }
+
+        let (kind, operands) = match words.split_first() {
+            Some((kind, operands)) => (*kind, operands),
+            None => return Err(err("expected a token".to_string())),
+        };
+        let expected = match kind {
+            "Push" | "Pop" | "CreateFile" | "ReadFileStream" | "WriteFileStream"
+            | "TellFileStream" | "CloseFileStream" | "MakeDir" | "RemoveDir" | "RemoveFile"
+            | "Exists" | "FileSize" | "ListDir" | "PreComputeJump" | "Jump" | "Label" => 1,
+            "Move"
+            | "Copy"
+            | "PushTo"
+            | "JumpIfTopPositive"
+            | "Rename"
+            | "CreateFileStream"
+            | "OpenFileStream"
+            | "AppendFileStream"
+            | "ReadWriteFileStream"
+            | "SeekFileStream" => 2,
+            _ => 0,
+        };
+        if operands.len() != expected {
+            return Err(err(format!(
+                "{} takes {} operands, found {}",
+                kind,
+                expected,
+                operands.len()
+            )));
+        }
+        let string = |i: usize| unquote(operands[i]).map_err(err);
+        let stack = |i: usize| parse_stack(operands[i]).map_err(err);
+        let number = |i: usize| {
+            BigInt::from_str(operands[i])
+                .map_err(|_| err(format!("expected a number, found {}", operands[i])))
+        };
+        let handle = |i: usize| {
+            Handle::from_name(operands[i])
+                .ok_or_else(|| err(format!("expected a handle, found {}", operands[i])))
+        };
+        let new_handle = |i: usize| match operands[i] {
+            "new" => Ok(true),
+            "default" => Ok(false),
+            other => Err(err(format!("expected new or default, found {}", other))),
+        };
+        let target = |i: usize| -> Result<Target, AsmError> {
+            let operand = operands[i];
+            Ok(if operand == "undefined" {
+                Target::Undefined
+            } else if let Some(n) = operand.strip_prefix('#') {
+                Target::Index(
+                    n.parse()
+                        .map_err(|_| err(format!("bad jump target {}", operand)))?,
+                )
+            } else {
+                Target::Label(string(i)?)
+            })
+        };
+
+        let token = match kind {
+            "Exit" => TokenType::Exit,
+            "Print" => TokenType::Print,
+            "PrintNum" => TokenType::PrintNum,
+            "GetNextIn" => TokenType::GetNextIn,
+            "CreateFile" => TokenType::CreateFile { arg: string(0)? },
+            "CreateFileStream" => TokenType::CreateFileStream {
+                arg: string(0)?,
+                new_handle: new_handle(1)?,
+            },
+            "OpenFileStream" => TokenType::OpenFileStream {
+                arg: string(0)?,
+                new_handle: new_handle(1)?,
+            },
+            "ReadFileStream" => TokenType::ReadFileStream { handle: handle(0)? },
+            "WriteFileStream" => TokenType::WriteFileStream { handle: handle(0)? },
+            "AppendFileStream" => TokenType::AppendFileStream {
+                arg: string(0)?,
+                new_handle: new_handle(1)?,
+            },
+            "ReadWriteFileStream" => TokenType::ReadWriteFileStream {
+                arg: string(0)?,
+                new_handle: new_handle(1)?,
+            },
+            "SeekFileStream" => TokenType::SeekFileStream {
+                whence: Whence::from_name(operands[0])
+                    .ok_or_else(|| err(format!("unknown seek origin {}", operands[0])))?,
+                handle: handle(1)?,
+            },
+            "TellFileStream" => TokenType::TellFileStream { handle: handle(0)? },
+            "CloseFileStream" => TokenType::CloseFileStream { handle: handle(0)? },
+            "MakeDir" => TokenType::MakeDir { arg: string(0)? },
+            "RemoveDir" => TokenType::RemoveDir { arg: string(0)? },
+            "Rename" => TokenType::Rename {
+                from: string(0)?,
+                to: string(1)?,
+            },
+            "RemoveFile" => TokenType::RemoveFile { arg: string(0)? },
+            "Exists" => TokenType::Exists { arg: string(0)? },
+            "FileSize" => TokenType::FileSize { arg: string(0)? },
+            "ListDir" => TokenType::ListDir { arg: string(0)? },
+            "Clear" => TokenType::Clear,
+            "Push" => TokenType::Push { arg: number(0)? },
+            "Pop" => TokenType::Pop { arg: stack(0)? },
+            "Add" => TokenType::Add,
+            "Subtract" => TokenType::Subtract,
+            "Multiply" => TokenType::Multiply,
+            "Divide" => TokenType::Divide,
+            "Modulo" => TokenType::Modulo,
+            "Move" => TokenType::Move {
+                arg: [stack(0)?, stack(1)?],
+            },
+            "Copy" => TokenType::Copy {
+                arg: [stack(0)?, stack(1)?],
+            },
+            "PreComputeJump" => TokenType::PreComputeJump { arg: string(0)? },
+            "Jump" => {
+                targets.push((line_index + 1, index, target(0)?));
+                TokenType::Jump { arg: usize::MAX }
+            }
+            "Label" => TokenType::Label { arg: string(0)? },
+            "Equal" => TokenType::Equal,
+            "LessThan" => TokenType::LessThan,
+            "LessThanOrEqual" => TokenType::LessThanOrEqual,
+            "GreaterThan" => TokenType::GreaterThan,
+            "GreaterThanOrEqual" => TokenType::GreaterThanOrEqual,
+            "BitAnd" => TokenType::BitAnd,
+            "BitOr" => TokenType::BitOr,
+            "BitXor" => TokenType::BitXor,
+            "BitRightShift" => TokenType::BitRightShift,
+            "BitLeftShift" => TokenType::BitLeftShift,
+            "PushTo" => TokenType::PushTo {
+                stack: stack(0)?,
+                value: number(1)?,
+            },
+            "DecA" => TokenType::DecA,
+            "JumpIfTopPositive" => {
+                targets.push((line_index + 1, index, target(1)?));
+                TokenType::JumpIfTopPositive {
+                    stack: stack(0)?,
+                    target: usize::MAX,
+                }
+            }
+            _ => return Err(err(format!("unknown token {}", kind))),
+        };
+        program.tokens.push(token);
+        spans.push(span);
+    }
+
+    //Jumps may refer to labels which come after them, so they are resolved last
+    for (line, index, target) in targets {
+        let resolved = match target {
+            Target::Undefined => usize::MAX,
+            Target::Index(i) if i < program.tokens.len() => i,
+            Target::Index(i) => {
+                return Err(AsmError {
+                    line,
+                    message: format!("jump target #{} is past the end of the program", i),
+                })
+            }
+            Target::Label(name) => program
+                .tokens
+                .iter()
+                .position(|t| matches!(t, TokenType::Label { arg } if *arg == name))
+                .ok_or_else(|| AsmError {
+                    line,
+                    message: format!("no label named {:?}", name),
+                })?,
+        };
+        match &mut program.tokens[index] {
+            TokenType::Jump { arg } | TokenType::JumpIfTopPositive { target: arg, .. } => {
+                *arg = resolved
+            }
+            _ => unreachable!("only jumps have targets"),
+        }
+    }
+
+    //Spans are all or nothing, since `spans[i]` must be the span of `tokens[i]`
+    if spans.iter().all(|s| s.is_some()) {
+        program.spans = spans.into_iter().flatten().collect();
+    } else if spans.iter().any(|s| s.is_some()) {
+        let missing = spans.iter().position(|s| s.is_none()).unwrap();
+        return Err(AsmError {
+            line: 0,
+            message: format!(
+                "token {} has no span, but other tokens do. Either every token or none must have one",
+                missing
+            ),
+        });
+    }
+
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use num::bigint::BigInt;
+
+    use super::{assemble, disassemble};
+    use crate::{
+        interpreter::tokenize,
+        optimize::optimize,
+        token::{Program, TokenType},
+        value::NumericModel,
+    };
+
+    #[test]
+    fn round_trip() {
+        let examples = [
+            include_str!("../examples/fibonnaci.stq"),
+            include_str!("../examples/hello_file.stq"),
+            include_str!("../examples/echo.stq"),
+            "push:-123456789012345678901234567890 jump:missing\nlabel:a label:a jump:a createfile:x.txt pop:B copy:A:C\ncopy:C:C jump:missing",
+            "mkdir:d rename:d:e rename::e rename:d rename\nexists:d filesize:e rmdir removefile:x.txt listdir:d listdir",
+            "openfilestream:a:append openfilestream::readwrite openfilestream:b:read\nseekfilestream seekfilestream:current seekfilestream:end tellfilestream",
+            "createfilestream:a:new openfilestream::readwrite:new readfilestream:C writefilestream:3\nseekfilestream:current:C tellfilestream:2 closefilestream closefilestream:5",
+        ];
+        for (source, level) in examples
+            .into_iter()
+            .flat_map(|s| (0..=2).map(move |level| (s, level)))
+        {
+            let mut program = tokenize(source);
+            optimize(&mut program, level, NumericModel::default());
+
+            let text = disassemble(&program);
+            assert_eq!(assemble(&text), Ok(program.clone()), "{}", text);
+
+            program.spans.clear();
+            assert_eq!(assemble(&disassemble(&program)), Ok(program));
+        }
+    }
+
+    #[test]
+    fn symbolic_targets() {
+        let program = Program {
+            tokens: vec![
+                TokenType::Label { arg: "x".into() },
+                TokenType::Label { arg: "x".into() },
+                TokenType::CreateFile {
+                    arg: "odd \"name\"; with\tescapes é".into(),
+                },
+                TokenType::Jump { arg: 0 },
+                TokenType::Jump { arg: 1 },
+                TokenType::Jump { arg: 2 },
+                TokenType::Jump { arg: usize::MAX },
+                TokenType::Push {
+                    arg: BigInt::from(7),
+                },
+            ],
+            spans: Vec::new(),
+        };
+
+        let text = disassemble(&program);
+        assert!(text.contains(";   #0    \"x\""));
+        assert!(text.contains("3  Jump \"x\"  ; -> 0"));
+        assert!(text.contains("4  Jump #1  ; -> 1"));
+        assert!(text.contains("6  Jump undefined\n"));
+        assert_eq!(assemble(&text), Ok(program));
+
+        assert_eq!(assemble("Jump \"nope\"").unwrap_err().line, 1);
+        assert!(assemble("0  Push 1\n2  Push 2").is_err());
+    }
+}
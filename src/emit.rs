@@ -0,0 +1,185 @@
+use crate::token::TokenType;
+
+///Where execution continues after a taken jump to `target`, or `None` if the jump's label doesn't exist.
+/// Like the interpreter, the target token itself is skipped, so the result can be `tokens.len()` (the end of the program)
+pub fn jump_entry(tokens: &[TokenType], target: usize) -> Option<usize> {
+    if target < tokens.len() {
+        Some(target + 1)
+    } else {
+        None
+    }
+}
+
+///Splits a resolved program into the blocks of a dispatch loop, returning the index each block starts at.
+/// A block starts at the beginning of the program, wherever a jump can land, and after every jump and `Exit`
+pub fn dispatch_blocks(tokens: &[TokenType]) -> Vec<usize> {
+    let mut starts = vec![false; tokens.len() + 1];
+    starts[0] = true;
+    for (i, token) in tokens.iter().enumerate() {
+        if let Some(target) = token.jump_target() {
+            if let Some(entry) = jump_entry(tokens, target) {
+                starts[entry] = true;
+            }
+            starts[i + 1] = true;
+        } else if let TokenType::Exit = token {
+            starts[i + 1] = true;
+        }
+    }
+
+    //A block at the very end would be empty
+    starts.pop();
+    (0..tokens.len()).filter(|i| starts[*i]).collect()
+}
+
+#[cfg(test)]
+pub mod tests {
+    //Helpers shared by the tests of each backend, which compare generated programs against the interpreter
+
+    use std::{
+        cell::RefCell,
+        fs,
+        io::{self, Write},
+        path::PathBuf,
+        process::{Command, Stdio},
+        rc::Rc,
+    };
+
+    use crate::{
+        interpreter::{run_program, tokenize, RunOptions},
+        optimize::optimize,
+        token::Program,
+        value::NumericModel,
+        vfs::VirtualFileSystem,
+    };
+
+    ///A writer whose contents can still be read after it has been given away
+    #[derive(Clone, Default)]
+    pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    ///The examples which finish on their own, with the input to give them. Tribonacci runs fewer iterations to keep the tests fast
+    pub fn examples() -> Vec<(&'static str, String, &'static str)> {
+        vec![
+            (
+                "directories",
+                include_str!("../examples/directories.stq").to_string(),
+                "",
+            ),
+            (
+                "echo",
+                include_str!("../examples/echo.stq").to_string(),
+                "hello",
+            ),
+            (
+                "fibonnaci",
+                include_str!("../examples/fibonnaci.stq").to_string(),
+                "",
+            ),
+            (
+                "handles",
+                include_str!("../examples/handles.stq").to_string(),
+                "",
+            ),
+            (
+                "hello_file",
+                include_str!("../examples/hello_file.stq").to_string(),
+                "",
+            ),
+            (
+                "hello_world",
+                include_str!("../examples/hello_world.stq").to_string(),
+                "",
+            ),
+            (
+                "records",
+                include_str!("../examples/records.stq").to_string(),
+                "",
+            ),
+            (
+                "tribonacci",
+                include_str!("../examples/tribonacci.stq").replace("push:500000", "push:3000"),
+                "",
+            ),
+        ]
+    }
+
+    ///Runs source code in the interpreter with a virtual file system, returning what it printed
+    pub fn interpret(source: &str, input: &'static str, model: NumericModel) -> String {
+        let mut program = tokenize(source);
+        optimize(&mut program, 0, model);
+        interpret_program(program, input, model)
+    }
+
+    ///Like `interpret`, for a program which has already been optimized
+    pub fn interpret_program(program: Program, input: &'static str, model: NumericModel) -> String {
+        let output = SharedBuffer::default();
+        run_program(
+            program,
+            Box::new(VirtualFileSystem::new()),
+            RunOptions {
+                numeric_model: model,
+                output: Some(Box::new(output.clone())),
+                input: Some(Box::new(input.as_bytes())),
+                ..Default::default()
+            },
+        );
+        let out = output.0.borrow().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    pub fn tool_available(tool: &str) -> bool {
+        Command::new(tool).arg("--version").output().is_ok()
+    }
+
+    ///Writes `code` to `file` in a fresh directory and builds it with the `build` command, which must produce an executable named `main`.
+    /// Then runs it in that directory, returning its stdout and whether it succeeded
+    pub fn build_and_run(
+        name: &str,
+        file: &str,
+        code: &str,
+        build: &[&str],
+        input: &str,
+    ) -> (String, bool) {
+        let dir: PathBuf =
+            std::env::temp_dir().join(format!("staq-emit-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(file), code).unwrap();
+
+        let status = Command::new(build[0])
+            .args(&build[1..])
+            .current_dir(&dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "{} failed to build", name);
+
+        let mut child = Command::new(dir.join("main"))
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .unwrap();
+        let result = child.wait_with_output().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+        (
+            String::from_utf8(result.stdout).unwrap(),
+            result.status.success(),
+        )
+    }
+}
@@ -0,0 +1,297 @@
+use std::fmt::Write;
+
+use num::BigInt;
+
+use crate::{
+    emit::{dispatch_blocks, jump_entry},
+    token::{Handle, Program, TokenType, Whence},
+    value::{BinaryOp, NumericModel, Value},
+};
+
+const RUNTIME: &str = include_str!("runtime/c_runtime.c");
+
+///Writes a string as a C string literal. Anything other than printable ASCII is an octal escape
+fn c_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for b in s.bytes() {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            //`?` is escaped so it can't form a trigraph
+            b'?' => out.push_str("\\?"),
+            0x20..=0x7e => out.push(b as char),
+            _ => write!(out, "\\{:03o}", b).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+///An `int64_t` literal. The most negative value can't be written as a negated literal
+fn c_int(n: i64) -> String {
+    if n == i64::MIN {
+        "INT64_MIN".to_string()
+    } else {
+        format!("INT64_C({})", n)
+    }
+}
+
+///A constant as a wrap64 `int64_t` literal
+fn constant(n: &BigInt) -> String {
+    match NumericModel::Wrap64.constant(n) {
+        Ok(Value::Small(n)) => c_int(n),
+        _ => unreachable!("wrap64 constants always fit in an i64"),
+    }
+}
+
+///The argument of a file command: `NULL` reads the path from stack C
+fn path_arg(arg: &str) -> String {
+    if arg.is_empty() {
+        "NULL".to_string()
+    } else {
+        c_string(arg)
+    }
+}
+
+///The handle of a stream command, which is popped from stack C if it isn't an argument
+fn handle_arg(handle: &Handle) -> String {
+    match handle {
+        Handle::Number(n) => n.to_string(),
+        Handle::Popped => "pop(2)".to_string(),
+    }
+}
+
+///The stream of a stream command, or `NULL` if its handle isn't open
+fn stream_arg(handle: &Handle) -> String {
+    format!("stream_at({})", handle_arg(handle))
+}
+
+///Generates the statements of a single token
+fn emit_token(tokens: &[TokenType], i: usize) -> String {
+    let stack = |s: u8| -> Option<u8> { (s < 3).then_some(s) };
+    let invalid_stack = format!("fail({}, \"invalid stack\");", i);
+
+    match &tokens[i] {
+        TokenType::Exit => "return;".to_string(),
+        TokenType::Print => format!("print({});", i),
+        TokenType::PrintNum => "print_num();".to_string(),
+        TokenType::GetNextIn => "get_next_in();".to_string(),
+        TokenType::CreateFile { arg } => format!("create_file({}, {});", i, path_arg(arg)),
+        TokenType::CreateFileStream { arg, new_handle } => format!(
+            "create_file_stream({}, {}, {});",
+            i,
+            path_arg(arg),
+            *new_handle as u8
+        ),
+        TokenType::OpenFileStream { arg, new_handle } => format!(
+            "open_file_stream({}, {}, {});",
+            i,
+            path_arg(arg),
+            *new_handle as u8
+        ),
+        TokenType::ReadFileStream { handle } => {
+            format!("read_file_stream({});", stream_arg(handle))
+        }
+        TokenType::WriteFileStream { handle } => {
+            format!("write_file_stream({}, {});", i, stream_arg(handle))
+        }
+        TokenType::AppendFileStream { arg, new_handle } => format!(
+            "append_file_stream({}, {}, {});",
+            i,
+            path_arg(arg),
+            *new_handle as u8
+        ),
+        TokenType::ReadWriteFileStream { arg, new_handle } => format!(
+            "read_write_file_stream({}, {}, {});",
+            i,
+            path_arg(arg),
+            *new_handle as u8
+        ),
+        TokenType::SeekFileStream { whence, handle } => format!(
+            "seek_file_stream({}, {});",
+            stream_arg(handle),
+            match whence {
+                Whence::Start => "SEEK_SET",
+                Whence::Current => "SEEK_CUR",
+                Whence::End => "SEEK_END",
+            }
+        ),
+        TokenType::TellFileStream { handle } => {
+            format!("tell_file_stream({});", stream_arg(handle))
+        }
+        TokenType::CloseFileStream { handle } => {
+            format!("close_file_stream({});", handle_arg(handle))
+        }
+        TokenType::MakeDir { arg } => format!("make_dir({}, {});", i, path_arg(arg)),
+        TokenType::RemoveDir { arg } => format!("remove_dir({}, {});", i, path_arg(arg)),
+        TokenType::Rename { from, to } => {
+            format!("rename_path({}, {}, {});", i, path_arg(from), path_arg(to))
+        }
+        TokenType::RemoveFile { arg } => format!("remove_file({}, {});", i, path_arg(arg)),
+        TokenType::Exists { arg } => format!("exists({}, {});", i, path_arg(arg)),
+        TokenType::FileSize { arg } => format!("file_size({}, {});", i, path_arg(arg)),
+        TokenType::ListDir { arg } => format!("list_dir({}, {});", i, path_arg(arg)),
+        TokenType::Clear => "stacks[2].len = 0;".to_string(),
+        TokenType::Push { arg } => format!("push(2, {});", constant(arg)),
+        TokenType::Pop { arg } => match stack(*arg) {
+            Some(s) => format!("pop({});", s),
+            None => invalid_stack,
+        },
+        TokenType::Move { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => format!("push({}, pop({}));", to, from),
+            _ => invalid_stack,
+        },
+        TokenType::Copy { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => format!("copy({}, {});", from, to),
+            _ => invalid_stack,
+        },
+        TokenType::Jump { arg } => match jump_entry(tokens, *arg) {
+            Some(entry) => format!("if (pop(2) > 0) {{ pc = {}; break; }}", entry),
+            //Jumps to missing labels do nothing, other than popping C
+            None => "pop(2);".to_string(),
+        },
+        TokenType::PushTo { stack: s, value } => match stack(*s) {
+            Some(s) => format!("push({}, {});", s, constant(value)),
+            None => invalid_stack,
+        },
+        TokenType::DecA => "push(0, op_sub(pop(0), INT64_C(1)));".to_string(),
+        TokenType::JumpIfTopPositive { stack: s, target } => {
+            match (stack(*s), jump_entry(tokens, *target)) {
+                (Some(s), Some(entry)) => format!(
+                    "{{ int64_t n = pop({0}); push({0}, n); if (n > 0) {{ pc = {1}; break; }} }}",
+                    s, entry
+                ),
+                (Some(s), None) => format!("push({0}, pop({0}));", s),
+                (None, _) => invalid_stack,
+            }
+        }
+        //Labels only matter as jump targets, and unresolved jumps are never executed
+        TokenType::Label { .. } | TokenType::PreComputeJump { .. } => String::new(),
+        token => {
+            let op = token
+                .binary_op()
+                .expect("every other token is a binary operator");
+            let result = match op {
+                BinaryOp::Add => "op_add(a, b)".to_string(),
+                BinaryOp::Subtract => "op_sub(a, b)".to_string(),
+                BinaryOp::Multiply => "op_mul(a, b)".to_string(),
+                BinaryOp::Divide => format!("op_div({}, a, b)", i),
+                BinaryOp::Modulo => format!("op_rem({}, a, b)", i),
+                BinaryOp::Equal => "a == b".to_string(),
+                BinaryOp::LessThan => "a < b".to_string(),
+                BinaryOp::LessThanOrEqual => "a <= b".to_string(),
+                BinaryOp::GreaterThan => "a > b".to_string(),
+                BinaryOp::GreaterThanOrEqual => "a >= b".to_string(),
+                BinaryOp::BitAnd => "a & b".to_string(),
+                BinaryOp::BitOr => "a | b".to_string(),
+                BinaryOp::BitXor => "a ^ b".to_string(),
+                BinaryOp::BitRightShift => "op_shr(a, b)".to_string(),
+                BinaryOp::BitLeftShift => "op_shl(a, b)".to_string(),
+            };
+            format!(
+                "{{ int64_t a = pop(0); int64_t b = pop(1); push(2, {}); }}",
+                result
+            )
+        }
+    }
+}
+
+///Generates a single C99 file which runs `program`. Values are 64 bit integers which wrap on overflow, as in the wrap64 numeric model.
+/// The directory commands use POSIX functions. Jumps must already be resolved
+pub fn emit_c(program: &Program) -> String {
+    let tokens = &program.tokens;
+    let blocks = dispatch_blocks(tokens);
+
+    let mut s = String::new();
+    writeln!(
+        s,
+        "/* Generated by `staq-lang-parser emit-c` with the wrap64 numeric model. Build with `cc -O2 -std=c99` */\n"
+    )
+    .unwrap();
+    s.push_str(RUNTIME);
+
+    writeln!(s, "\nstatic void run(void) {{").unwrap();
+    writeln!(s, "    size_t pc = 0;").unwrap();
+    writeln!(s, "    for (;;) {{").unwrap();
+    writeln!(s, "        switch (pc) {{").unwrap();
+    for (b, &start) in blocks.iter().enumerate() {
+        let end = blocks.get(b + 1).copied().unwrap_or(tokens.len());
+        writeln!(s, "        case {}:", start).unwrap();
+        for (i, token) in tokens.iter().enumerate().take(end).skip(start) {
+            let line = match program.spans.get(i) {
+                Some(span) => format!(" (line {})", span.line),
+                None => String::new(),
+            };
+            //The token is escaped in case a string argument contains `*/`
+            let comment = format!("{}", token).replace("*/", "*\\/");
+            writeln!(s, "            /* #{} {}{} */", i, comment, line).unwrap();
+            let code = emit_token(tokens, i);
+            if !code.is_empty() {
+                writeln!(s, "            {}", code).unwrap();
+            }
+        }
+        //Falling through to the next block, which is the end of the program after the last one
+        writeln!(s, "            pc = {};", end).unwrap();
+        writeln!(s, "            break;").unwrap();
+    }
+    writeln!(s, "        default:").unwrap();
+    writeln!(s, "            return;").unwrap();
+    writeln!(s, "        }}").unwrap();
+    writeln!(s, "    }}").unwrap();
+    writeln!(s, "}}").unwrap();
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{c_string, emit_c};
+    use crate::{
+        emit::tests::{build_and_run, examples, interpret, tool_available},
+        interpreter::tokenize,
+        optimize::optimize,
+        value::NumericModel,
+    };
+
+    #[test]
+    fn escapes() {
+        assert_eq!(c_string("a\"b\\c?é\n"), "\"a\\\"b\\\\c\\?\\303\\251\\012\"");
+
+        let mut program = tokenize("push:-9223372036854775808 push:18446744073709551615 jump:x");
+        optimize(&mut program, 0, NumericModel::Wrap64);
+        let c = emit_c(&program);
+        assert!(c.contains("push(2, INT64_MIN);"));
+        assert!(c.contains("push(2, INT64_C(-1));"));
+        assert!(c.contains("            pop(2);\n"));
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        if !tool_available("cc") {
+            eprintln!("cc not found, skipping");
+            return;
+        }
+
+        for ((name, source, input), level) in examples()
+            .into_iter()
+            .flat_map(|e| (0..=2).map(move |level| (e.clone(), level)))
+        {
+            let expected = interpret(&source, input, NumericModel::Wrap64);
+
+            //Optimized programs must behave just like the tokens they replace
+            let mut program = tokenize(&source);
+            optimize(&mut program, level, NumericModel::Wrap64);
+            let (output, ok) = build_and_run(
+                &format!("c-{}-O{}", name, level),
+                "main.c",
+                &emit_c(&program),
+                &[
+                    "cc", "-O2", "-std=c99", "-Wall", "-Werror", "main.c", "-o", "main",
+                ],
+                input,
+            );
+            assert!(ok, "{} -O {} failed", name, level);
+            assert_eq!(output, expected, "{} -O {}", name, level);
+        }
+    }
+}
@@ -0,0 +1,489 @@
+use std::fmt::Write;
+
+use num::BigInt;
+
+use crate::{
+    emit::{dispatch_blocks, jump_entry},
+    token::{Handle, Program, TokenType},
+    value::{BinaryOp, NumericModel, Value},
+};
+
+const COMMON_RUNTIME: &str = include_str!("runtime/rust_common.rs");
+const BIGINT_RUNTIME: &str = include_str!("runtime/rust_bigint.rs");
+const WRAP64_RUNTIME: &str = include_str!("runtime/rust_wrap64.rs");
+const CHECKED64_RUNTIME: &str = include_str!("runtime/rust_checked64.rs");
+
+///The runtime function implementing an arithmetic, bitwise or shift operator
+fn op_function(op: BinaryOp) -> &'static str {
+    match op {
+        BinaryOp::Add => "num_add",
+        BinaryOp::Subtract => "num_sub",
+        BinaryOp::Multiply => "num_mul",
+        BinaryOp::Divide => "num_div",
+        BinaryOp::Modulo => "num_rem",
+        BinaryOp::BitAnd => "num_and",
+        BinaryOp::BitOr => "num_or",
+        BinaryOp::BitXor => "num_xor",
+        BinaryOp::BitRightShift => "num_shr",
+        BinaryOp::BitLeftShift => "num_shl",
+        _ => unreachable!("comparisons don't call the runtime"),
+    }
+}
+
+///The condition on `num_cmp(&a, &b)` for which a comparison operator gives 1
+fn comparison(op: BinaryOp) -> Option<&'static str> {
+    match op {
+        BinaryOp::Equal => Some("== Equal"),
+        BinaryOp::LessThan => Some("== Less"),
+        BinaryOp::LessThanOrEqual => Some("!= Greater"),
+        BinaryOp::GreaterThan => Some("== Greater"),
+        BinaryOp::GreaterThanOrEqual => Some("!= Less"),
+        _ => None,
+    }
+}
+
+///The argument of a file command: `None` reads the path from stack C
+fn path_arg(arg: &str) -> String {
+    if arg.is_empty() {
+        "None".to_string()
+    } else {
+        format!("Some({:?})", arg)
+    }
+}
+
+///The handle of a stream command: `None` pops it from stack C
+fn handle_arg(handle: &Handle) -> String {
+    match handle {
+        Handle::Number(n) => format!("Some({})", n),
+        Handle::Popped => "None".to_string(),
+    }
+}
+
+///Pushes a constant to stack `s`. Constants which aren't small are added to `constants`, and ones which don't fit the model are an error
+fn push_constant(
+    i: usize,
+    s: usize,
+    n: &BigInt,
+    model: NumericModel,
+    constants: &mut Vec<String>,
+) -> String {
+    match model.constant(n) {
+        Ok(Value::Small(n)) => format!("rt.push({}, num_from_i64({}));", s, n),
+        Ok(Value::Big(n)) => {
+            constants.push(n.to_string());
+            format!(
+                "rt.push({}, constants[{}].clone());",
+                s,
+                constants.len() - 1
+            )
+        }
+        Err(e) => format!("return Err(error({}, {:?}));", i, e.to_string()),
+    }
+}
+
+///Generates the statements of a single token. `constants` collects the big integers which are parsed once at startup
+fn emit_token(
+    program: &Program,
+    i: usize,
+    model: NumericModel,
+    constants: &mut Vec<String>,
+) -> String {
+    let tokens = &program.tokens;
+    let stack = |s: u8| -> Option<usize> { (s < 3).then_some(s as usize) };
+    let invalid_stack = format!("return Err(error({}, \"invalid stack\"));", i);
+
+    match &tokens[i] {
+        TokenType::Exit => "return Ok(());".to_string(),
+        TokenType::Print => format!("rt.print().map_err(|e| error({}, e))?;", i),
+        TokenType::PrintNum => "rt.print_num();".to_string(),
+        TokenType::GetNextIn => "rt.get_next_in();".to_string(),
+        TokenType::CreateFile { arg } => format!(
+            "rt.create_file({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::CreateFileStream { arg, new_handle } => format!(
+            "rt.create_file_stream({}, {}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            new_handle,
+            i
+        ),
+        TokenType::OpenFileStream { arg, new_handle } => format!(
+            "rt.open_file_stream({}, {}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            new_handle,
+            i
+        ),
+        TokenType::ReadFileStream { handle } => {
+            format!("rt.read_file_stream({});", handle_arg(handle))
+        }
+        TokenType::WriteFileStream { handle } => format!(
+            "rt.write_file_stream({}).map_err(|e| error({}, e))?;",
+            handle_arg(handle),
+            i
+        ),
+        TokenType::AppendFileStream { arg, new_handle } => format!(
+            "rt.append_file_stream({}, {}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            new_handle,
+            i
+        ),
+        TokenType::ReadWriteFileStream { arg, new_handle } => format!(
+            "rt.read_write_file_stream({}, {}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            new_handle,
+            i
+        ),
+        TokenType::SeekFileStream { whence, handle } => format!(
+            "rt.seek_file_stream({:?}, {});",
+            whence.name(),
+            handle_arg(handle)
+        ),
+        TokenType::TellFileStream { handle } => {
+            format!("rt.tell_file_stream({});", handle_arg(handle))
+        }
+        TokenType::CloseFileStream { handle } => {
+            format!("rt.close_file_stream({});", handle_arg(handle))
+        }
+        TokenType::MakeDir { arg } => format!(
+            "rt.make_dir({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::RemoveDir { arg } => format!(
+            "rt.remove_dir({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::Rename { from, to } => format!(
+            "rt.rename({}, {}).map_err(|e| error({}, e))?;",
+            path_arg(from),
+            path_arg(to),
+            i
+        ),
+        TokenType::RemoveFile { arg } => format!(
+            "rt.remove_file({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::Exists { arg } => format!(
+            "rt.exists({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::FileSize { arg } => format!(
+            "rt.file_size({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::ListDir { arg } => format!(
+            "rt.list_dir({}).map_err(|e| error({}, e))?;",
+            path_arg(arg),
+            i
+        ),
+        TokenType::Clear => "rt.stacks[2].clear();".to_string(),
+        TokenType::Push { arg } => push_constant(i, 2, arg, model, constants),
+        TokenType::Pop { arg } => match stack(*arg) {
+            Some(s) => format!("rt.pop({});", s),
+            None => invalid_stack,
+        },
+        TokenType::Move { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => format!("let n = rt.pop({}); rt.push({}, n);", from, to),
+            _ => invalid_stack,
+        },
+        TokenType::Copy { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => format!("rt.copy({}, {});", from, to),
+            _ => invalid_stack,
+        },
+        TokenType::Jump { arg } => match jump_entry(tokens, *arg) {
+            Some(entry) => format!(
+                "if num_is_positive(&rt.pop(2)) {{ pc = {}; continue; }}",
+                entry
+            ),
+            //Jumps to missing labels do nothing, other than popping C
+            None => "rt.pop(2);".to_string(),
+        },
+        TokenType::PushTo { stack: s, value } => match stack(*s) {
+            Some(s) => push_constant(i, s, value, model, constants),
+            None => invalid_stack,
+        },
+        TokenType::DecA => format!(
+            "let a = rt.pop(0); let n = num_sub(a, num_from_i64(1)).map_err(|e| error({}, e))?; rt.push(0, n);",
+            i
+        ),
+        TokenType::JumpIfTopPositive { stack: s, target } => {
+            match (stack(*s), jump_entry(tokens, *target)) {
+                (Some(s), Some(entry)) => format!(
+                    "let n = rt.pop({0}); let positive = num_is_positive(&n); rt.push({0}, n); if positive {{ pc = {1}; continue; }}",
+                    s, entry
+                ),
+                (Some(s), None) => format!("let n = rt.pop({0}); rt.push({0}, n);", s),
+                (None, _) => invalid_stack,
+            }
+        }
+        //Labels only matter as jump targets, and unresolved jumps are never executed
+        TokenType::Label { .. } | TokenType::PreComputeJump { .. } => String::new(),
+        token => {
+            let op = token
+                .binary_op()
+                .expect("every other token is a binary operator");
+            let result = match comparison(op) {
+                Some(condition) => format!("num_from_i64((num_cmp(&a, &b) {}) as i64)", condition),
+                None => format!("{}(a, b).map_err(|e| error({}, e))?", op_function(op), i),
+            };
+            format!(
+                "let a = rt.pop(0); let b = rt.pop(1); let n = {}; rt.push(2, n);",
+                result
+            )
+        }
+    }
+}
+
+///Generates a standalone Rust program which runs `program`, using the given numeric model.
+/// The program has no dependencies, so it can be built with just `rustc -O`. Like the interpreter, its file commands can't reach outside of
+/// the directory it's run in. Jumps must already be resolved
+pub fn emit_rust(program: &Program, model: NumericModel) -> String {
+    let tokens = &program.tokens;
+    let blocks = dispatch_blocks(tokens);
+    let mut constants: Vec<String> = Vec::new();
+
+    let mut body = String::new();
+    for (b, &start) in blocks.iter().enumerate() {
+        let end = blocks.get(b + 1).copied().unwrap_or(tokens.len());
+        writeln!(body, "            {} => {{", start).unwrap();
+        for (i, token) in tokens.iter().enumerate().take(end).skip(start) {
+            let line = match program.spans.get(i) {
+                Some(span) => format!(" (line {})", span.line),
+                None => String::new(),
+            };
+            writeln!(body, "                //#{} {}{}", i, token, line).unwrap();
+            let code = emit_token(program, i, model, &mut constants);
+            if !code.is_empty() {
+                writeln!(body, "                {}", code).unwrap();
+            }
+        }
+        //Falling through to the next block, which is the end of the program after the last one
+        writeln!(body, "                pc = {};", end).unwrap();
+        writeln!(body, "            }}").unwrap();
+    }
+
+    let mut s = String::new();
+    writeln!(
+        s,
+        "//Generated by `staq-lang-parser emit-rust` with the {} numeric model. Build with `rustc -O`",
+        model.name()
+    )
+    .unwrap();
+    writeln!(
+        s,
+        "#![allow(unused_mut, unused_variables, unused_imports, unreachable_code, dead_code)]\n"
+    )
+    .unwrap();
+    s.push_str(COMMON_RUNTIME);
+    s.push('\n');
+    s.push_str(match model {
+        NumericModel::BigInt => BIGINT_RUNTIME,
+        NumericModel::Wrap64 => WRAP64_RUNTIME,
+        NumericModel::Checked64 => CHECKED64_RUNTIME,
+    });
+
+    writeln!(s, "\nfn run(rt: &mut Runtime) -> Result<(), String> {{").unwrap();
+    writeln!(s, "    use std::cmp::Ordering::*;").unwrap();
+    let parsed: Vec<String> = constants
+        .iter()
+        .map(|n| format!("Num::parse(\"{}\")", n))
+        .collect();
+    writeln!(
+        s,
+        "    let constants: Vec<Num> = vec![{}];",
+        parsed.join(", ")
+    )
+    .unwrap();
+    writeln!(s, "    let mut pc: usize = 0;").unwrap();
+    writeln!(s, "    loop {{").unwrap();
+    writeln!(s, "        match pc {{").unwrap();
+    s.push_str(&body);
+    writeln!(s, "            _ => return Ok(()),").unwrap();
+    writeln!(s, "        }}").unwrap();
+    writeln!(s, "    }}").unwrap();
+    writeln!(s, "}}").unwrap();
+
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit_rust;
+    use crate::{
+        emit::tests::{build_and_run, examples, interpret, tool_available},
+        interpreter::tokenize,
+        optimize::optimize,
+        value::NumericModel,
+    };
+
+    fn compile_and_run(
+        name: &str,
+        source: &str,
+        input: &str,
+        model: NumericModel,
+    ) -> (String, bool) {
+        let mut program = tokenize(source);
+        optimize(&mut program, 0, model);
+        build_and_run(
+            &format!("rust-{}-{}", name, model.name()),
+            "main.rs",
+            &emit_rust(&program, model),
+            &["rustc", "-O", "main.rs", "-o", "main"],
+            input,
+        )
+    }
+
+    #[test]
+    fn matches_interpreter() {
+        if !tool_available("rustc") {
+            eprintln!("rustc not found, skipping");
+            return;
+        }
+
+        for (name, source, input) in examples() {
+            let expected = interpret(&source, input, NumericModel::BigInt);
+            let (output, ok) = compile_and_run(name, &source, input, NumericModel::BigInt);
+            assert!(ok, "{} failed", name);
+            assert_eq!(output, expected, "{}", name);
+        }
+
+        //Fibonacci overflows 64 bits, so the models give different results
+        let fibonacci = include_str!("../examples/fibonnaci.stq");
+        for model in [NumericModel::Wrap64, NumericModel::Checked64] {
+            let expected = interpret(fibonacci, "", model);
+            let (output, ok) = compile_and_run("fibonnaci", fibonacci, "", model);
+            assert_eq!(output, expected, "{}", model.name());
+            assert_eq!(ok, model == NumericModel::Wrap64);
+        }
+    }
+
+    #[test]
+    fn paths_stay_in_root() {
+        if !tool_available("rustc") {
+            eprintln!("rustc not found, skipping");
+            return;
+        }
+
+        //`..` stops at the root, and the root can't be removed
+        let source = "createfile:../../escaped printnum\nexists:escaped printnum\nexists:/escaped printnum\ncreatefile:none/../x printnum\nrmdir:. printnum\nrename:/:moved printnum";
+        let expected = interpret(source, "", NumericModel::BigInt);
+        assert_eq!(expected, "111-1-1-1");
+        let (output, ok) = compile_and_run("paths", source, "", NumericModel::BigInt);
+        assert!(ok);
+        assert_eq!(output, expected);
+    }
+
+    ///The embedded bignum must agree with `num`
+    mod bigint_runtime {
+        use std::str::FromStr;
+
+        use num::{bigint::BigInt, ToPrimitive};
+
+        include!("runtime/rust_bigint.rs");
+
+        ///A small xorshift generator, so the test is reproducible without extra dependencies
+        struct Rng(u64);
+
+        impl Rng {
+            fn next(&mut self) -> u64 {
+                self.0 ^= self.0 << 13;
+                self.0 ^= self.0 >> 7;
+                self.0 ^= self.0 << 17;
+                self.0
+            }
+
+            ///A random number with up to about 200 bits, as a decimal string
+            fn number(&mut self) -> String {
+                let mut n = BigInt::from(self.next() as i64);
+                for _ in 0..self.next() % 3 {
+                    n = n * BigInt::from(self.next()) + BigInt::from(self.next() % 1000);
+                }
+                n.to_string()
+            }
+        }
+
+        #[test]
+        fn matches_num() {
+            let mut rng = Rng(0x853C49E6748FEA9B);
+            let ops: [(
+                fn(Num, Num) -> Result<Num, &'static str>,
+                fn(&BigInt, &BigInt) -> BigInt,
+            ); 6] = [
+                (num_add, |a, b| a + b),
+                (num_sub, |a, b| a - b),
+                (num_mul, |a, b| a * b),
+                (num_and, |a, b| a & b),
+                (num_or, |a, b| a | b),
+                (num_xor, |a, b| a ^ b),
+            ];
+
+            for _ in 0..3000 {
+                let (a, b) = (rng.number(), rng.number());
+                let (big_a, big_b) = (BigInt::from_str(&a).unwrap(), BigInt::from_str(&b).unwrap());
+                assert_eq!(Num::parse(&a).to_string(), a);
+
+                for (op, expected) in ops {
+                    let n = op(Num::parse(&a), Num::parse(&b)).unwrap();
+                    assert_eq!(
+                        n.to_string(),
+                        expected(&big_a, &big_b).to_string(),
+                        "{} {}",
+                        a,
+                        b
+                    );
+                }
+                if big_b != BigInt::from(0) {
+                    let div = num_div(Num::parse(&a), Num::parse(&b)).unwrap();
+                    let rem = num_rem(Num::parse(&a), Num::parse(&b)).unwrap();
+                    assert_eq!(
+                        div.to_string(),
+                        (&big_a / &big_b).to_string(),
+                        "{} / {}",
+                        a,
+                        b
+                    );
+                    assert_eq!(
+                        rem.to_string(),
+                        (&big_a % &big_b).to_string(),
+                        "{} % {}",
+                        a,
+                        b
+                    );
+                }
+
+                let shift = rng.next() % 100;
+                let shl = num_shl(Num::parse(&a), Num::from_i64(shift as i64)).unwrap();
+                let shr = num_shr(Num::parse(&a), Num::from_i64(shift as i64)).unwrap();
+                assert_eq!(shl.to_string(), (&big_a << shift).to_string());
+                assert_eq!(
+                    shr.to_string(),
+                    (&big_a >> shift).to_string(),
+                    "{} >> {}",
+                    a,
+                    shift
+                );
+                assert_eq!(num_cmp(&Num::parse(&a), &Num::parse(&b)), big_a.cmp(&big_b));
+                assert_eq!(num_is_positive(&Num::parse(&a)), big_a > BigInt::from(0));
+                assert_eq!(num_to_i64(&Num::parse(&a)), big_a.to_i64(), "{}", a);
+            }
+        }
+
+        #[test]
+        fn converts_small_values() {
+            for n in [0, 1, 255, 256, -1, i64::MIN, i64::MAX] {
+                let num = num_from_i64(n);
+                assert_eq!(num.to_string(), n.to_string());
+                assert_eq!(num_to_i64(&num), Some(n));
+                assert_eq!(num_to_u8(&num), u8::try_from(n).ok(), "{}", n);
+                assert_eq!(num_is_positive(&num), n > 0);
+            }
+            assert_eq!(num_to_i64(&Num::parse("9223372036854775808")), None);
+            assert_eq!(num_to_u8(&Num::parse("-9223372036854775809")), None);
+        }
+    }
+}
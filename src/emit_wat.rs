@@ -0,0 +1,545 @@
+use std::fmt::Write;
+
+use num::BigInt;
+
+use crate::{
+    emit::{dispatch_blocks, jump_entry},
+    token::{Handle, Program, TokenType, Whence},
+    value::{BinaryOp, NumericModel, Value},
+};
+
+const RUNTIME: &str = include_str!("runtime/wat_runtime.wat");
+
+///The stack descriptors take up the start of memory, so string data goes after them
+const DATA_START: usize = 36;
+const PAGE_SIZE: usize = 65536;
+
+///The code given to the host's `runtime_error` for an invalid stack. The runtime reports the other errors itself
+const ERROR_INVALID_STACK: u32 = 3;
+
+const IMPORTS: &str = r#"  (import "staq" "print_char" (func $host_print_char (param i32)))
+  (import "staq" "print_num" (func $host_print_num (param i64)))
+  (import "staq" "get_next_in" (func $host_get_next_in (result i32)))
+  (import "staq" "create_file" (func $host_create_file (param i32 i32) (result i32)))
+  (import "staq" "create_file_stream" (func $host_create_file_stream (param i32 i32 i32) (result i32)))
+  (import "staq" "open_file_stream" (func $host_open_file_stream (param i32 i32 i32) (result i32)))
+  (import "staq" "read_file_stream" (func $host_read_file_stream (param i32) (result i32)))
+  (import "staq" "write_file_stream" (func $host_write_file_stream (param i32 i32 i32) (result i32)))
+  (import "staq" "open_file_stream_append" (func $host_open_file_stream_append (param i32 i32 i32) (result i32)))
+  (import "staq" "open_file_stream_read_write" (func $host_open_file_stream_read_write (param i32 i32 i32) (result i32)))
+  (import "staq" "seek_file_stream" (func $host_seek_file_stream (param i32 i64 i32) (result i32)))
+  (import "staq" "tell_file_stream" (func $host_tell_file_stream (param i32) (result i64)))
+  (import "staq" "close_file_stream" (func $host_close_file_stream (param i32) (result i32)))
+  (import "staq" "make_dir" (func $host_make_dir (param i32 i32) (result i32)))
+  (import "staq" "remove_dir" (func $host_remove_dir (param i32 i32) (result i32)))
+  (import "staq" "rename" (func $host_rename (param i32 i32 i32 i32) (result i32)))
+  (import "staq" "remove_file" (func $host_remove_file (param i32 i32) (result i32)))
+  (import "staq" "exists" (func $host_exists (param i32 i32) (result i32)))
+  (import "staq" "file_size" (func $host_file_size (param i32 i32) (result i64)))
+  (import "staq" "list_dir" (func $host_list_dir (param i32 i32) (result i32)))
+  (import "staq" "list_dir_char" (func $host_list_dir_char (param i32 i32) (result i32)))
+  (import "staq" "runtime_error" (func $host_runtime_error (param i32 i32)))
+"#;
+
+///Writes bytes as a WebAssembly string literal. Anything other than printable ASCII is a hex escape
+fn wat_string(bytes: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &b in bytes {
+        match b {
+            0x20..=0x7e if b != b'"' && b != b'\\' => out.push(b as char),
+            _ => write!(out, "\\{:02x}", b).unwrap(),
+        }
+    }
+    out.push('"');
+    out
+}
+
+///The string arguments of file commands, laid out one after another in a data segment
+struct StringData {
+    bytes: Vec<u8>,
+    offsets: Vec<(String, usize)>,
+}
+
+impl StringData {
+    fn new(tokens: &[TokenType]) -> Self {
+        let mut data = StringData {
+            bytes: Vec::new(),
+            offsets: Vec::new(),
+        };
+        for token in tokens {
+            let paths = match token {
+                TokenType::CreateFile { arg }
+                | TokenType::CreateFileStream { arg, .. }
+                | TokenType::OpenFileStream { arg, .. }
+                | TokenType::AppendFileStream { arg, .. }
+                | TokenType::ReadWriteFileStream { arg, .. }
+                | TokenType::MakeDir { arg }
+                | TokenType::RemoveDir { arg }
+                | TokenType::RemoveFile { arg }
+                | TokenType::Exists { arg }
+                | TokenType::FileSize { arg }
+                | TokenType::ListDir { arg } => vec![arg],
+                TokenType::Rename { from, to } => vec![from, to],
+                _ => Vec::new(),
+            };
+            for arg in paths {
+                if !arg.is_empty() && data.find(arg).is_none() {
+                    data.offsets
+                        .push((arg.clone(), DATA_START + data.bytes.len()));
+                    data.bytes.extend_from_slice(arg.as_bytes());
+                }
+            }
+        }
+        data
+    }
+
+    fn find(&self, s: &str) -> Option<usize> {
+        self.offsets.iter().find(|(o, _)| o == s).map(|(_, at)| *at)
+    }
+
+    ///Where the heap starts, aligned for the stacks' 64 bit values
+    fn end(&self) -> usize {
+        (DATA_START + self.bytes.len() + 7) & !7
+    }
+}
+
+///The arguments of a file command's host function: a path from the data segment, or one read from stack C if there isn't one
+fn path_args(data: &StringData, i: usize, arg: &str) -> String {
+    match data.find(arg) {
+        Some(at) if !arg.is_empty() => format!("(i32.const {}) (i32.const {})", at, arg.len()),
+        _ => format!(
+            "(call $path_from_c (i32.const {})) (global.get $path_len)",
+            i
+        ),
+    }
+}
+
+///The handle argument of a stream command's host function, which is popped from stack C if it isn't an argument
+fn handle_arg(handle: &Handle) -> String {
+    match handle {
+        //Handles past `i32::MAX` wrap around to negative ones, which are never open
+        Handle::Number(n) => format!("(i32.const {})", *n as i32),
+        Handle::Popped => "(call $pop_handle)".to_string(),
+    }
+}
+
+///Opens a stream with the host function `import`, either into a new handle or into the default handle `alias`
+fn open_stream(
+    import: &str,
+    data: &StringData,
+    i: usize,
+    arg: &str,
+    new_handle: bool,
+    alias: i32,
+) -> String {
+    format!(
+        "(call $push_opened (call $host_{} {} (i32.const {})) (i32.const {}))",
+        import,
+        path_args(data, i, arg),
+        if new_handle { -1 } else { alias },
+        new_handle as i32
+    )
+}
+
+///An `i64.const` of a wrap64 constant
+fn constant(n: &BigInt) -> String {
+    match NumericModel::Wrap64.constant(n) {
+        Ok(Value::Small(n)) => format!("(i64.const {})", n),
+        _ => unreachable!("wrap64 constants always fit in an i64"),
+    }
+}
+
+///The `then` clause of a taken jump. `blocks` are the starts of the dispatch blocks, which jumps select by number
+fn jump_to(blocks: &[usize], entry: usize) -> String {
+    //A jump to the end of the program has no block of its own, and picks the `br_table` default instead
+    let block = blocks.binary_search(&entry).unwrap_or(blocks.len());
+    format!(
+        "(then (local.set $pc (i32.const {})) (br $dispatch))",
+        block
+    )
+}
+
+///Generates the instructions of a single token. `blocks` are the starts of the dispatch blocks, which jumps select by number
+fn emit_token(tokens: &[TokenType], blocks: &[usize], data: &StringData, i: usize) -> String {
+    let stack = |s: u8| -> Option<u8> { (s < 3).then_some(s) };
+    let invalid_stack = format!(
+        "(call $fail (i32.const {}) (i32.const {}))",
+        i, ERROR_INVALID_STACK
+    );
+
+    match &tokens[i] {
+        TokenType::Exit => "(return)".to_string(),
+        TokenType::Print => format!("(call $print (i32.const {}))", i),
+        TokenType::PrintNum => "(call $print_num)".to_string(),
+        TokenType::GetNextIn => "(call $get_next_in)".to_string(),
+        TokenType::CreateFile { arg } => format!(
+            "(call $push_status (call $host_create_file {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::CreateFileStream { arg, new_handle } => {
+            open_stream("create_file_stream", data, i, arg, *new_handle, 1)
+        }
+        TokenType::OpenFileStream { arg, new_handle } => {
+            open_stream("open_file_stream", data, i, arg, *new_handle, 0)
+        }
+        TokenType::ReadFileStream { handle } => {
+            format!("(call $read_file_stream {})", handle_arg(handle))
+        }
+        TokenType::WriteFileStream { handle } => format!(
+            "(call $write_file_stream (i32.const {}) {})",
+            i,
+            handle_arg(handle)
+        ),
+        TokenType::AppendFileStream { arg, new_handle } => {
+            open_stream("open_file_stream_append", data, i, arg, *new_handle, 1)
+        }
+        TokenType::ReadWriteFileStream { arg, new_handle } => {
+            open_stream("open_file_stream_read_write", data, i, arg, *new_handle, 0)
+        }
+        //The handle is popped before the offset
+        TokenType::SeekFileStream { whence, handle } => format!(
+            "(call $push_status (call $host_seek_file_stream {} (call $pop (i32.const 2)) (i32.const {})))",
+            handle_arg(handle),
+            match whence {
+                Whence::Start => 0,
+                Whence::Current => 1,
+                Whence::End => 2,
+            }
+        ),
+        TokenType::TellFileStream { handle } => format!(
+            "(call $push_file_size (call $host_tell_file_stream {}))",
+            handle_arg(handle)
+        ),
+        TokenType::CloseFileStream { handle } => format!(
+            "(call $push_status (call $host_close_file_stream {}))",
+            handle_arg(handle)
+        ),
+        TokenType::MakeDir { arg } => format!(
+            "(call $push_status (call $host_make_dir {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::RemoveDir { arg } => format!(
+            "(call $push_status (call $host_remove_dir {}))",
+            path_args(data, i, arg)
+        ),
+        //Only the runtime can split stack C between two paths
+        TokenType::Rename { from, to } if from.is_empty() && to.is_empty() => format!(
+            "(call $push_status (call $rename_from_c (i32.const {})))",
+            i
+        ),
+        TokenType::Rename { from, to } => format!(
+            "(call $push_status (call $host_rename {} {}))",
+            path_args(data, i, from),
+            path_args(data, i, to)
+        ),
+        TokenType::RemoveFile { arg } => format!(
+            "(call $push_status (call $host_remove_file {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::Exists { arg } => format!(
+            "(call $push_status (call $host_exists {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::FileSize { arg } => format!(
+            "(call $push_file_size (call $host_file_size {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::ListDir { arg } => format!(
+            "(call $push_listing (call $host_list_dir {}))",
+            path_args(data, i, arg)
+        ),
+        TokenType::Clear => "(call $clear_c)".to_string(),
+        TokenType::Push { arg } => format!("(call $push (i32.const 2) {})", constant(arg)),
+        TokenType::Pop { arg } => match stack(*arg) {
+            Some(s) => format!("(drop (call $pop (i32.const {})))", s),
+            None => invalid_stack,
+        },
+        TokenType::Move { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => format!(
+                "(call $push (i32.const {}) (call $pop (i32.const {})))",
+                to, from
+            ),
+            _ => invalid_stack,
+        },
+        TokenType::Copy { arg } => match (stack(arg[0]), stack(arg[1])) {
+            (Some(from), Some(to)) => {
+                format!("(call $copy (i32.const {}) (i32.const {}))", from, to)
+            }
+            _ => invalid_stack,
+        },
+        TokenType::Jump { arg } => match jump_entry(tokens, *arg) {
+            Some(entry) => format!(
+                "(if (i64.gt_s (call $pop (i32.const 2)) (i64.const 0)) {})",
+                jump_to(blocks, entry)
+            ),
+            //Jumps to missing labels do nothing, other than popping C
+            None => "(drop (call $pop (i32.const 2)))".to_string(),
+        },
+        TokenType::PushTo { stack: s, value } => match stack(*s) {
+            Some(s) => format!("(call $push (i32.const {}) {})", s, constant(value)),
+            None => invalid_stack,
+        },
+        TokenType::DecA => {
+            "(call $push (i32.const 0) (i64.sub (call $pop (i32.const 0)) (i64.const 1)))"
+                .to_string()
+        }
+        TokenType::JumpIfTopPositive { stack: s, target } => match stack(*s) {
+            Some(s) => {
+                let restore = format!(
+                    "(local.set $a (call $pop (i32.const {0}))) (call $push (i32.const {0}) (local.get $a))",
+                    s
+                );
+                match jump_entry(tokens, *target) {
+                    Some(entry) => format!(
+                        "{} (if (i64.gt_s (local.get $a) (i64.const 0)) {})",
+                        restore,
+                        jump_to(blocks, entry)
+                    ),
+                    None => restore,
+                }
+            }
+            None => invalid_stack,
+        },
+        //Labels only matter as jump targets, and unresolved jumps are never executed
+        TokenType::Label { .. } | TokenType::PreComputeJump { .. } => String::new(),
+        token => {
+            let op = token
+                .binary_op()
+                .expect("every other token is a binary operator");
+            let operands = "(local.get $a) (local.get $b)";
+            let result = match op {
+                BinaryOp::Add => format!("(i64.add {})", operands),
+                BinaryOp::Subtract => format!("(i64.sub {})", operands),
+                BinaryOp::Multiply => format!("(i64.mul {})", operands),
+                BinaryOp::Divide => format!("(call $div {} (i32.const {}))", operands, i),
+                BinaryOp::Modulo => format!("(call $rem {} (i32.const {}))", operands, i),
+                BinaryOp::Equal => format!("(i64.extend_i32_u (i64.eq {}))", operands),
+                BinaryOp::LessThan => format!("(i64.extend_i32_u (i64.lt_s {}))", operands),
+                BinaryOp::LessThanOrEqual => {
+                    format!("(i64.extend_i32_u (i64.le_s {}))", operands)
+                }
+                BinaryOp::GreaterThan => format!("(i64.extend_i32_u (i64.gt_s {}))", operands),
+                BinaryOp::GreaterThanOrEqual => {
+                    format!("(i64.extend_i32_u (i64.ge_s {}))", operands)
+                }
+                BinaryOp::BitAnd => format!("(i64.and {})", operands),
+                BinaryOp::BitOr => format!("(i64.or {})", operands),
+                BinaryOp::BitXor => format!("(i64.xor {})", operands),
+                //WebAssembly shifts only use the low 6 bits of the amount, just like wrap64
+                BinaryOp::BitRightShift => format!("(i64.shr_s {})", operands),
+                BinaryOp::BitLeftShift => format!("(i64.shl {})", operands),
+            };
+            format!(
+                "(local.set $a (call $pop (i32.const 0))) (local.set $b (call $pop (i32.const 1))) (call $push (i32.const 2) {})",
+                result
+            )
+        }
+    }
+}
+
+///Generates a WebAssembly text module which runs `program` when its exported `run` function is called.
+/// Values are 64 bit integers which wrap on overflow, as in the wrap64 numeric model. Jumps must already be resolved.
+///
+///The host provides printing, input and file streams through the imports of the `staq` module:
+/// - `print_char(c: i32)` prints a byte as a character, and `print_num(n: i64)` prints a number
+/// - `get_next_in() -> i32` returns the next byte of input, or -1 if there isn't one
+/// - `create_file` takes the address and length of a path's bytes in the exported memory, returning 1 on success or -1 on failure
+/// - `create_file_stream`, `open_file_stream`, `open_file_stream_append` (for an existing file, always writing at its end) and
+///   `open_file_stream_read_write` (for an existing file) take a path and then a handle. A handle of -1 asks for a new handle,
+///   which should be the lowest free one from 2 up. Otherwise the stream replaces handle 0 or 1, and a read-write stream opened
+///   into handle 0 becomes handle 1 too, sharing one position. They return the handle or -1 on failure.
+///   Like in the interpreter, handles 0 and 1 aren't open until the program opens them
+/// - The stream functions take a handle first, and fail for a handle which isn't open (including negative ones).
+///   `read_file_stream(handle: i32) -> i32` returns the next byte, -1 at the end of the stream, or -2 if reading failed,
+///   and `write_file_stream(handle: i32, ptr: i32, len: i32) -> i32` writes bytes, returning 1 or -1
+/// - `seek_file_stream(handle: i32, offset: i64, whence: i32) -> i32` moves a stream relative to its start (0), its position (1) or its end (2),
+///   returning 1 or -1. `tell_file_stream(handle: i32) -> i64` returns a stream's position, or -1 on failure.
+///   `close_file_stream(handle: i32) -> i32` returns 1, or -1 if the handle wasn't open
+/// - `make_dir`, `remove_dir` (which only removes empty directories), `remove_file` and `exists` take a path like `create_file` and return 1 or -1.
+///   `rename` takes two paths, the one to move from and then the one to move to
+/// - `file_size(ptr: i32, len: i32) -> i64` returns the size of a file, or -1 if it's a directory or can't be read
+/// - `list_dir(ptr: i32, len: i32) -> i32` lists a directory, returning the number of entries or -1 on failure. The names must be sorted.
+///   Then `list_dir_char(entry: i32, i: i32) -> i32` returns byte `i` of an entry's name, or -1 past its end
+/// - `runtime_error(index: i32, code: i32)` reports an error at a token before the module traps.
+///   The codes are 1 for division by zero, 2 for an invalid char value and 3 for an invalid stack
+pub fn emit_wat(program: &Program) -> String {
+    let tokens = &program.tokens;
+    let blocks = dispatch_blocks(tokens);
+    let data = StringData::new(tokens);
+    let heap = data.end();
+
+    let mut s = String::new();
+    writeln!(
+        s,
+        ";; Generated by `staq-lang-parser emit-wat` with the wrap64 numeric model"
+    )
+    .unwrap();
+    writeln!(s, "(module").unwrap();
+    s.push_str(IMPORTS);
+    writeln!(
+        s,
+        "\n  (memory (export \"memory\") {})",
+        heap.div_ceil(PAGE_SIZE).max(1)
+    )
+    .unwrap();
+    if !data.bytes.is_empty() {
+        writeln!(
+            s,
+            "  (data (i32.const {}) {})",
+            DATA_START,
+            wat_string(&data.bytes)
+        )
+        .unwrap();
+    }
+    writeln!(s, "  (global $heap (mut i32) (i32.const {}))\n", heap).unwrap();
+    s.push_str(RUNTIME);
+
+    writeln!(s, "\n  (func $run (export \"run\")").unwrap();
+    writeln!(s, "    (local $pc i32)").unwrap();
+    writeln!(s, "    (local $a i64)").unwrap();
+    writeln!(s, "    (local $b i64)").unwrap();
+    writeln!(s, "    block $end").unwrap();
+    writeln!(s, "    loop $dispatch").unwrap();
+    //Breaking out of block `$bN` lands on the code of dispatch block N, so the innermost block is the first one
+    for b in (0..blocks.len()).rev() {
+        writeln!(s, "    block $b{}", b).unwrap();
+    }
+    let targets: Vec<String> = (0..blocks.len()).map(|b| format!("$b{}", b)).collect();
+    writeln!(
+        s,
+        "      (br_table {} $end (local.get $pc))",
+        targets.join(" ")
+    )
+    .unwrap();
+    for (b, &start) in blocks.iter().enumerate() {
+        let end = blocks.get(b + 1).copied().unwrap_or(tokens.len());
+        writeln!(s, "    end").unwrap();
+        for (i, token) in tokens.iter().enumerate().take(end).skip(start) {
+            let line = match program.spans.get(i) {
+                Some(span) => format!(" (line {})", span.line),
+                None => String::new(),
+            };
+            writeln!(s, "      ;; #{} {}{}", i, token, line).unwrap();
+            let code = emit_token(tokens, &blocks, &data, i);
+            if !code.is_empty() {
+                writeln!(s, "      {}", code).unwrap();
+            }
+        }
+        //Each block falls through to the next, and the last one falls out of the loop
+    }
+    writeln!(s, "    end").unwrap();
+    writeln!(s, "    end)").unwrap();
+    writeln!(s, ")").unwrap();
+    s
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{emit_wat, wat_string};
+    use crate::{
+        emit::dispatch_blocks, emit::tests::examples, interpreter::tokenize, optimize::optimize,
+        value::NumericModel,
+    };
+
+    ///Checks that the parentheses of a module are balanced, skipping strings and comments
+    fn balanced(wat: &str) -> bool {
+        let mut depth = 0i32;
+        for line in wat.lines() {
+            let mut chars = line.chars().peekable();
+            let mut in_string = false;
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' if in_string => {
+                        chars.next();
+                    }
+                    '"' => in_string = !in_string,
+                    ';' if !in_string && chars.peek() == Some(&';') => break,
+                    '(' if !in_string => depth += 1,
+                    ')' if !in_string => depth -= 1,
+                    _ => (),
+                }
+                if depth < 0 {
+                    return false;
+                }
+            }
+        }
+        depth == 0
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            wat_string("a\"b\\c é\n".as_bytes()),
+            "\"a\\22b\\5cc \\c3\\a9\\0a\""
+        );
+
+        let mut program = tokenize(
+            "push:-9223372036854775808 push:18446744073709551615 jump:x\ncreatefile:a\"b\ncreatefile",
+        );
+        optimize(&mut program, 0, NumericModel::Wrap64);
+        let wat = emit_wat(&program);
+        assert!(balanced(&wat));
+        assert!(wat.contains("(i64.const -9223372036854775808)"));
+        assert!(wat.contains("(i64.const -1)"));
+        assert!(wat.contains("      (drop (call $pop (i32.const 2)))\n"));
+        assert!(wat.contains("(data (i32.const 36) \"a\\22b\")"));
+        assert!(wat.contains("(call $host_create_file (i32.const 36) (i32.const 3))"));
+        assert!(wat.contains("(call $host_create_file (call $path_from_c"));
+    }
+
+    #[test]
+    fn structure() {
+        for (name, source, _) in examples() {
+            let mut program = tokenize(&source);
+            optimize(&mut program, 0, NumericModel::Wrap64);
+            let wat = emit_wat(&program);
+            let blocks = dispatch_blocks(&program.tokens);
+
+            assert!(balanced(&wat), "{}", name);
+            assert!(wat.starts_with(";; Generated by"), "{}", name);
+            for import in [
+                "print_char",
+                "print_num",
+                "get_next_in",
+                "create_file",
+                "create_file_stream",
+                "open_file_stream",
+                "read_file_stream",
+                "write_file_stream",
+                "runtime_error",
+            ] {
+                assert!(
+                    wat.contains(&format!("(import \"staq\" \"{}\"", import)),
+                    "{} imports {}",
+                    name,
+                    import
+                );
+            }
+            assert!(wat.contains("(memory (export \"memory\") 1)"), "{}", name);
+            assert!(wat.contains("(func $run (export \"run\")"), "{}", name);
+
+            //One block per dispatch block, each of which the `br_table` can select
+            assert_eq!(
+                wat.matches("    block $b").count(),
+                blocks.len(),
+                "{}",
+                name
+            );
+            let table: Vec<String> = (0..blocks.len()).map(|b| format!("$b{}", b)).collect();
+            assert!(
+                wat.contains(&format!(
+                    "(br_table {} $end (local.get $pc))",
+                    table.join(" ")
+                )),
+                "{}",
+                name
+            );
+
+            //Every jump selects one of those blocks, or the end of the program
+            for jump in wat.split("(local.set $pc (i32.const ").skip(1) {
+                let block: usize = jump[..jump.find(')').unwrap()].parse().unwrap();
+                assert!(block <= blocks.len(), "{}", name);
+            }
+        }
+    }
+}
@@ -1,193 +1,437 @@
 use std::{
+    cell::RefCell,
     env,
     fs::{self, File},
-    io::{Read, Write},
+    io::{self, Read, Seek, SeekFrom, Write},
     path::PathBuf,
+    rc::Rc,
     str::FromStr,
-    time::SystemTime,
+    time::{Instant, SystemTime},
 };
 
 extern crate num;
-use num::{bigint::BigInt, FromPrimitive, ToPrimitive};
+use num::bigint::BigInt;
 
 use crate::{
-    optimize::optimize,
-    token::TokenType,
-    vfs::{FileStream, FileSystem, RealLocalFileSystem},
+    bytecode,
+    optimize::optimize_with,
+    profile::{ProfileConfig, Profiler},
+    token::{Handle, Program, Span, TokenType, Whence},
+    trace::{Journal, TraceConfig, Tracer},
+    value::{ArithmeticError, BinaryOp, NumericModel, Value},
+    vfs::{FileStream, FileStreamMode, FileSystem, RealLocalFileSystem},
 };
 
 pub struct Stack {
-    dat: Vec<BigInt>,
+    dat: Vec<Value>,
+    ///Records the values popped and pushed while the current token is being traced
+    journal: Option<Journal>,
 }
 
 impl Stack {
     pub fn new() -> Stack {
-        Stack { dat: Vec::new() }
+        Stack {
+            dat: Vec::new(),
+            journal: None,
+        }
     }
 
-    pub fn push(&mut self, n: BigInt) {
+    pub fn push(&mut self, n: Value) {
+        if let Some(journal) = &mut self.journal {
+            journal.pushed.push(n.clone());
+        }
         self.dat.push(n);
     }
 
-    pub fn pop(&mut self) -> BigInt {
+    pub fn pop(&mut self) -> Value {
         if self.dat.len() == 0 {
-            return BigInt::from(0);
+            return Value::zero();
+        }
+        let n = self.dat.pop().expect("pop failed");
+        if let Some(journal) = &mut self.journal {
+            journal.popped.push(n.clone());
         }
-        self.dat.pop().expect("pop failed")
+        n
     }
 
     pub fn len(&self) -> usize {
         self.dat.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.dat.is_empty()
+    }
+
     pub fn clear(&mut self) {
         self.dat.clear();
     }
+
+    fn start_journal(&mut self) {
+        self.journal = Some(Journal::default());
+    }
+
+    fn take_journal(&mut self) -> Journal {
+        self.journal.take().unwrap_or_default()
+    }
+}
+
+///Options which change how a program is run
+#[derive(Default)]
+pub struct RunOptions {
+    ///Writes a record of every executed token, separate from the program's own output
+    pub trace: Option<TraceConfig>,
+    ///Counts executions and time per token, printing a report when the program finishes
+    pub profile: Option<ProfileConfig>,
+    ///How integers overflow. Arithmetic, comparison and shift tokens all follow it
+    pub numeric_model: NumericModel,
+    ///Where `print` and `printnum` write to, instead of stdout
+    pub output: Option<Box<dyn Write>>,
+    ///Where `getnextin` reads from, instead of stdin
+    pub input: Option<Box<dyn Read>>,
+    ///How much source code is optimized before running it (see `optimize`). Compiled programs are run as they are
+    pub opt_level: usize,
+    ///Prints how many tokens each optimization pass removed to stderr
+    pub verbose: bool,
+}
+
+impl RunOptions {
+    ///Whether the program's labels are used while running, to find a traced label range or the profiler's label regions.
+    /// If so, optimizing doesn't strip them
+    fn uses_labels(&self) -> bool {
+        self.profile.is_some()
+            || self
+                .trace
+                .as_ref()
+                .is_some_and(|t| t.filter.label_range.is_some())
+    }
 }
 
 //Takes a character representing one of the stacks and turns it into that stack's index
-fn stack_char_to_index(s: &str) -> u8 {
+fn stack_char_to_index(s: &str) -> Option<u8> {
     match s {
-        "A" => 0u8,
-        "B" => 1u8,
-        "C" => 2u8,
-        _ => 255u8,
+        "A" => Some(0u8),
+        "B" => Some(1u8),
+        "C" => Some(2u8),
+        _ => None,
     }
 }
 
-pub fn run_from_string(string: String, mut file_system: Box<dyn FileSystem>) {
-    let mut tokens: Vec<TokenType> = Vec::new();
+pub fn run_from_string(string: String, file_system: Box<dyn FileSystem>, options: RunOptions) {
+    let program = parse(
+        string,
+        options.opt_level,
+        options.numeric_model,
+        options.verbose,
+        options.uses_labels(),
+    );
 
-    parse(string, &mut tokens);
+    interpret(program, file_system, options);
+}
 
-    interpret(tokens, file_system);
+///Runs an already parsed (or decoded) program. Its jumps must be resolved
+pub fn run_program(program: Program, file_system: Box<dyn FileSystem>, options: RunOptions) {
+    interpret(program, file_system, options);
 }
 
-pub fn run_from_file_path(file_path: String) {
-    let mut s = String::new();
+///Runs either StaqLang source code or a compiled program, depending on the contents of the file
+pub fn run_from_file_path(file_path: String, options: RunOptions) {
+    //Init runtime IO system
+    //Make the file system local to the StaqLang program's path
+    let root = program_root(&file_path);
+    run_from_file_path_with(file_path, Box::new(RealLocalFileSystem { root }), options);
+}
+
+///The directory file commands are relative to when running the program at `file_path`, which is the program's parent directory
+pub fn program_root(file_path: &str) -> String {
+    //A file in the working directory has an empty parent, which must not become `/`
+    match PathBuf::from_str(file_path).unwrap().parent() {
+        Some(p) if !p.as_os_str().is_empty() => p.to_str().unwrap().to_string() + "/",
+        _ => "./".to_string(),
+    }
+}
+
+///Like `run_from_file_path`, but file commands go to the given file system
+pub fn run_from_file_path_with(
+    file_path: String,
+    file_system: Box<dyn FileSystem>,
+    options: RunOptions,
+) {
+    let mut bytes: Vec<u8> = Vec::new();
 
     File::open(file_path.clone())
         .expect("Cannot open file from file_path")
-        .read_to_string(&mut s)
+        .read_to_end(&mut bytes)
         .unwrap();
 
-    //Init runtime IO system
-    //Make the file system local to the StaqLang program's path
-    //This means the root is the program's parent directory
-    let root = PathBuf::from_str(&file_path)
-        .unwrap()
-        .parent()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string()
-        + "/";
-    let file_system: Box<dyn FileSystem> = Box::new(RealLocalFileSystem { root });
-
-    run_from_string(s, file_system);
+    if bytecode::is_bytecode(&bytes) {
+        let program = bytecode::decode(&bytes).expect("Failed decoding compiled program");
+        run_program(program, file_system, options);
+    } else {
+        let s = String::from_utf8(bytes).expect("Source file is not valid UTF-8");
+        run_from_string(s, file_system, options);
+    }
 }
 
-fn parse(file: String, tokens: &mut Vec<TokenType>) {
-    let start_time: SystemTime = SystemTime::now();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenizeErrorKind {
+    ///A command whose first clause isn't any known command
+    UnknownCommand,
+    ///A known command with a missing or invalid argument
+    Malformed,
+}
+
+///A command which could not be turned into a token
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenizeError {
+    pub kind: TokenizeErrorKind,
+    pub span: Span,
+    ///The command as written
+    pub command: String,
+    pub message: String,
+}
+
+///Splits StaqLang source code into tokens, recording the span of each one.
+/// Unlike `parse`, this does not optimize the tokens (so jumps are left unresolved) or print any debug info.
+/// Unknown commands are skipped after printing them, and malformed commands panic. Use `tokenize_checked` to get these as errors instead
+pub fn tokenize(file: &str) -> Program {
+    let (program, errors) = tokenize_checked(file);
+    for error in errors {
+        match error.kind {
+            TokenizeErrorKind::UnknownCommand => println!("Invalid token: {}", error.command),
+            TokenizeErrorKind::Malformed => panic!("{}: {}", error.message, error.command),
+        }
+    }
+    program
+}
+
+///Like `tokenize`, but returns every unknown or malformed command as an error. These commands are left out of the program
+pub fn tokenize_checked(file: &str) -> (Program, Vec<TokenizeError>) {
+    let mut tokens: Vec<TokenType> = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
+    let mut errors: Vec<TokenizeError> = Vec::new();
 
     let lines: Vec<&str> = file.lines().collect();
 
     //Read each line, and at the end of each add a Clear token
-    for line in lines {
+    for (line_index, line) in lines.into_iter().enumerate() {
+        let mut col: usize = 1;
+
         //Read the commands in a line
         let commands: Vec<&str> = line.split(' ').collect();
         for command in commands {
-            //Read the parts of the command
-            let parts: Vec<&str> = command.split(':').collect();
-            let id: &str = parts[0];
+            let span = Span {
+                line: line_index + 1,
+                col,
+                len: command.len(),
+            };
+            col += command.len() + 1;
 
             //Check for newline characters and break
-            if id.starts_with("//") {
+            if command.starts_with("//") {
                 break;
             }
 
-            //Parse each command based on the identifying first clause
-            match id {
-                "exit" => tokens.push(TokenType::Exit {}),
-                "" => (),
-
-                "print" => tokens.push(TokenType::Print {}),
-                "printnum" => tokens.push(TokenType::PrintNum {}),
-                "getnextin" => tokens.push(TokenType::GetNextIn {}),
-
-                "createfile" => tokens.push(TokenType::CreateFile {
-                    arg: if parts.len() > 1 {
-                        parts[1].to_string()
-                    } else {
-                        "".to_string()
-                    },
-                }),
-                "createfilestream" => tokens.push(TokenType::CreateFileStream {
-                    arg: if parts.len() > 1 {
-                        parts[1].to_string()
-                    } else {
-                        "".to_string()
-                    },
-                }),
-                "openfilestream" => tokens.push(TokenType::OpenFileStream {
-                    arg: if parts.len() > 1 {
-                        parts[1].to_string()
-                    } else {
-                        "".to_string()
-                    },
+            match tokenize_command(command) {
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => (),
+                Err((kind, message)) => errors.push(TokenizeError {
+                    kind,
+                    span,
+                    command: command.to_string(),
+                    message,
                 }),
-                "readfilestream" => tokens.push(TokenType::ReadFileStream {}),
-                "writefilestream" => tokens.push(TokenType::WriteFileStream {}),
+            }
 
-                "push" => tokens.push(TokenType::Push {
-                    arg: BigInt::from_str(parts[1]).expect(
-                        format!("Failed parsing Push token argument: {}", command).as_str(),
-                    ),
-                }),
-                "pop" => tokens.push(TokenType::Pop {
-                    arg: stack_char_to_index(parts[1]),
-                }),
+            spans.resize(tokens.len(), span);
+        }
 
-                "+" => tokens.push(TokenType::Add {}),
-                "-" => tokens.push(TokenType::Subtract {}),
-                "*" => tokens.push(TokenType::Multiply {}),
-                "/" => tokens.push(TokenType::Divide {}),
-                "%" => tokens.push(TokenType::Modulo {}),
+        tokens.push(TokenType::Clear);
+        spans.push(Span {
+            line: line_index + 1,
+            col: line.len() + 1,
+            len: 0,
+        });
+    }
 
-                "move" => tokens.push(TokenType::Move {
-                    arg: [stack_char_to_index(parts[1]), stack_char_to_index(parts[2])],
-                }),
-                "copy" => tokens.push(TokenType::Copy {
-                    arg: [stack_char_to_index(parts[1]), stack_char_to_index(parts[2])],
-                }),
+    (Program { tokens, spans }, errors)
+}
 
-                "jump" => tokens.push(TokenType::PreComputeJump {
-                    arg: parts[1].to_string(),
-                }),
-                "label" => tokens.push(TokenType::Label {
-                    arg: parts[1].to_string(),
-                }),
+///Turns a single command into its token, or `None` for an empty command
+fn tokenize_command(command: &str) -> Result<Option<TokenType>, (TokenizeErrorKind, String)> {
+    let malformed = |message: String| (TokenizeErrorKind::Malformed, message);
+
+    //Read the parts of the command
+    let parts: Vec<&str> = command.split(':').collect();
+    let id: &str = parts[0];
+    //An argument which may be left out, which means it's read from stack C when the command runs
+    let optional_part = |i: usize| parts.get(i).map_or(String::new(), |p| p.to_string());
+    //An argument which must be given
+    let required_part = |i: usize| {
+        parts
+            .get(i)
+            .copied()
+            .ok_or_else(|| malformed(format!("missing argument {} of `{}`", i, id)))
+    };
+    //A stack's name, one of `A`, `B` or `C`
+    let stack_part = |i: usize| {
+        let name = required_part(i)?;
+        stack_char_to_index(name).ok_or_else(|| malformed(format!("invalid stack \"{}\"", name)))
+    };
+    //A stream's handle, which is `default` when it's left out
+    let handle_part = |i: usize, default: Handle| match parts.get(i).copied() {
+        None | Some("") => Ok(default),
+        Some(name) => Handle::from_name(name)
+            .ok_or_else(|| malformed(format!("invalid file stream handle \"{}\"", name))),
+    };
+    //Whether an open command ends in `new`
+    let new_handle_part = |i: usize| match parts.get(i).copied() {
+        None | Some("") => Ok(false),
+        Some("new") => Ok(true),
+        Some(other) => Err(malformed(format!("expected \"new\", found \"{}\"", other))),
+    };
+
+    //Parse each command based on the identifying first clause
+    let token = match id {
+        "exit" => TokenType::Exit {},
+        "" => return Ok(None),
+
+        "print" => TokenType::Print {},
+        "printnum" => TokenType::PrintNum {},
+        "getnextin" => TokenType::GetNextIn {},
+
+        "createfile" => TokenType::CreateFile {
+            arg: optional_part(1),
+        },
+        "createfilestream" => TokenType::CreateFileStream {
+            arg: optional_part(1),
+            new_handle: new_handle_part(2)?,
+        },
+        "openfilestream" => {
+            let arg = optional_part(1);
+            let new_handle = new_handle_part(3)?;
+            match parts.get(2).copied().unwrap_or("") {
+                "" | "read" => TokenType::OpenFileStream { arg, new_handle },
+                "append" => TokenType::AppendFileStream { arg, new_handle },
+                "readwrite" => TokenType::ReadWriteFileStream { arg, new_handle },
+                mode => {
+                    return Err(malformed(format!(
+                        "unknown `openfilestream` mode \"{}\"",
+                        mode
+                    )))
+                }
+            }
+        }
+        "readfilestream" => TokenType::ReadFileStream {
+            handle: handle_part(1, Handle::READ)?,
+        },
+        "writefilestream" => TokenType::WriteFileStream {
+            handle: handle_part(1, Handle::WRITE)?,
+        },
+        "seekfilestream" => TokenType::SeekFileStream {
+            whence: match parts.get(1).copied().unwrap_or("") {
+                "" => Whence::Start,
+                name => Whence::from_name(name).ok_or_else(|| {
+                    malformed(format!("unknown `seekfilestream` origin \"{}\"", name))
+                })?,
+            },
+            handle: handle_part(2, Handle::READ)?,
+        },
+        "tellfilestream" => TokenType::TellFileStream {
+            handle: handle_part(1, Handle::READ)?,
+        },
+        "closefilestream" => TokenType::CloseFileStream {
+            handle: handle_part(1, Handle::Popped)?,
+        },
+        "mkdir" => TokenType::MakeDir {
+            arg: optional_part(1),
+        },
+        "rmdir" => TokenType::RemoveDir {
+            arg: optional_part(1),
+        },
+        "rename" => TokenType::Rename {
+            from: optional_part(1),
+            to: optional_part(2),
+        },
+        "removefile" => TokenType::RemoveFile {
+            arg: optional_part(1),
+        },
+        "exists" => TokenType::Exists {
+            arg: optional_part(1),
+        },
+        "filesize" => TokenType::FileSize {
+            arg: optional_part(1),
+        },
+        "listdir" => TokenType::ListDir {
+            arg: optional_part(1),
+        },
+
+        "push" => TokenType::Push {
+            arg: BigInt::from_str(required_part(1)?)
+                .map_err(|_| malformed(format!("invalid `push` argument \"{}\"", parts[1])))?,
+        },
+        "pop" => TokenType::Pop {
+            arg: stack_part(1)?,
+        },
+
+        "+" => TokenType::Add {},
+        "-" => TokenType::Subtract {},
+        "*" => TokenType::Multiply {},
+        "/" => TokenType::Divide {},
+        "%" => TokenType::Modulo {},
+
+        "move" => TokenType::Move {
+            arg: [stack_part(1)?, stack_part(2)?],
+        },
+        "copy" => TokenType::Copy {
+            arg: [stack_part(1)?, stack_part(2)?],
+        },
+
+        "jump" => TokenType::PreComputeJump {
+            arg: required_part(1)?.to_string(),
+        },
+        "label" => TokenType::Label {
+            arg: required_part(1)?.to_string(),
+        },
+
+        "==" => TokenType::Equal {},
+        ">" => TokenType::GreaterThan {},
+        ">=" => TokenType::GreaterThanOrEqual {},
+        "<" => TokenType::LessThan {},
+        "<=" => TokenType::LessThanOrEqual {},
+
+        "&" => TokenType::BitAnd {},
+        "|" => TokenType::BitOr {},
+        "^" => TokenType::BitXor {},
+        ">>" => TokenType::BitRightShift {},
+        "<<" => TokenType::BitLeftShift {},
+
+        _ => {
+            return Err((
+                TokenizeErrorKind::UnknownCommand,
+                format!("unknown command `{}`", id),
+            ))
+        }
+    };
 
-                "==" => tokens.push(TokenType::Equal {}),
-                ">" => tokens.push(TokenType::GreaterThan {}),
-                ">=" => tokens.push(TokenType::GreaterThanOrEqual {}),
-                "<" => tokens.push(TokenType::LessThan {}),
-                "<=" => tokens.push(TokenType::LessThanOrEqual {}),
+    Ok(Some(token))
+}
 
-                "&" => tokens.push(TokenType::BitAnd {}),
-                "|" => tokens.push(TokenType::BitOr {}),
-                "^" => tokens.push(TokenType::BitXor {}),
-                ">>" => tokens.push(TokenType::BitRightShift {}),
-                "<<" => tokens.push(TokenType::BitLeftShift {}),
+fn parse(
+    file: String,
+    opt_level: usize,
+    numeric_model: NumericModel,
+    verbose: bool,
+    keep_labels: bool,
+) -> Program {
+    let start_time: SystemTime = SystemTime::now();
 
-                _ => println!("Invalid token: {}", command),
-            }
-        }
+    let mut program = tokenize(&file);
 
-        tokens.push(TokenType::Clear);
+    let report = optimize_with(&mut program, opt_level, numeric_model, keep_labels);
+    if verbose {
+        eprint!("{}", report);
     }
-
-    optimize(tokens);
+    let tokens = &program.tokens;
 
     //Debug print out all tokens
     println!();
@@ -208,43 +452,157 @@ fn parse(file: String, tokens: &mut Vec<TokenType>) {
         total_time.as_micros()
     );
     println!("Number of commands: {}\n", tokens_len);
+
+    program
+}
+
+///Uses the path argument of a file command, or pops the path from stack C if it's empty.
+/// The path is popped from the top down, either until a 0 (which is discarded) or through the whole stack
+fn path_or_pop(arg: &str, stack: &mut Stack, until_zero: bool, token_index: usize) -> String {
+    if !arg.is_empty() {
+        return arg.to_string();
+    }
+    //Each value is a byte of the path, so names with characters past ASCII come back as they were listed
+    let mut path: Vec<u8> = Vec::new();
+    while !stack.is_empty() {
+        let c: u8 = stack
+            .pop()
+            .to_u8()
+            .unwrap_or_else(|| panic!("Invalid char value in path. Index: {}", token_index));
+        if until_zero && c == 0 {
+            break;
+        }
+        path.push(c);
+    }
+    String::from_utf8_lossy(&path).to_string()
+}
+
+///Pushes 1 to signal success or -1 to signal failure
+fn push_status(stack: &mut Stack, ok: bool) {
+    stack.push(Value::from(if ok { 1 } else { -1 }));
+}
+
+type StreamSlot = Rc<RefCell<Box<dyn FileStream>>>;
+
+///The open file streams, indexed by their handles. Handles 0 and 1 are what the handle-less commands read from and write to
+struct StreamTable {
+    streams: Vec<Option<StreamSlot>>,
+}
+
+impl StreamTable {
+    ///Stores a newly opened stream and pushes the status, after its handle if it gets a new one.
+    /// Otherwise it replaces each of the `aliases`, which share it so that reads and writes share one position
+    fn open(
+        &mut self,
+        opened: io::Result<Box<dyn FileStream>>,
+        new_handle: bool,
+        aliases: &[usize],
+        stack: &mut Stack,
+    ) {
+        let stream: StreamSlot = match opened {
+            Ok(f) => Rc::new(RefCell::new(f)),
+            Err(_) => return push_status(stack, false),
+        };
+        if new_handle {
+            //Like file descriptors, the lowest free handle is used
+            let handle = match (2..self.streams.len()).find(|&h| self.streams[h].is_none()) {
+                Some(h) => h,
+                None => {
+                    self.streams.push(None);
+                    self.streams.len() - 1
+                }
+            };
+            self.streams[handle] = Some(stream);
+            stack.push(Value::from(handle as i64));
+        } else {
+            for &handle in aliases {
+                self.streams[handle] = Some(stream.clone());
+            }
+        }
+        push_status(stack, true);
+    }
+
+    ///The stream a command uses, popping its handle from `stack` if it isn't an argument
+    fn get(&self, handle: &Handle, stack: &mut Stack) -> Option<StreamSlot> {
+        let index = match handle {
+            Handle::Number(n) => *n as usize,
+            Handle::Popped => usize::try_from(stack.pop().to_i64()?).ok()?,
+        };
+        self.streams.get(index)?.clone()
+    }
+
+    ///Closes a stream, returning whether it was open
+    fn close(&mut self, handle: &Handle, stack: &mut Stack) -> bool {
+        let index = match handle {
+            Handle::Number(n) => Some(*n as usize),
+            Handle::Popped => stack.pop().to_i64().and_then(|n| usize::try_from(n).ok()),
+        };
+        index
+            .and_then(|i| self.streams.get_mut(i))
+            .and_then(|slot| slot.take())
+            .is_some()
+    }
 }
 
-fn interpret(tokens: Vec<TokenType>, mut file_system: Box<dyn FileSystem>) {
+fn interpret(program: Program, mut file_system: Box<dyn FileSystem>, options: RunOptions) {
     //Initialization
+    let tokens = &program.tokens;
 
-    let mut file_stream_write: Box<dyn FileStream> = file_system
-        .create_file_stream("staqdump")
-        .expect("Could not create staqdump (write)");
-    let mut file_stream_read: Box<dyn FileStream> = file_system
-        .open_file_stream("staqdump")
-        .expect("Could not open staqdump (read)");
+    //No stream is open until the program opens one, so reading and writing fail until then
+    let mut streams = StreamTable {
+        streams: vec![None, None],
+    };
 
     //There are three stacks, initialized seperately since they don't implement Copy()
-    let mut stacks: [Stack; 3] = [
-        Stack { dat: Vec::new() },
-        Stack { dat: Vec::new() },
-        Stack { dat: Vec::new() },
-    ];
+    let mut stacks: [Stack; 3] = [Stack::new(), Stack::new(), Stack::new()];
     let mut token_index: usize = 0;
 
+    let mut tracer: Option<Tracer> = options.trace.map(|t| Tracer::new(t, &program));
+    let mut step: usize = 0;
+
+    let mut profile: Option<(ProfileConfig, Profiler)> =
+        options.profile.map(|p| (p, Profiler::new(tokens.len())));
+
+    let numeric_model: NumericModel = options.numeric_model;
+    let mut output: Box<dyn Write> = options.output.unwrap_or_else(|| Box::new(io::stdout()));
+    let mut input: Box<dyn Read> = options.input.unwrap_or_else(|| Box::new(io::stdin()));
+
     //Execution start
     println!("Program execution start\n----");
 
     let program_start_time: SystemTime = SystemTime::now();
 
     let program_exit_reason: String;
+    //Set by the exit command so that the exit can still be traced before breaking
+    let mut exit_reason: Option<String> = None;
 
     loop {
         if token_index >= tokens.len() {
             program_exit_reason = "successfully reached end of program".to_string();
             break;
         }
+
+        let tracing: bool = match &tracer {
+            Some(t) => t.wants(token_index, &tokens[token_index]),
+            None => false,
+        };
+        if tracing {
+            for stack in stacks.iter_mut() {
+                stack.start_journal();
+            }
+        }
+        //Remembered for tracing, since jumps change token_index
+        let executed_index: usize = token_index;
+        let step_start: Option<Instant> = profile.as_ref().map(|_| Instant::now());
+        let runtime_error = |e: ArithmeticError| match program.spans.get(token_index) {
+            Some(span) => format!("runtime error at index {} ({}): {}", token_index, span, e),
+            None => format!("runtime error at index {}: {}", token_index, e),
+        };
+
         //Execute the correct method for the enum
         match &tokens[token_index] {
             TokenType::Exit => {
-                program_exit_reason = format!("exit command called from index {}", token_index);
-                break;
+                exit_reason = Some(format!("exit command called from index {}", token_index));
             }
 
             TokenType::Print => {
@@ -256,7 +614,7 @@ fn interpret(tokens: Vec<TokenType>, mut file_system: Box<dyn FileSystem>) {
                     ) as char;
                     s.push(c);
                 }
-                print!("{}", s);
+                write!(output, "{}", s).expect("Failed writing program output");
             }
             TokenType::PrintNum => {
                 let stack_len: usize = stacks[2].len();
@@ -264,117 +622,69 @@ fn interpret(tokens: Vec<TokenType>, mut file_system: Box<dyn FileSystem>) {
                 for _ in 0..stack_len {
                     s += stacks[2].pop().to_string().as_str();
                 }
-                print!("{}", s);
+                write!(output, "{}", s).expect("Failed writing program output");
             }
             TokenType::GetNextIn => {
+                output.flush().expect("Failed writing program output");
                 let mut arr = [0];
-                if std::io::stdin().read_exact(&mut arr).is_ok() {
-                    stacks[2].push(BigInt::from_i16(arr[0] as i16).unwrap());
+                if input.read_exact(&mut arr).is_ok() {
+                    stacks[2].push(Value::from(arr[0] as i64));
                 }
             }
 
             TokenType::CreateFile { arg } => {
-                let mut path: String;
-                if arg.is_empty() {
-                    path = "".to_string();
-                    for _ in 0..stacks[2].len() {
-                        let c: char = stacks[2].pop().to_u8().expect(
-                            format!("Invalid char value in print index {}", token_index).as_str(),
-                        ) as char;
-                        path.push(c);
-                    }
-                } else {
-                    path = arg.to_string();
-                }
-
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
                 //Discard the file stream since the only importance is whether or not the file was successfully created
-                if let Ok(_) = file_system.create_file_stream(&path) {
-                    //Signal success
-                    stacks[2]
-                        .push(BigInt::from_i32(1).expect("Invalid conversion from 1 to BigInt"));
-                } else {
-                    //Signal failure
-                    stacks[2]
-                        .push(BigInt::from_i32(-1).expect("Invalid conversion from -1 to BigInt"));
-                }
+                let created = file_system.create_file_stream(&path).is_ok();
+                push_status(&mut stacks[2], created);
             }
-            TokenType::CreateFileStream { arg } => {
-                let mut path: String;
-                if arg.is_empty() {
-                    path = "".to_string();
-                    for _ in 0..stacks[2].len() {
-                        let c: char = stacks[2].pop().to_u8().expect(
-                            format!("Invalid char value in print index {}", token_index).as_str(),
-                        ) as char;
-                        path.push(c);
-                    }
-                } else {
-                    path = arg.to_string();
-                }
-
-                //If the file doesn't open properly, push -1 to the c stack. Otherwise, push 1
-                if let Ok(f) = file_system.create_file_stream(&path) {
-                    file_stream_write = f;
-                    //Signal success
-                    stacks[2]
-                        .push(BigInt::from_i32(1).expect("Invalid conversion from 1 to BigInt"));
-                } else {
-                    //Signal failure
-                    stacks[2]
-                        .push(BigInt::from_i32(-1).expect("Invalid conversion from -1 to BigInt"));
-                }
+            TokenType::CreateFileStream { arg, new_handle } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                let opened = file_system.create_file_stream(&path);
+                streams.open(opened, *new_handle, &[1], &mut stacks[2]);
             }
-            TokenType::OpenFileStream { arg } => {
-                let mut path: String;
-                if arg.is_empty() {
-                    path = "".to_string();
-                    for _ in 0..stacks[2].len() {
-                        let c: char = stacks[2].pop().to_u8().expect(
-                            format!("Invalid char value in print index {}", token_index).as_str(),
-                        ) as char;
-                        path.push(c);
-                    }
-                } else {
-                    path = arg.to_string();
-                }
-
-                //If the file doesn't open properly, push -1 to the c stack. Otherwise, push 1
-                if let Ok(f) = file_system.open_file_stream(&path) {
-                    file_stream_read = f;
-                    //Signal success
-                    stacks[2]
-                        .push(BigInt::from_i32(1).expect("Invalid conversion from 1 to BigInt"));
-                } else {
-                    //Signal failure
-                    stacks[2]
-                        .push(BigInt::from_i32(-1).expect("Invalid conversion from -1 to BigInt"));
-                }
+            TokenType::OpenFileStream { arg, new_handle } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                let opened = file_system.open_file_stream(&path);
+                streams.open(opened, *new_handle, &[0], &mut stacks[2]);
+            }
+            TokenType::AppendFileStream { arg, new_handle } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                let opened = file_system.open_file_stream_with(&path, FileStreamMode::Append);
+                streams.open(opened, *new_handle, &[1], &mut stacks[2]);
+            }
+            TokenType::ReadWriteFileStream { arg, new_handle } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                let opened = file_system.open_file_stream_with(&path, FileStreamMode::ReadWrite);
+                streams.open(opened, *new_handle, &[0, 1], &mut stacks[2]);
             }
-            TokenType::ReadFileStream => {
+            TokenType::ReadFileStream { handle } => {
                 let mut arr: [u8; 1] = [1];
-                match file_stream_read.read(&mut arr) {
-                    Ok(bytes_read) => {
-                        let push_value: BigInt = if bytes_read == 0 {
-                            BigInt::from_i32(-1).expect("Failed to convert -1 to BigInt")
+                let read = streams
+                    .get(handle, &mut stacks[2])
+                    .map(|f| f.borrow_mut().read(&mut arr));
+                match read {
+                    Some(Ok(bytes_read)) => {
+                        let push_value: Value = if bytes_read == 0 {
+                            Value::from(-1)
                         } else {
-                            BigInt::from_u8(arr[0]).expect("Failed to convert from u8 to BigInt")
+                            Value::from(arr[0] as i64)
                         };
 
                         stacks[2].push(push_value);
 
                         //Signal success
-                        stacks[2].push(
-                            BigInt::from_i32(1).expect("Invalid conversion from 1 to BigInt"),
-                        );
+                        stacks[2].push(Value::from(1));
                     }
-                    Err(_) => {
+                    _ => {
                         //Signal failure
-                        stacks[2]
-                            .push(BigInt::from_i32(-1).expect("Failed to convert -1 to BigInt"));
+                        stacks[2].push(Value::from(-1));
                     }
                 }
             }
-            TokenType::WriteFileStream => {
+            TokenType::WriteFileStream { handle } => {
+                //The handle is popped before the bytes to write
+                let stream = streams.get(handle, &mut stacks[2]);
                 let mut arr: Vec<u8> = Vec::with_capacity(stacks[2].len());
                 for _ in 0..stacks[2].len() {
                     arr.push(
@@ -387,141 +697,218 @@ fn interpret(tokens: Vec<TokenType>, mut file_system: Box<dyn FileSystem>) {
                         ),
                     );
                 }
-                match file_stream_write.write(&arr) {
-                    Ok(_) => {
+                match stream.map(|f| f.borrow_mut().write(&arr)) {
+                    Some(Ok(_)) => {
                         //Signal success
-                        stacks[2].push(
-                            BigInt::from_i32(1).expect("Invalid conversion from 1 to BigInt"),
-                        );
+                        stacks[2].push(Value::from(1));
                     }
-                    Err(_) => {
+                    _ => {
                         //Signal failure
-                        stacks[2]
-                            .push(BigInt::from_i32(-1).expect("Failed to convert -1 to BigInt"));
+                        stacks[2].push(Value::from(-1));
                     }
                 }
             }
-
-            TokenType::Clear => stacks[2].clear(),
-            TokenType::Push { arg } => stacks[2].push(arg.to_owned()),
-            TokenType::Pop { arg } => {
-                stacks[(*arg) as usize].pop();
+            TokenType::SeekFileStream { whence, handle } => {
+                //The handle is popped before the offset
+                let stream = streams.get(handle, &mut stacks[2]);
+                let offset = stacks[2].pop().to_i64();
+                let target = match (whence, offset) {
+                    (Whence::Start, Some(n)) if n >= 0 => Some(SeekFrom::Start(n as u64)),
+                    (Whence::Current, Some(n)) => Some(SeekFrom::Current(n)),
+                    (Whence::End, Some(n)) => Some(SeekFrom::End(n)),
+                    _ => None,
+                };
+                let ok = match (stream, target) {
+                    (Some(f), Some(t)) => f.borrow_mut().seek(t).is_ok(),
+                    _ => false,
+                };
+                push_status(&mut stacks[2], ok);
             }
-
-            TokenType::Add => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a + b)
+            TokenType::TellFileStream { handle } => {
+                let position = streams
+                    .get(handle, &mut stacks[2])
+                    .map(|f| f.borrow_mut().stream_position());
+                match position {
+                    Some(Ok(pos)) => {
+                        stacks[2].push(Value::from(pos as i64));
+                        push_status(&mut stacks[2], true);
+                    }
+                    _ => push_status(&mut stacks[2], false),
+                }
+            }
+            TokenType::CloseFileStream { handle } => {
+                let ok = streams.close(handle, &mut stacks[2]);
+                push_status(&mut stacks[2], ok);
+            }
+            TokenType::MakeDir { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                push_status(&mut stacks[2], file_system.create_dir(&path).is_ok());
             }
-            TokenType::Subtract => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a - b)
+            TokenType::RemoveDir { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                push_status(&mut stacks[2], file_system.remove_dir(&path, false).is_ok());
             }
-            TokenType::Multiply => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a * b)
+            TokenType::Rename { from, to } => {
+                //When both paths come from stack C, the first one ends at a 0
+                let from = path_or_pop(from, &mut stacks[2], to.is_empty(), token_index);
+                let to = path_or_pop(to, &mut stacks[2], false, token_index);
+                push_status(&mut stacks[2], file_system.rename(&from, &to).is_ok());
             }
-            TokenType::Divide => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a / b)
+            TokenType::RemoveFile { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                push_status(&mut stacks[2], file_system.remove_file(&path).is_ok());
             }
-            TokenType::Modulo => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a % b)
+            TokenType::Exists { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                push_status(&mut stacks[2], file_system.exists(&path));
+            }
+            TokenType::FileSize { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                //Directories don't have a size, so they count as a failure
+                match file_system.metadata(&path) {
+                    Ok(m) if !m.is_dir => {
+                        stacks[2].push(Value::from(m.size as i64));
+                        push_status(&mut stacks[2], true);
+                    }
+                    _ => push_status(&mut stacks[2], false),
+                }
+            }
+            TokenType::ListDir { arg } => {
+                let path = path_or_pop(arg, &mut stacks[2], false, token_index);
+                match file_system.ls(&path) {
+                    Ok(names) => {
+                        //The first name ends up nearest the top, with its first byte uppermost
+                        for name in names.iter().rev() {
+                            stacks[2].push(Value::from(0));
+                            for b in name.bytes().rev() {
+                                stacks[2].push(Value::from(b as i64));
+                            }
+                        }
+                        stacks[2].push(Value::from(names.len() as i64));
+                        push_status(&mut stacks[2], true);
+                    }
+                    Err(_) => push_status(&mut stacks[2], false),
+                }
+            }
+
+            TokenType::Clear => stacks[2].clear(),
+            TokenType::Push { arg } => match numeric_model.constant(arg) {
+                Ok(n) => stacks[2].push(n),
+                Err(e) => exit_reason = Some(runtime_error(e)),
+            },
+            TokenType::Pop { arg } => {
+                stacks[(*arg) as usize].pop();
             }
 
             TokenType::Move { arg } => {
-                let n: BigInt = stacks[arg[0] as usize].pop();
+                let n: Value = stacks[arg[0] as usize].pop();
                 stacks[arg[1] as usize].push(n);
             }
             TokenType::Copy { arg } => {
-                let n: BigInt = stacks[arg[0] as usize].pop();
+                let n: Value = stacks[arg[0] as usize].pop();
                 stacks[arg[0] as usize].push(n.to_owned());
                 stacks[arg[1] as usize].push(n);
             }
 
             TokenType::Jump { arg } => {
-                let n: BigInt = stacks[2].pop();
-                if n > BigInt::from(0i32) {
+                let n: Value = stacks[2].pop();
+                //Jumps to undefined labels are never taken
+                if n.is_positive() && *arg < tokens.len() {
                     token_index = *arg;
                 }
             }
             TokenType::Label { arg } => (),
 
-            TokenType::Equal => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(BigInt::from((a == b) as i32))
-            }
-            TokenType::GreaterThan => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(BigInt::from((a > b) as i32))
-            }
-            TokenType::GreaterThanOrEqual => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(BigInt::from((a >= b) as i32))
-            }
-            TokenType::LessThan => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(BigInt::from((a < b) as i32))
+            TokenType::PushTo { stack, value } => match numeric_model.constant(value) {
+                Ok(n) => stacks[*stack as usize].push(n),
+                Err(e) => exit_reason = Some(runtime_error(e)),
+            },
+            TokenType::DecA => {
+                let a: Value = stacks[0].pop();
+                match numeric_model.apply(BinaryOp::Subtract, a, Value::from(1)) {
+                    Ok(n) => stacks[0].push(n),
+                    Err(e) => exit_reason = Some(runtime_error(e)),
+                }
             }
-            TokenType::LessThanOrEqual => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(BigInt::from((a <= b) as i32))
+            TokenType::JumpIfTopPositive { stack, target } => {
+                let n: Value = stacks[*stack as usize].pop();
+                if n.is_positive() && *target < tokens.len() {
+                    token_index = *target;
+                }
+                stacks[*stack as usize].push(n);
             }
 
-            TokenType::BitAnd => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a & b)
-            }
-            TokenType::BitOr => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a | b)
-            }
-            TokenType::BitXor => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a ^ b)
-            }
-            TokenType::BitRightShift => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a >> b.to_i128().expect("shift value too high"))
-            }
-            TokenType::BitLeftShift => {
-                let a: BigInt = stacks[0].pop();
-                let b: BigInt = stacks[1].pop();
-                stacks[2].push(a << b.to_i128().expect("shift value too high"))
+            token if token.binary_op().is_some() => {
+                let a: Value = stacks[0].pop();
+                let b: Value = stacks[1].pop();
+                match numeric_model.apply(token.binary_op().unwrap(), a, b) {
+                    Ok(n) => stacks[2].push(n),
+                    Err(e) => exit_reason = Some(runtime_error(e)),
+                }
             }
 
             _ => println!("Invalid token in execution. Index: {}", token_index),
         }
 
+        if let (Some((_, profiler)), Some(start)) = (&mut profile, step_start) {
+            profiler.record(executed_index, start.elapsed());
+        }
+
+        if tracing {
+            let journals = [
+                stacks[0].take_journal(),
+                stacks[1].take_journal(),
+                stacks[2].take_journal(),
+            ];
+            let heights = [stacks[0].len(), stacks[1].len(), stacks[2].len()];
+            let result = tracer.as_mut().unwrap().record(
+                step,
+                executed_index,
+                program.spans.get(executed_index).copied(),
+                &tokens[executed_index],
+                &journals,
+                heights,
+            );
+            if let Err(e) = result {
+                eprintln!("Failed to write trace, tracing disabled: {}", e);
+                tracer = None;
+            }
+        }
+        step += 1;
+
+        if let Some(reason) = exit_reason.take() {
+            program_exit_reason = reason;
+            break;
+        }
+
         token_index += 1;
     }
 
+    output.flush().expect("Failed writing program output");
+
+    if let Some(tracer) = &mut tracer {
+        if let Err(e) = tracer.finish() {
+            eprintln!("Failed to write trace: {}", e);
+        }
+    }
+
     let program_time: std::time::Duration = SystemTime::now()
         .duration_since(program_start_time)
         .expect("Time went backwards!");
 
-    file_system
-        .remove_file("staqdump")
-        .expect("Failed to remove 'staqdump' temporary file");
-
     println!(
         "\n----\nProgram execution finished: {}\nTime taken: {}ms or {}μs",
         program_exit_reason,
         program_time.as_millis(),
         program_time.as_micros()
     );
+
+    if let Some((config, profiler)) = &mut profile {
+        println!("\n{}", profiler.report(&program, config.top));
+        if let Some(writer) = &mut config.collapsed {
+            if let Err(e) = profiler.write_collapsed(&program, writer.as_mut()) {
+                eprintln!("Failed to write collapsed stacks: {}", e);
+            }
+        }
+    }
 }
@@ -1,8 +1,21 @@
 extern crate num;
 
+pub mod analysis;
+pub mod bytecode;
+pub mod cfg;
+pub mod disasm;
+pub mod emit;
+pub mod emit_c;
+pub mod emit_rust;
+pub mod emit_wat;
 pub mod interpreter;
+pub mod lint;
 pub mod optimize;
-mod token;
+pub mod profile;
+pub mod tar;
+pub mod token;
+pub mod trace;
+pub mod value;
 pub mod vfs;
 
 use interpreter::*;
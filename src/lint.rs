@@ -0,0 +1,275 @@
+use std::collections::HashSet;
+
+use crate::{
+    analysis::{is_binary_op, token_heights},
+    interpreter::{tokenize_checked, TokenizeError, TokenizeErrorKind},
+    optimize::resolve_jumps,
+    token::{Program, Span, TokenType},
+};
+
+///Every kind of warning the linter can report. Each one has a stable code and name, either of which can be used to suppress it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LintCode {
+    ///`print` or `printnum` when stack C is always empty
+    PrintEmpty,
+    ///`jump` when stack C is always empty, so it can never jump
+    JumpEmpty,
+    ///An operation reading stacks A and B when one of them is always empty
+    EmptyOperand,
+    ///Code which can never be executed
+    Unreachable,
+    ///`move` from a stack to itself
+    SelfMove,
+    ///A command which doesn't exist, which is skipped when run
+    UnknownCommand,
+    ///A command with a missing or invalid argument, which stops the program from running
+    Malformed,
+}
+
+impl LintCode {
+    pub const ALL: [LintCode; 7] = [
+        LintCode::PrintEmpty,
+        LintCode::JumpEmpty,
+        LintCode::EmptyOperand,
+        LintCode::Unreachable,
+        LintCode::SelfMove,
+        LintCode::UnknownCommand,
+        LintCode::Malformed,
+    ];
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            LintCode::PrintEmpty => "L001",
+            LintCode::JumpEmpty => "L002",
+            LintCode::EmptyOperand => "L003",
+            LintCode::Unreachable => "L004",
+            LintCode::SelfMove => "L005",
+            LintCode::UnknownCommand => "L006",
+            LintCode::Malformed => "L007",
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            LintCode::PrintEmpty => "print-empty-c",
+            LintCode::JumpEmpty => "jump-empty-c",
+            LintCode::EmptyOperand => "empty-operand",
+            LintCode::Unreachable => "unreachable-code",
+            LintCode::SelfMove => "self-move",
+            LintCode::UnknownCommand => "unknown-command",
+            LintCode::Malformed => "malformed-command",
+        }
+    }
+
+    ///Finds the lint with the given code (ex. "L001") or name (ex. "print-empty-c")
+    pub fn from_name(s: &str) -> Option<LintCode> {
+        LintCode::ALL
+            .into_iter()
+            .find(|l| l.code().eq_ignore_ascii_case(s) || l.name() == s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lint {
+    pub code: LintCode,
+    pub span: Span,
+    pub message: String,
+}
+
+impl From<TokenizeError> for Lint {
+    fn from(e: TokenizeError) -> Lint {
+        Lint {
+            code: match e.kind {
+                TokenizeErrorKind::UnknownCommand => LintCode::UnknownCommand,
+                TokenizeErrorKind::Malformed => LintCode::Malformed,
+            },
+            span: e.span,
+            message: e.message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct LintConfig {
+    ///Lints which will never be reported
+    pub allowed: HashSet<LintCode>,
+}
+
+///Finds the lints suppressed on each line using comments of the form `//lint-allow:L001,self-move`.
+/// A comment on a line with no commands applies to the following line instead
+fn suppressed_lints(source: &str) -> Vec<(usize, LintCode)> {
+    let mut suppressed = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let comment_start = match line.find("//lint-allow:") {
+            Some(i) => i,
+            None => continue,
+        };
+        let list = line[comment_start + "//lint-allow:".len()..]
+            .split(' ')
+            .next()
+            .unwrap_or("");
+        let line_number = if line[..comment_start].trim().is_empty() {
+            line_index + 2
+        } else {
+            line_index + 1
+        };
+
+        for name in list.split(',') {
+            if let Some(code) = LintCode::from_name(name) {
+                suppressed.push((line_number, code));
+            }
+        }
+    }
+
+    suppressed
+}
+
+///Lints StaqLang source code, returning every warning in source order
+pub fn lint(source: &str, config: &LintConfig) -> Vec<Lint> {
+    let (mut program, errors) = tokenize_checked(source);
+    resolve_jumps(&mut program.tokens);
+
+    let suppressed = suppressed_lints(source);
+
+    //Commands which couldn't be tokenized are reported, and the rest of the program is linted without them
+    let tokenize_lints = errors.into_iter().map(Lint::from);
+
+    let mut lints: Vec<Lint> = lint_program(&program)
+        .into_iter()
+        .chain(tokenize_lints)
+        .filter(|l| !config.allowed.contains(&l.code))
+        .filter(|l| !suppressed.contains(&(l.span.line, l.code)))
+        .collect();
+    lints.sort_by_key(|l| (l.span.line, l.span.col));
+
+    lints
+}
+
+///Lints an already tokenized program. Jumps must already be resolved
+pub fn lint_program(program: &Program) -> Vec<Lint> {
+    let tokens = &program.tokens;
+    let states = token_heights(tokens);
+
+    let mut lints = Vec::new();
+    let mut in_unreachable_run = false;
+
+    for i in 0..tokens.len() {
+        let span = program.spans[i];
+        let token = &tokens[i];
+
+        if let TokenType::Move { arg } = token {
+            if arg[0] == arg[1] {
+                lints.push(Lint {
+                    code: LintCode::SelfMove,
+                    span,
+                    message: "`move` from a stack to itself does nothing".to_string(),
+                });
+            }
+        }
+
+        let state = match states[i] {
+            Some(s) => {
+                in_unreachable_run = false;
+                s
+            }
+            None => {
+                //Only report the first real command of each unreachable section
+                let is_filler = matches!(token, TokenType::Clear | TokenType::Label { .. });
+                if !in_unreachable_run && !is_filler {
+                    in_unreachable_run = true;
+                    lints.push(Lint {
+                        code: LintCode::Unreachable,
+                        span,
+                        message: "unreachable code".to_string(),
+                    });
+                }
+                continue;
+            }
+        };
+
+        match token {
+            TokenType::Print | TokenType::PrintNum if state[2].is_empty() => lints.push(Lint {
+                code: LintCode::PrintEmpty,
+                span,
+                message: "stack C is always empty here, so this prints nothing".to_string(),
+            }),
+            TokenType::Jump { .. } if state[2].is_empty() => lints.push(Lint {
+                code: LintCode::JumpEmpty,
+                span,
+                message: "stack C is always empty here, so this never jumps".to_string(),
+            }),
+            _ if is_binary_op(token) => {
+                for (stack, name) in [(0, 'A'), (1, 'B')] {
+                    if state[stack].is_empty() {
+                        lints.push(Lint {
+                            code: LintCode::EmptyOperand,
+                            span,
+                            message: format!(
+                                "stack {} is always empty here, so its operand is always 0",
+                                name
+                            ),
+                        });
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    lints
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lint, LintCode, LintConfig};
+
+    fn codes(source: &str) -> Vec<LintCode> {
+        lint(source, &LintConfig::default())
+            .into_iter()
+            .map(|l| l.code)
+            .collect()
+    }
+
+    #[test]
+    fn lints() {
+        assert_eq!(codes("print"), vec![LintCode::PrintEmpty]);
+        assert_eq!(codes("jump:a\nlabel:a"), vec![LintCode::JumpEmpty]);
+        assert_eq!(codes("push:1 move:C:A +"), vec![LintCode::EmptyOperand]);
+        assert_eq!(codes("exit\npush:1 print"), vec![LintCode::Unreachable]);
+        assert_eq!(codes("move:C:C"), vec![LintCode::SelfMove]);
+
+        //Code after `exit` which is jumped to is reachable
+        assert!(codes("push:1 jump:a\nexit\nlabel:a push:1 print").is_empty());
+    }
+
+    #[test]
+    fn bad_commands() {
+        assert_eq!(codes("frobnicate"), vec![LintCode::UnknownCommand]);
+        assert_eq!(codes("push:abc"), vec![LintCode::Malformed]);
+        assert_eq!(codes("push"), vec![LintCode::Malformed]);
+        assert_eq!(codes("move:A:D"), vec![LintCode::Malformed]);
+        assert_eq!(codes("seekfilestream:middle"), vec![LintCode::Malformed]);
+
+        //The rest of the line is still linted
+        let lints = lint("push:1 push:x move:C:C", &LintConfig::default());
+        assert_eq!(
+            lints.iter().map(|l| l.code).collect::<Vec<_>>(),
+            vec![LintCode::Malformed, LintCode::SelfMove]
+        );
+        assert_eq!((lints[0].span.col, lints[0].span.len), (8, 6));
+        assert_eq!(lints[0].message, "invalid `push` argument \"x\"");
+
+        assert!(codes("frobnicate //lint-allow:unknown-command").is_empty());
+    }
+
+    #[test]
+    fn suppression() {
+        assert!(codes("print //lint-allow:L001").is_empty());
+        assert!(codes("//lint-allow:print-empty-c\nprint").is_empty());
+
+        let mut config = LintConfig::default();
+        config.allowed.insert(LintCode::SelfMove);
+        assert!(lint("move:A:A", &config).is_empty());
+    }
+}
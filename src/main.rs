@@ -1,14 +1,551 @@
-use staq_lang_parser::interpreter::run_from_file_path;
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read},
+    process::exit,
+};
 
-extern crate num;
-
-pub mod interpreter;
-pub mod optimize;
-mod token;
-pub mod vfs;
+use staq_lang_parser::{
+    analysis::analyze,
+    bytecode::{decode, encode, is_bytecode},
+    cfg::{render, CfgFormat},
+    disasm::{assemble, disassemble},
+    emit_c::emit_c,
+    emit_rust::emit_rust,
+    emit_wat::emit_wat,
+    interpreter::{
+        program_root, run_from_file_path, run_from_file_path_with, tokenize, tokenize_checked,
+        RunOptions,
+    },
+    lint::{lint, Lint, LintCode, LintConfig},
+    optimize::{optimize, resolve_jumps},
+    profile::ProfileConfig,
+    token::Program,
+    trace::TraceConfig,
+    value::NumericModel,
+    vfs::{OverlayFileSystem, RealLocalFileSystem, VirtualFileSystem},
+};
 
 fn main() {
     //println!("{:?}", std::env::args().collect::<Vec<String>>());
-    let file_path = std::env::args().nth(1).unwrap();
-    run_from_file_path(file_path);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|s| s.as_str()) {
+        Some("run") => run_command(&args[1..]),
+        Some("lint") => lint_command(&args[1..]),
+        Some("analyze") => {
+            let file_path = expect_file_arg(&args);
+            let program = tokenize_reporting(&file_path, &read_source(&file_path));
+            print!("{}", analyze(&program.tokens).report(&program));
+        }
+        Some("cfg") => cfg_command(&args[1..]),
+        Some("compile") => compile_command(&args[1..]),
+        Some("disasm") => print!(
+            "{}",
+            disassemble(&load_program(
+                &expect_file_arg(&args),
+                0,
+                NumericModel::default(),
+                false,
+            ))
+        ),
+        Some("asm") => asm_command(&args[1..]),
+        Some("emit-rust") => emit_command("emit-rust", &args[1..]),
+        Some("emit-c") => emit_command("emit-c", &args[1..]),
+        Some("emit-wat") => emit_command("emit-wat", &args[1..]),
+        //With no subcommand, the first argument is the file to run
+        Some(_) => run_command(&args),
+        None => {
+            eprintln!("Usage: staq-lang-parser [run|compile|disasm|asm|emit-rust|emit-c|emit-wat|lint|analyze|cfg] <file> [options]");
+            exit(2);
+        }
+    }
+}
+
+///Gets the file path argument following a subcommand, exiting if there isn't one
+fn expect_file_arg(args: &[String]) -> String {
+    match args.get(1) {
+        Some(path) => path.clone(),
+        None => {
+            eprintln!("Expected a file path after `{}`", args[0]);
+            exit(2);
+        }
+    }
+}
+
+fn read_source(file_path: &str) -> String {
+    let mut s = String::new();
+    File::open(file_path)
+        .expect("Cannot open file from file_path")
+        .read_to_string(&mut s)
+        .unwrap();
+    s
+}
+
+///Reads either source code (which is tokenized and has its jumps resolved) or a compiled program.
+/// With `verbose`, prints what optimizing the source removed
+fn load_program(file_path: &str, opt_level: usize, model: NumericModel, verbose: bool) -> Program {
+    let bytes = match std::fs::read(file_path) {
+        Ok(b) => b,
+        Err(e) => {
+            eprintln!("Cannot read {}: {}", file_path, e);
+            exit(1);
+        }
+    };
+    if is_bytecode(&bytes) {
+        match decode(&bytes) {
+            Ok(p) => p,
+            Err(e) => {
+                eprintln!("Cannot decode {}: {}", file_path, e);
+                exit(1);
+            }
+        }
+    } else {
+        let mut program = tokenize(&String::from_utf8_lossy(&bytes));
+        let report = optimize(&mut program, opt_level, model);
+        if verbose {
+            eprint!("{}", report);
+        }
+        program
+    }
+}
+
+///`run <file> [--trace FILE] [--trace-kind KIND,...] [--trace-labels START[:END]] [--trace-limit N] [--profile] [--profile-collapsed FILE] [--numeric-model bigint|wrap64|checked64] [-O LEVEL] [-v] [--fs real|overlay] [--vfs-in TAR] [--vfs-out TAR]`
+/// With `--fs overlay`, the program reads files from disk but its changes are kept in memory, and are listed on stderr once it finishes.
+/// With `--vfs-in` or `--vfs-out`, the program doesn't touch the disk: its files start as the ones in the `--vfs-in` archive and are saved to the `--vfs-out` archive
+fn run_command(args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut options = RunOptions::default();
+
+    let mut trace_path: Option<&String> = None;
+    let mut trace_kinds: Vec<String> = Vec::new();
+    let mut trace_labels: Option<(String, Option<String>)> = None;
+    let mut trace_limit: Option<usize> = None;
+    let mut overlay = false;
+    let mut vfs_in: Option<&String> = None;
+    let mut vfs_out: Option<&String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--trace" => trace_path = Some(expect_value(arg, args.next())),
+            "--trace-kind" => trace_kinds.extend(
+                expect_value(arg, args.next())
+                    .split(',')
+                    .map(|s| s.to_string()),
+            ),
+            "--trace-labels" => {
+                let range = expect_value(arg, args.next());
+                trace_labels = Some(match range.split_once(':') {
+                    Some((start, end)) => (start.to_string(), Some(end.to_string())),
+                    None => (range.to_string(), None),
+                });
+            }
+            "--trace-limit" => match expect_value(arg, args.next()).parse() {
+                Ok(n) => trace_limit = Some(n),
+                Err(_) => {
+                    eprintln!("Expected a number after --trace-limit");
+                    exit(2);
+                }
+            },
+            "--numeric-model" => {
+                options.numeric_model = parse_numeric_model(expect_value(arg, args.next()))
+            }
+            "-O" | "--opt-level" => {
+                options.opt_level = parse_opt_level(expect_value(arg, args.next()))
+            }
+            "-v" | "--verbose" => options.verbose = true,
+            "--fs" => {
+                overlay = match expect_value(arg, args.next()).as_str() {
+                    "real" => false,
+                    "overlay" => true,
+                    name => {
+                        eprintln!("Unknown file system: {}. Expected real or overlay", name);
+                        exit(2);
+                    }
+                }
+            }
+            "--vfs-in" => vfs_in = Some(expect_value(arg, args.next())),
+            "--vfs-out" => vfs_out = Some(expect_value(arg, args.next())),
+            "--profile" => {
+                options.profile.get_or_insert_with(ProfileConfig::default);
+            }
+            "--profile-collapsed" => {
+                let path = expect_value(arg, args.next());
+                let file = match File::create(path) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        eprintln!("Cannot create collapsed stack file {}: {}", path, e);
+                        exit(1);
+                    }
+                };
+                options
+                    .profile
+                    .get_or_insert_with(ProfileConfig::default)
+                    .collapsed = Some(Box::new(BufWriter::new(file)));
+            }
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser run <file> [--trace FILE] [--trace-kind KIND,...] [--trace-labels START[:END]] [--trace-limit N] [--profile] [--profile-collapsed FILE] [--numeric-model bigint|wrap64|checked64] [-O LEVEL] [-v] [--fs real|overlay] [--vfs-in TAR] [--vfs-out TAR]");
+            exit(2);
+        }
+    };
+
+    if let Some(path) = trace_path {
+        let mut trace = match TraceConfig::to_file(path) {
+            Ok(t) => t,
+            Err(e) => {
+                eprintln!("Cannot create trace file {}: {}", path, e);
+                exit(1);
+            }
+        };
+        trace.filter.kinds = trace_kinds;
+        trace.filter.label_range = trace_labels;
+        trace.limit = trace_limit;
+        options.trace = Some(trace);
+    }
+
+    if vfs_in.is_some() || vfs_out.is_some() {
+        if overlay {
+            eprintln!("--fs overlay can't be used with --vfs-in or --vfs-out");
+            exit(2);
+        }
+        run_in_memory(file_path, vfs_in, vfs_out, options);
+    } else if overlay {
+        run_in_overlay(file_path, options);
+    } else {
+        run_from_file_path(file_path.clone(), options);
+    }
+}
+
+///Runs the program with its changes kept in memory, listing them on stderr afterwards
+fn run_in_overlay(file_path: &str, options: RunOptions) {
+    //The program gets a clone, which shares its layers, so the changes can still be read afterwards
+    let fs = OverlayFileSystem::new(RealLocalFileSystem {
+        root: program_root(file_path),
+    });
+    run_from_file_path_with(file_path.to_string(), Box::new(fs.clone()), options);
+    match fs.diff() {
+        Ok(changes) => {
+            for change in changes {
+                eprintln!("{}", change);
+            }
+        }
+        Err(e) => {
+            eprintln!("Cannot read the overlay's changes: {}", e);
+            exit(1);
+        }
+    }
+}
+
+///Runs the program on a virtual file system, which starts with the files in the `vfs_in` archive (or empty),
+/// and is saved to the `vfs_out` archive afterwards
+fn run_in_memory(
+    file_path: &str,
+    vfs_in: Option<&String>,
+    vfs_out: Option<&String>,
+    options: RunOptions,
+) {
+    let fs = match vfs_in {
+        Some(path) => {
+            match File::open(path).and_then(|f| VirtualFileSystem::from_tar(BufReader::new(f))) {
+                Ok(fs) => fs,
+                Err(e) => {
+                    eprintln!("Cannot load {}: {}", path, e);
+                    exit(1);
+                }
+            }
+        }
+        None => VirtualFileSystem::new(),
+    };
+    //Like the overlay, clones share their files
+    run_from_file_path_with(file_path.to_string(), Box::new(fs.clone()), options);
+
+    if let Some(path) = vfs_out {
+        let mut archive = Vec::new();
+        if let Err(e) = fs
+            .to_tar(&mut archive)
+            .and_then(|_| std::fs::write(path, archive))
+        {
+            eprintln!("Cannot save {}: {}", path, e);
+            exit(1);
+        }
+    }
+}
+
+fn parse_numeric_model(name: &str) -> NumericModel {
+    match NumericModel::from_name(name) {
+        Some(m) => m,
+        None => {
+            eprintln!(
+                "Unknown numeric model: {}. Expected bigint, wrap64 or checked64",
+                name
+            );
+            exit(2);
+        }
+    }
+}
+
+///Level 0 only resolves jumps, level 1 also threads jumps, removes dead code and labels and fuses common sequences into superinstructions,
+/// and level 2 also folds constants
+fn parse_opt_level(level: &str) -> usize {
+    match level.parse() {
+        Ok(n) if n <= 2 => n,
+        _ => {
+            eprintln!("Unknown optimization level: {}. Expected 0, 1 or 2", level);
+            exit(2);
+        }
+    }
+}
+
+///Gets the value following an option, exiting if there isn't one
+fn expect_value<'a>(option: &str, value: Option<&'a String>) -> &'a String {
+    match value {
+        Some(v) => v,
+        None => {
+            eprintln!("Expected a value after {}", option);
+            exit(2);
+        }
+    }
+}
+
+///`lint <file> [--allow CODE]...`
+/// Exits with 1 if any lints were reported
+fn lint_command(args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut config = LintConfig::default();
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--allow" | "-A" => {
+                let name = args.next().map(|s| s.as_str()).unwrap_or("");
+                match LintCode::from_name(name) {
+                    Some(code) => {
+                        config.allowed.insert(code);
+                    }
+                    None => {
+                        eprintln!("Unknown lint: {}", name);
+                        exit(2);
+                    }
+                }
+            }
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser lint <file> [--allow CODE]...");
+            exit(2);
+        }
+    };
+
+    let lints = lint(&read_source(file_path), &config);
+    for l in &lints {
+        println!("{}", format_lint(file_path, l));
+    }
+
+    if !lints.is_empty() {
+        exit(1);
+    }
+}
+
+///Formats a lint as `file:line:col: warning[L001]: message (print-empty-c)`
+fn format_lint(file_path: &str, l: &Lint) -> String {
+    format!(
+        "{}:{}: warning[{}]: {} ({})",
+        file_path,
+        l.span,
+        l.code.code(),
+        l.message,
+        l.code.name()
+    )
+}
+
+///Tokenizes source code and resolves its jumps for the commands which only look at it. Unknown and malformed commands are
+/// reported on stderr the way `lint` reports them, and left out of the program
+fn tokenize_reporting(file_path: &str, source: &str) -> Program {
+    let (mut program, errors) = tokenize_checked(source);
+    for e in errors {
+        eprintln!("{}", format_lint(file_path, &Lint::from(e)));
+    }
+    resolve_jumps(&mut program.tokens);
+    program
+}
+
+///`cfg <file> [--format dot|mermaid]`
+fn cfg_command(args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut format = CfgFormat::Dot;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" | "-f" => {
+                let name = args.next().map(|s| s.as_str()).unwrap_or("");
+                format = match CfgFormat::from_name(name) {
+                    Some(f) => f,
+                    None => {
+                        eprintln!("Unknown format: {}. Expected dot or mermaid", name);
+                        exit(2);
+                    }
+                };
+            }
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser cfg <file> [--format dot|mermaid]");
+            exit(2);
+        }
+    };
+
+    let source = read_source(file_path);
+    let program = tokenize_reporting(file_path, &source);
+    print!("{}", render(&program, &source, format));
+}
+
+///`compile <file> [-o OUT] [--no-debug] [-O LEVEL] [--numeric-model bigint|wrap64|checked64] [-v]`
+/// Writes the program as bytecode, by default next to the source with the extension `.stqc`.
+/// At level 2 constants are folded for the given numeric model, so the program should be run with the same one
+fn compile_command(args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut out_path: Option<String> = None;
+    let mut debug_info = true;
+    let mut opt_level = 0;
+    let mut model = NumericModel::default();
+    let mut verbose = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => out_path = Some(expect_value(arg, args.next()).clone()),
+            "--no-debug" => debug_info = false,
+            "-v" | "--verbose" => verbose = true,
+            "--numeric-model" => model = parse_numeric_model(expect_value(arg, args.next())),
+            "-O" | "--opt-level" => opt_level = parse_opt_level(expect_value(arg, args.next())),
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser compile <file> [-o OUT] [--no-debug] [-O LEVEL] [--numeric-model bigint|wrap64|checked64] [-v]");
+            exit(2);
+        }
+    };
+    let out_path = out_path.unwrap_or_else(|| match file_path.strip_suffix(".stq") {
+        Some(stem) => format!("{}.stqc", stem),
+        None => format!("{}.stqc", file_path),
+    });
+
+    let mut program = tokenize(&read_source(file_path));
+    let report = optimize(&mut program, opt_level, model);
+    if verbose {
+        eprint!("{}", report);
+    }
+
+    if let Err(e) = std::fs::write(&out_path, encode(&program, debug_info)) {
+        eprintln!("Cannot write {}: {}", out_path, e);
+        exit(1);
+    }
+}
+
+///`asm <file> [-o OUT]`
+/// Assembles the output of `disasm` into bytecode, with debug info if every token has a span
+fn asm_command(args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut out_path: Option<String> = None;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => out_path = Some(expect_value(arg, args.next()).clone()),
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser asm <file> [-o OUT]");
+            exit(2);
+        }
+    };
+    let out_path = out_path.unwrap_or_else(|| format!("{}.stqc", file_path));
+
+    let program = match assemble(&read_source(file_path)) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("{}:{}", file_path, e);
+            exit(1);
+        }
+    };
+    let debug_info = !program.spans.is_empty();
+
+    if let Err(e) = std::fs::write(&out_path, encode(&program, debug_info)) {
+        eprintln!("Cannot write {}: {}", out_path, e);
+        exit(1);
+    }
+}
+
+///`emit-rust <file> [-o OUT] [--numeric-model bigint|wrap64|checked64] [-O LEVEL] [-v]`, `emit-c <file> [-o OUT] [-O LEVEL] [-v]` or `emit-wat <file> [-o OUT] [-O LEVEL] [-v]`
+/// Prints the generated code unless an output file is given. C and WebAssembly always use the wrap64 numeric model
+fn emit_command(command: &str, args: &[String]) {
+    let mut file_path: Option<&String> = None;
+    let mut out_path: Option<&String> = None;
+    let mut model = NumericModel::default();
+    let mut opt_level = 0;
+    let mut verbose = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--output" => out_path = Some(expect_value(arg, args.next())),
+            "-O" | "--opt-level" => opt_level = parse_opt_level(expect_value(arg, args.next())),
+            "-v" | "--verbose" => verbose = true,
+            "--numeric-model" if command == "emit-rust" => {
+                model = parse_numeric_model(expect_value(arg, args.next()))
+            }
+            _ => file_path = Some(arg),
+        }
+    }
+
+    let file_path = match file_path {
+        Some(p) => p,
+        None => {
+            eprintln!("Usage: staq-lang-parser emit-rust <file> [-o OUT] [--numeric-model bigint|wrap64|checked64] [-O LEVEL] [-v]\n       staq-lang-parser emit-c <file> [-o OUT] [-O LEVEL] [-v]\n       staq-lang-parser emit-wat <file> [-o OUT] [-O LEVEL] [-v]");
+            exit(2);
+        }
+    };
+
+    //C and WebAssembly always use wrap64, so constants are folded for it
+    if command != "emit-rust" {
+        model = NumericModel::Wrap64;
+    }
+    let program = load_program(file_path, opt_level, model, verbose);
+    let code = match command {
+        "emit-c" => emit_c(&program),
+        "emit-wat" => emit_wat(&program),
+        _ => emit_rust(&program, model),
+    };
+    match out_path {
+        Some(path) => {
+            if let Err(e) = std::fs::write(path, code) {
+                eprintln!("Cannot write {}: {}", path, e);
+                exit(1);
+            }
+        }
+        None => print!("{}", code),
+    }
 }
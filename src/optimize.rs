@@ -1,13 +1,84 @@
-use crate::token::TokenType;
+use std::fmt::Display;
+
+use num::BigInt;
+
+use crate::{
+    analysis::{stack_ops, token_heights, transfer, StackOp},
+    token::{Program, Span, TokenType},
+    value::{NumericModel, Value},
+};
+
+///How many tokens each pass of `optimize` removed, in the order the passes ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OptimizeReport {
+    pub passes: Vec<(&'static str, usize)>,
+    ///Passes which only rewrite jumps, with how many tokens the rewritten jumps no longer run through
+    pub rewrites: Vec<(&'static str, usize)>,
+}
+
+impl OptimizeReport {
+    fn run(&mut self, name: &'static str, program: &mut Program, pass: impl FnOnce(&mut Program)) {
+        let len = program.tokens.len();
+        pass(program);
+        self.passes
+            .push((name, len.saturating_sub(program.tokens.len())));
+    }
+}
+
+impl Display for OptimizeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (pass, removed) in &self.passes {
+            writeln!(f, "{}: {} tokens removed", pass, removed)?;
+        }
+        for (pass, skipped) in &self.rewrites {
+            writeln!(f, "{}: {} tokens skipped", pass, skipped)?;
+        }
+        Ok(())
+    }
+}
 
 /// Optimizes a token stream for computational speed (not memory).
-/// Primarily, this removes unneeded tokens from the stream
-pub fn optimize(tokens: &mut Vec<TokenType>, level: usize) {
-    let opt_levels = [|| opt_0(&mut tokens), || opt_1(&mut tokens)];
-    for _ in 1..level {}
+/// Primarily, this removes unneeded tokens from the stream.
+/// Level 1 fuses common sequences into superinstructions, threads jumps and removes unreachable code and labels.
+/// Level 2 also folds constants, which is only valid for the given numeric model
+pub fn optimize(program: &mut Program, level: usize, model: NumericModel) -> OptimizeReport {
+    optimize_with(program, level, model, false)
 }
 
-fn opt_0(tokens: &mut Vec<TokenType>) {
+///Like `optimize`, but with `keep_labels` the labels aren't stripped, so they can still be used to find places in the program
+/// (ex. a traced label range or the profiler's label regions)
+pub fn optimize_with(
+    program: &mut Program,
+    level: usize,
+    model: NumericModel,
+    keep_labels: bool,
+) -> OptimizeReport {
+    let mut report = OptimizeReport::default();
+    report.run("redundant clears", program, opt_0);
+    if level >= 2 {
+        report.run("constant folding", program, |p| opt_2(p, model));
+    }
+    if level >= 1 {
+        report.run("fusion", program, opt_1);
+
+        //Threading only changes where jumps go, so it counts the tokens jumps no longer run through
+        let (threaded, skipped_clears) = thread_jumps(&mut program.tokens);
+        report.rewrites.push(("jump threading", threaded));
+        report.rewrites.push(("clear skipping", skipped_clears));
+
+        report.run("unreachable code", program, |p| {
+            remove_unreachable(p, keep_labels)
+        });
+        if !keep_labels {
+            report.run("labels", program, strip_labels);
+        }
+    }
+    report
+}
+
+fn opt_0(program: &mut Program) {
+    let tokens = &mut program.tokens;
+
     //Remove any redundant 'Clear' tokens
     for i in (1..tokens.len()).rev() {
         match tokens[i] {
@@ -15,6 +86,7 @@ fn opt_0(tokens: &mut Vec<TokenType>) {
                 //If there's a Clear token before this one, remove this one
                 if let Some(TokenType::Clear) = tokens.get(i - 1) {
                     tokens.remove(i);
+                    program.spans.remove(i);
                 }
             }
             _ => (),
@@ -23,6 +95,12 @@ fn opt_0(tokens: &mut Vec<TokenType>) {
 
     //Finally, set the index of all jump tokens (this is optimization, but must be done)
     //Also, this MUST happen after any tokens are added or removed
+    resolve_jumps(tokens);
+}
+
+///Replaces every `PreComputeJump` with a `Jump` to the index of the first matching label.
+/// Jumps to labels which don't exist are given an index of `usize::MAX`
+pub fn resolve_jumps(tokens: &mut [TokenType]) {
     for i in 0..tokens.len() {
         match &tokens[i] {
             TokenType::PreComputeJump { arg } => {
@@ -49,6 +127,810 @@ fn opt_0(tokens: &mut Vec<TokenType>) {
     }
 }
 
-fn opt_1(tokens: &mut Vec<TokenType>) {
-    todo!()
+///Recognizes a superinstruction at the start of `tokens`, returning it along with how many tokens it replaces
+fn fuse(tokens: &[TokenType]) -> Option<(TokenType, usize)> {
+    let dec_a = [
+        TokenType::Push {
+            arg: BigInt::from(1),
+        },
+        TokenType::Move { arg: [2, 1] },
+        TokenType::Subtract,
+        TokenType::Move { arg: [2, 0] },
+    ];
+    if tokens.starts_with(&dec_a) {
+        return Some((TokenType::DecA, dec_a.len()));
+    }
+
+    match tokens {
+        [TokenType::Push { arg }, TokenType::Move { arg: [2, stack] }, ..] if *stack < 3 => Some((
+            TokenType::PushTo {
+                stack: *stack,
+                value: arg.clone(),
+            },
+            2,
+        )),
+        [TokenType::Copy { arg: [stack, 2] }, TokenType::Jump { arg }, ..] if *stack < 3 => Some((
+            TokenType::JumpIfTopPositive {
+                stack: *stack,
+                target: *arg,
+            },
+            2,
+        )),
+        _ => None,
+    }
+}
+
+///Fuses common sequences of tokens into superinstructions, remapping jumps to the new indices.
+/// A sequence is left alone if a jump can land in the middle of it
+fn opt_1(program: &mut Program) {
+    let tokens = std::mem::take(&mut program.tokens);
+    let spans = std::mem::take(&mut program.spans);
+    let with_spans = spans.len() == tokens.len();
+
+    let entries = jump_entries(&tokens);
+
+    //Maps each old index to the index of the token which replaces it
+    let mut new_index: Vec<usize> = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < tokens.len() {
+        let (token, len) = match fuse(&tokens[i..]) {
+            Some((token, len)) if !entries[i + 1..i + len].contains(&true) => (token, len),
+            _ => (tokens[i].clone(), 1),
+        };
+
+        new_index.extend(std::iter::repeat_n(program.tokens.len(), len));
+        if with_spans {
+            //The span covers the whole sequence if it's on one line
+            let (first, last) = (spans[i], spans[i + len - 1]);
+            let mut span = first;
+            if last.line == first.line {
+                span.len = last.col + last.len - first.col;
+            }
+            program.spans.push(span);
+        }
+        program.tokens.push(token);
+        i += len;
+    }
+
+    remap_jumps(&mut program.tokens, &new_index);
+}
+
+///Marks every index a jump can continue at, which is one past each defined target. The result has room for `tokens.len()`
+fn jump_entries(tokens: &[TokenType]) -> Vec<bool> {
+    let mut entries = vec![false; tokens.len() + 1];
+    for token in tokens {
+        if let Some(target) = token.jump_target().filter(|t| *t < tokens.len()) {
+            entries[target + 1] = true;
+        }
+    }
+    entries
+}
+
+///Points every jump at the new index of its target. Jumps to undefined labels are left alone
+fn remap_jumps(tokens: &mut [TokenType], new_index: &[usize]) {
+    for token in tokens.iter_mut() {
+        if let TokenType::Jump { arg: target } | TokenType::JumpIfTopPositive { target, .. } = token
+        {
+            if *target < new_index.len() {
+                *target = new_index[*target];
+            }
+        }
+    }
+}
+
+///Points jumps past the tokens they would always run straight through: labels, clears and jumps which are never taken
+/// while stack C is known to be empty, and `JumpIfTopPositive` tokens which are always taken right after a jump on the same stack.
+/// Returns how many tokens were skipped, first by threading and then by skipping clears
+fn thread_jumps(tokens: &mut [TokenType]) -> (usize, usize) {
+    let heights = token_heights(tokens);
+    let (mut threaded, mut skipped_clears) = (0, 0);
+
+    for i in 0..tokens.len() {
+        let (Some(target), Some(before)) = (tokens[i].jump_target(), heights[i]) else {
+            continue;
+        };
+        if target >= tokens.len() {
+            continue;
+        }
+        //The heights once the jump has been taken
+        let c_empty = transfer(&tokens[i], before)[2].is_empty();
+        let positive = match tokens[i] {
+            TokenType::JumpIfTopPositive { stack, .. } => Some(stack),
+            _ => None,
+        };
+
+        //None of the skipped tokens change the stacks, so what was known at the jump stays true
+        let (mut next, mut jumps, mut clears) = (target + 1, 0, 0);
+        let mut steps = 0;
+        while steps <= tokens.len() {
+            steps += 1;
+            match tokens.get(next) {
+                Some(TokenType::Label { .. }) => next += 1,
+                Some(TokenType::Clear) if c_empty => {
+                    next += 1;
+                    clears += 1;
+                }
+                Some(TokenType::Jump { .. }) if c_empty => {
+                    next += 1;
+                    jumps += 1;
+                }
+                Some(TokenType::JumpIfTopPositive { stack, target })
+                    if Some(*stack) == positive && *target < tokens.len() =>
+                {
+                    next = target + 1;
+                    jumps += 1;
+                }
+                _ => break,
+            }
+        }
+        //A jump which would run around a loop of jumps forever is left alone
+        if steps > tokens.len() || next - 1 == target {
+            continue;
+        }
+
+        threaded += jumps;
+        skipped_clears += clears;
+        match &mut tokens[i] {
+            TokenType::Jump { arg: target } | TokenType::JumpIfTopPositive { target, .. } => {
+                *target = next - 1
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    (threaded, skipped_clears)
+}
+
+///Removes every token which can't be reached from the start of the program by falling through or taking a jump.
+/// Threaded jumps run past labels, so with `keep_labels` unreachable labels are kept too
+fn remove_unreachable(program: &mut Program, keep_labels: bool) {
+    let removed: Vec<bool> = token_heights(&program.tokens)
+        .iter()
+        .zip(&program.tokens)
+        .map(|(h, t)| h.is_none() && !(keep_labels && matches!(t, TokenType::Label { .. })))
+        .collect();
+    remove_tokens(program, removed);
+}
+
+///Removes every `Label`, which does nothing once jumps are resolved
+fn strip_labels(program: &mut Program) {
+    let removed: Vec<bool> = program
+        .tokens
+        .iter()
+        .map(|t| matches!(t, TokenType::Label { .. }))
+        .collect();
+    remove_tokens(program, removed);
+}
+
+///Removes the marked tokens, pointing each jump at the token before the first one left at or after where it used to continue.
+/// A jump can't continue at index 0, so the first token is kept if one would have to
+fn remove_tokens(program: &mut Program, mut removed: Vec<bool>) {
+    let len = program.tokens.len();
+    let first_kept = removed.iter().position(|r| !r).unwrap_or(len);
+    let must_keep_first = program.tokens.iter().zip(&removed).any(|(token, r)| {
+        !r && token
+            .jump_target()
+            .is_some_and(|t| t < len && t < first_kept)
+    });
+    if must_keep_first {
+        removed[0] = false;
+    }
+
+    //The number of tokens kept before each index, with room for `len`
+    let mut kept_before = Vec::with_capacity(len + 1);
+    kept_before.push(0);
+    for r in &removed {
+        kept_before.push(kept_before.last().unwrap() + !r as usize);
+    }
+
+    let with_spans = program.spans.len() == len;
+    let tokens = std::mem::take(&mut program.tokens);
+    let spans = std::mem::take(&mut program.spans);
+    for (i, mut token) in tokens.into_iter().enumerate() {
+        if removed[i] {
+            continue;
+        }
+        if let TokenType::Jump { arg: target } | TokenType::JumpIfTopPositive { target, .. } =
+            &mut token
+        {
+            if *target < len {
+                *target = kept_before[*target + 1] - 1;
+            }
+        }
+        program.tokens.push(token);
+        if with_spans {
+            program.spans.push(spans[i]);
+        }
+    }
+}
+
+///What is known about one stack while folding a basic block
+#[derive(Clone, Default)]
+struct KnownStack {
+    ///Constants pushed in this block which haven't been written out yet, on top of the real stack
+    pending: Vec<(Value, Span)>,
+    ///Whether the real stack (under the pending constants) is known to be empty
+    empty: bool,
+}
+
+///Rebuilds a token stream while delaying pushes of constants, so they can be folded or dropped
+struct Folder {
+    model: NumericModel,
+    tokens: Vec<TokenType>,
+    spans: Vec<Span>,
+    stacks: [KnownStack; 3],
+}
+
+impl Folder {
+    fn emit(&mut self, token: TokenType, span: Span) {
+        self.tokens.push(token);
+        self.spans.push(span);
+    }
+
+    ///Writes out the pending constants of stack `s`, pushing each one through C
+    fn flush(&mut self, s: usize) {
+        let pending = std::mem::take(&mut self.stacks[s].pending);
+        if !pending.is_empty() {
+            self.stacks[s].empty = false;
+        }
+        for (value, span) in pending {
+            self.emit(
+                TokenType::Push {
+                    arg: value.into_bigint(),
+                },
+                span,
+            );
+            if s != 2 {
+                self.emit(TokenType::Move { arg: [2, s as u8] }, span);
+            }
+        }
+    }
+
+    fn flush_all(&mut self) {
+        for s in 0..3 {
+            self.flush(s);
+        }
+    }
+
+    ///The value on top of stack `s`, if it's known. Popping an empty stack gives 0
+    fn peek(&self, s: usize) -> Option<Value> {
+        let stack = &self.stacks[s];
+        match stack.pending.last() {
+            Some((value, _)) => Some(value.clone()),
+            None if stack.empty => Some(Value::zero()),
+            None => None,
+        }
+    }
+
+    ///Pops a known value off stack `s`, which must have been checked with `peek`
+    fn pop(&mut self, s: usize, span: Span) -> (Value, Span) {
+        self.stacks[s]
+            .pending
+            .pop()
+            .unwrap_or((Value::zero(), span))
+    }
+
+    ///Folds a single token, or writes it out along with any pending constants it depends on
+    fn token(&mut self, token: &TokenType, span: Span) {
+        let stack = |s: u8| -> Option<usize> { (s < 3).then_some(s as usize) };
+
+        match token {
+            TokenType::Push { arg } => match self.model.constant(arg) {
+                Ok(value) => self.stacks[2].pending.push((value, span)),
+                //Constants which don't fit the model are left to fail at runtime
+                Err(_) => self.opaque(token, span),
+            },
+            TokenType::Pop { arg } if stack(*arg).is_some() => {
+                let s = *arg as usize;
+                if self.peek(s).is_some() {
+                    //The pushed value is never used
+                    self.pop(s, span);
+                } else {
+                    self.emit(token.clone(), span);
+                }
+            }
+            TokenType::Move { arg } if stack(arg[0]).is_some() && stack(arg[1]).is_some() => {
+                let (from, to) = (arg[0] as usize, arg[1] as usize);
+                if self.peek(from).is_some() {
+                    let (value, value_span) = self.pop(from, span);
+                    self.stacks[to].pending.push((value, value_span));
+                } else {
+                    self.flush(to);
+                    self.emit(token.clone(), span);
+                    self.stacks[to].empty = false;
+                }
+            }
+            TokenType::Copy { arg } if stack(arg[0]).is_some() && stack(arg[1]).is_some() => {
+                let (from, to) = (arg[0] as usize, arg[1] as usize);
+                if self.peek(from).is_some() {
+                    let (value, value_span) = self.pop(from, span);
+                    self.stacks[from].pending.push((value.clone(), value_span));
+                    self.stacks[to].pending.push((value, value_span));
+                } else {
+                    self.flush(to);
+                    self.emit(token.clone(), span);
+                    self.stacks[to].empty = false;
+                }
+            }
+            TokenType::Clear => {
+                //Constants which are cleared without being used are dropped
+                self.stacks[2].pending.clear();
+                if !self.stacks[2].empty {
+                    self.emit(TokenType::Clear, span);
+                    self.stacks[2].empty = true;
+                }
+            }
+            _ => match token.binary_op() {
+                Some(op) => {
+                    if let (Some(a), Some(b)) = (self.peek(0), self.peek(1)) {
+                        //Errors such as division by zero are left to happen at runtime
+                        if let Ok(n) = self.model.apply(op, a, b) {
+                            self.pop(0, span);
+                            self.pop(1, span);
+                            self.stacks[2].pending.push((n, span));
+                            return;
+                        }
+                    }
+                    self.flush_all();
+                    self.emit(token.clone(), span);
+                    self.stacks[2].empty = false;
+                }
+                None => self.opaque(token, span),
+            },
+        }
+    }
+
+    ///Writes out a token which can't be folded, after the pending constants of every stack it uses
+    fn opaque(&mut self, token: &TokenType, span: Span) {
+        let ops = stack_ops(token);
+        if ops.is_empty() || token.jump_target().is_some() || *token == TokenType::Exit {
+            self.flush_all();
+        }
+        for op in &ops {
+            match op {
+                StackOp::Pop(s) | StackOp::Push(s, ..) | StackOp::Set(s, _) => self.flush(*s),
+            }
+        }
+
+        self.emit(token.clone(), span);
+        for op in ops {
+            match op {
+                StackOp::Pop(_) => (),
+                StackOp::Push(s, ..) => self.stacks[s].empty = false,
+                StackOp::Set(s, n) => self.stacks[s].empty = n == 0,
+            }
+        }
+    }
+}
+
+///Folds arithmetic, bitwise and comparison tokens on constants, and drops constants which are popped or cleared without being used.
+/// This only happens within basic blocks, which end at labels, jumps and anywhere a jump can land, so jump targets are always kept
+fn opt_2(program: &mut Program, model: NumericModel) {
+    let tokens = std::mem::take(&mut program.tokens);
+    let spans = std::mem::take(&mut program.spans);
+    let with_spans = spans.len() == tokens.len();
+
+    let entries = jump_entries(&tokens);
+    let mut targets = vec![false; tokens.len()];
+    for token in &tokens {
+        if let Some(target) = token.jump_target().filter(|t| *t < tokens.len()) {
+            targets[target] = true;
+        }
+    }
+    //Labels and jump targets are kept in blocks of their own, and jumps and exits end their blocks
+    let block_before =
+        |i: usize| entries[i] || targets[i] || matches!(tokens[i], TokenType::Label { .. });
+    let block_after = |i: usize| {
+        targets[i]
+            || matches!(
+                tokens[i],
+                TokenType::Label { .. } | TokenType::Jump { .. } | TokenType::Exit
+            )
+    };
+
+    let mut folder = Folder {
+        model,
+        tokens: Vec::with_capacity(tokens.len()),
+        spans: Vec::with_capacity(tokens.len()),
+        //Every stack starts out empty
+        stacks: std::array::from_fn(|_| KnownStack {
+            pending: Vec::new(),
+            empty: true,
+        }),
+    };
+    let mut new_index: Vec<usize> = Vec::with_capacity(tokens.len());
+    for (i, token) in tokens.iter().enumerate() {
+        if i > 0 && (block_before(i) || block_after(i - 1)) {
+            folder.flush_all();
+            folder.stacks = Default::default();
+        }
+
+        new_index.push(folder.tokens.len());
+        let span = spans.get(i).copied().unwrap_or_default();
+        if targets[i] {
+            //A jump target must stay a single token, so that execution still continues after it
+            folder.emit(token.clone(), span);
+        } else {
+            folder.token(token, span);
+        }
+    }
+    //Constants still pending at the end of the program are never used
+
+    program.tokens = folder.tokens;
+    if with_spans {
+        program.spans = folder.spans;
+    }
+    remap_jumps(&mut program.tokens, &new_index);
+}
+
+#[cfg(test)]
+mod tests {
+    use num::BigInt;
+
+    use super::{opt_2, optimize, optimize_with};
+    use crate::{
+        emit::tests::{examples, interpret_program},
+        interpreter::tokenize,
+        token::{Program, TokenType},
+        value::NumericModel,
+    };
+
+    fn compile(source: &str, level: usize, model: NumericModel) -> Program {
+        let mut program = tokenize(source);
+        optimize(&mut program, level, model);
+        program
+    }
+
+    ///A small xorshift generator, so the property test is reproducible without extra dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    ///Generates a program made mostly of the fused idioms and arithmetic on constants. Jumps only go forward, so it always finishes
+    fn random_program(rng: &mut Rng) -> String {
+        let values = [
+            "0",
+            "1",
+            "-1",
+            "7",
+            "9223372036854775807",
+            "-9223372036854775808",
+        ];
+        let ops = [
+            "+", "-", "*", "/", "%", "==", "<", "<=", ">", ">=", "&", "|", "^", ">>", "<<",
+        ];
+        let mut source = String::new();
+        let mut next_label = 0;
+        let mut last_label = 0;
+        for _ in 0..40 {
+            let value = values[rng.below(values.len() as u64) as usize];
+            let stack = ["A", "B", "C"][rng.below(3) as usize];
+            let line = match rng.below(13) {
+                0 => "push:1 move:C:B - move:C:A".to_string(),
+                1 | 2 => format!("push:{} move:C:{}", value, stack),
+                3 | 4 => {
+                    let label = next_label + rng.below(3);
+                    last_label = last_label.max(label);
+                    format!("copy:{}:C jump:l{}", stack, label)
+                }
+                5 => {
+                    next_label += 1;
+                    format!("label:l{}", next_label - 1)
+                }
+                6 => format!("copy:{}:C printnum push:32 print", stack),
+                7 => format!("push:{} move:C:B copy:A:C + move:C:A", value),
+                8 | 9 => {
+                    //Shift amounts are kept small, since bigint shifts by huge amounts would run out of memory
+                    let op = ops[rng.below(ops.len() as u64) as usize];
+                    let amount = if matches!(op, "<<" | ">>") {
+                        (rng.below(70) as i64 - 3).to_string()
+                    } else {
+                        values[rng.below(values.len() as u64) as usize].to_string()
+                    };
+                    format!(
+                        "push:{} move:C:A push:{} move:C:B {} move:C:{}",
+                        value, amount, op, stack
+                    )
+                }
+                10 => format!("push:{} push:{}", value, value),
+                //Exits are rare, so most of the program still runs
+                11 if rng.below(4) == 0 => "exit".to_string(),
+                11 => format!("copy:{}:C jump:nowhere", stack),
+                _ => format!("push:{} pop:{}", value, stack),
+            };
+            source.push_str(&line);
+            source.push(if rng.below(2) == 0 { '\n' } else { ' ' });
+        }
+        for label in next_label..=last_label {
+            source.push_str(&format!("\nlabel:l{}", label));
+        }
+        source.push_str("\nmove:A:C printnum move:B:C printnum");
+        source
+    }
+
+    #[test]
+    fn fuses_idioms() {
+        let source = include_str!("../examples/fibonnaci.stq");
+        let unfused = compile(source, 0, NumericModel::default());
+        let fused = compile(source, 1, NumericModel::default());
+        assert_eq!(fused.tokens.len(), fused.spans.len());
+        assert!(fused.tokens.len() < unfused.tokens.len());
+
+        assert!(fused.tokens.contains(&TokenType::DecA));
+        assert!(fused.tokens.contains(&TokenType::PushTo {
+            stack: 0,
+            value: BigInt::from(500),
+        }));
+        //The loop jumps past the stripped `label:start` and the clear ending its line, straight to `move:B:C`
+        let target = fused
+            .tokens
+            .iter()
+            .find_map(|t| match t {
+                TokenType::JumpIfTopPositive { stack: 0, target } => Some(*target),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(fused.tokens[target + 1], TokenType::Move { arg: [1, 2] });
+        assert!(!fused
+            .tokens
+            .iter()
+            .any(|t| matches!(t, TokenType::Label { .. })));
+
+        //`push:1 move:C:B - move:C:A` spans the whole line
+        let dec = fused
+            .tokens
+            .iter()
+            .position(|t| *t == TokenType::DecA)
+            .unwrap();
+        assert_eq!(fused.spans[dec].col, 1);
+        assert_eq!(fused.spans[dec].len, "push:1 move:C:B - move:C:A".len());
+    }
+
+    #[test]
+    fn keeps_sequences_jumped_into() {
+        //A jump to #0 continues at #1, in the middle of `push:5 move:C:A`
+        let program = Program {
+            tokens: vec![
+                TokenType::Push {
+                    arg: BigInt::from(5),
+                },
+                TokenType::Move { arg: [2, 0] },
+                TokenType::Copy { arg: [0, 2] },
+                TokenType::Jump { arg: 0 },
+                TokenType::Copy { arg: [0, 2] },
+                TokenType::Jump { arg: 3 },
+            ],
+            spans: Vec::new(),
+        };
+        let mut fused = program.clone();
+        optimize(&mut fused, 1, NumericModel::default());
+        assert_eq!(
+            fused.tokens,
+            vec![
+                TokenType::Push {
+                    arg: BigInt::from(5),
+                },
+                TokenType::Move { arg: [2, 0] },
+                //The jump to #3 (the end of the fused sequence) still continues after it
+                TokenType::JumpIfTopPositive {
+                    stack: 0,
+                    target: 0,
+                },
+                TokenType::JumpIfTopPositive {
+                    stack: 0,
+                    target: 2,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn folds_constants() {
+        let source = "push:9223372036854775807 move:C:A push:1 move:C:B + printnum";
+        let push = |n: BigInt| TokenType::Push { arg: n };
+        let max = BigInt::from(i64::MAX);
+
+        let folded = compile(source, 2, NumericModel::BigInt);
+        assert_eq!(folded.tokens.len(), folded.spans.len());
+        assert_eq!(folded.tokens, vec![push(&max + 1), TokenType::PrintNum]);
+        let folded = compile(source, 2, NumericModel::Wrap64);
+        assert_eq!(folded.tokens[0], push(BigInt::from(i64::MIN)));
+
+        //Operations which fail at runtime are left for the interpreter to report
+        for (source, model) in [
+            (source, NumericModel::Checked64),
+            (
+                "push:1 move:C:A push:0 move:C:B / printnum",
+                NumericModel::BigInt,
+            ),
+        ] {
+            let folded = compile(source, 2, model);
+            assert!(folded.tokens.iter().any(|t| t.binary_op().is_some()));
+        }
+    }
+
+    #[test]
+    fn drops_dead_pushes() {
+        let folded = compile("push:5 push:6\npush:7 printnum", 2, NumericModel::default());
+        assert_eq!(
+            folded.tokens,
+            vec![
+                TokenType::Push {
+                    arg: BigInt::from(7),
+                },
+                TokenType::PrintNum,
+            ]
+        );
+    }
+
+    #[test]
+    fn never_folds_across_labels() {
+        //Only folding runs, since later passes strip the label
+        let mut folded = compile(
+            "push:1 move:C:A\nlabel:x\ncopy:A:C printnum",
+            0,
+            NumericModel::default(),
+        );
+        opt_2(&mut folded, NumericModel::default());
+        let label = folded
+            .tokens
+            .iter()
+            .position(|t| matches!(t, TokenType::Label { .. }))
+            .unwrap();
+        assert_eq!(
+            folded.tokens[..label],
+            [
+                TokenType::Push {
+                    arg: BigInt::from(1),
+                },
+                TokenType::Move { arg: [2, 0] },
+            ]
+        );
+        //The label's line still ends with a clear, since nothing is known after a label
+        assert_eq!(folded.tokens[label + 1], TokenType::Clear);
+        assert_eq!(folded.tokens[label + 2], TokenType::Copy { arg: [0, 2] });
+    }
+
+    #[test]
+    fn threads_jumps() {
+        let source = "push:1 move:C:A\ncopy:A:C jump:b\nexit\nlabel:b\ncopy:A:C jump:c\nexit\nlabel:c\npush:7 printnum";
+        let program = compile(source, 1, NumericModel::default());
+        assert_eq!(
+            interpret_program(program.clone(), "", NumericModel::default()),
+            "7"
+        );
+
+        //The first jump goes straight to `push:7`, which leaves the second one unreachable
+        let jumps: Vec<usize> = program
+            .tokens
+            .iter()
+            .filter_map(|t| t.jump_target())
+            .collect();
+        assert_eq!(jumps.len(), 1);
+        assert_eq!(
+            program.tokens[jumps[0] + 1],
+            TokenType::Push {
+                arg: BigInt::from(7),
+            }
+        );
+        assert!(!program
+            .tokens
+            .iter()
+            .any(|t| matches!(t, TokenType::Label { .. })));
+
+        //Labels can be kept for tracing and profiling without changing how the program runs.
+        //Threading only rewrites jumps, so it isn't reported as removing tokens
+        let mut kept = tokenize(source);
+        let report = optimize_with(&mut kept, 1, NumericModel::default(), true);
+        assert_eq!(
+            interpret_program(kept.clone(), "", NumericModel::default()),
+            "7"
+        );
+        assert!(kept
+            .tokens
+            .iter()
+            .any(|t| matches!(t, TokenType::Label { arg } if arg == "c")));
+        assert!(report
+            .passes
+            .iter()
+            .all(|(pass, _)| *pass != "jump threading"));
+        assert!(report
+            .to_string()
+            .contains("jump threading: 1 tokens skipped"));
+    }
+
+    #[test]
+    fn removes_unreachable_code() {
+        let mut program =
+            tokenize("push:1 printnum exit\npush:2 printnum\nlabel:x\npush:3 printnum");
+        let report = optimize(&mut program, 1, NumericModel::default());
+        assert_eq!(
+            program.tokens,
+            vec![
+                TokenType::Push {
+                    arg: BigInt::from(1),
+                },
+                TokenType::PrintNum,
+                TokenType::Exit,
+            ]
+        );
+        assert_eq!(program.spans.len(), 3);
+        assert!(report.passes.contains(&("unreachable code", 9)));
+        assert!(report
+            .to_string()
+            .contains("unreachable code: 9 tokens removed"));
+    }
+
+    #[test]
+    fn keeps_first_label_when_jumped_to() {
+        //Without the label, the jump would have to continue at index 0
+        let mut program = Program {
+            tokens: vec![
+                TokenType::Label { arg: "x".into() },
+                TokenType::Push {
+                    arg: BigInt::from(1),
+                },
+                TokenType::Jump { arg: 0 },
+            ],
+            spans: Vec::new(),
+        };
+        let unchanged = program.clone();
+        optimize(&mut program, 1, NumericModel::default());
+        assert_eq!(program, unchanged);
+    }
+
+    #[test]
+    fn never_takes_undefined_jumps() {
+        for level in 0..=2 {
+            let program = compile(
+                "push:1 jump:nowhere\npush:5 printnum",
+                level,
+                NumericModel::default(),
+            );
+            assert_eq!(interpret_program(program, "", NumericModel::default()), "5");
+        }
+    }
+
+    #[test]
+    fn optimized_programs_match() {
+        let mut sources: Vec<(String, &'static str)> = examples()
+            .into_iter()
+            .map(|(_, source, input)| (source, input))
+            .collect();
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+        for _ in 0..200 {
+            sources.push((random_program(&mut rng), ""));
+        }
+
+        for (source, input) in sources {
+            for model in [
+                NumericModel::BigInt,
+                NumericModel::Wrap64,
+                NumericModel::Checked64,
+            ] {
+                let expected = interpret_program(compile(&source, 0, model), input, model);
+                for level in 1..=2 {
+                    assert_eq!(
+                        interpret_program(compile(&source, level, model), input, model),
+                        expected,
+                        "-O {} {:?} {}",
+                        level,
+                        model,
+                        source
+                    );
+                }
+            }
+        }
+    }
 }
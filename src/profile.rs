@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    io::{self, Write},
+    time::Duration,
+};
+
+use crate::token::{Program, TokenType};
+
+///What to output when profiling. A sorted report is always printed when the program finishes
+pub struct ProfileConfig {
+    ///The number of rows shown in each table of the report
+    pub top: usize,
+    ///Where to write collapsed stacks (the input format of flamegraph tools), if anywhere
+    pub collapsed: Option<Box<dyn Write>>,
+}
+
+impl Default for ProfileConfig {
+    fn default() -> ProfileConfig {
+        ProfileConfig {
+            top: 10,
+            collapsed: None,
+        }
+    }
+}
+
+///Execution counts and accumulated time, per token index
+pub struct Profiler {
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+}
+
+///A row of the report: the totals for a single token, line or label region
+struct Row {
+    name: String,
+    count: u64,
+    time: Duration,
+}
+
+impl Profiler {
+    pub fn new(token_count: usize) -> Profiler {
+        Profiler {
+            counts: vec![0; token_count],
+            times: vec![Duration::ZERO; token_count],
+        }
+    }
+
+    pub fn record(&mut self, index: usize, time: Duration) {
+        self.counts[index] += 1;
+        self.times[index] += time;
+    }
+
+    ///Names the label region of each token: the name of the last label at or before it, or `<start>` before the first label
+    fn label_regions(program: &Program) -> Vec<String> {
+        let mut region = "<start>".to_string();
+        program
+            .tokens
+            .iter()
+            .map(|t| {
+                if let TokenType::Label { arg } = t {
+                    region = arg.clone();
+                }
+                region.clone()
+            })
+            .collect()
+    }
+
+    ///Groups the per token totals by `key`, sorted from most to least time
+    fn aggregate(&self, key: impl Fn(usize) -> String) -> Vec<Row> {
+        let mut groups: HashMap<String, (u64, Duration)> = HashMap::new();
+        for i in 0..self.counts.len() {
+            if self.counts[i] == 0 {
+                continue;
+            }
+            let group = groups.entry(key(i)).or_default();
+            group.0 += self.counts[i];
+            group.1 += self.times[i];
+        }
+
+        let mut rows: Vec<Row> = groups
+            .into_iter()
+            .map(|(name, (count, time))| Row { name, count, time })
+            .collect();
+        rows.sort_by(|a, b| {
+            b.time
+                .cmp(&a.time)
+                .then(b.count.cmp(&a.count))
+                .then(a.name.cmp(&b.name))
+        });
+        rows
+    }
+
+    ///Builds a report of the hottest tokens, lines and label regions. `program` must be the program which was profiled
+    pub fn report(&self, program: &Program, top: usize) -> String {
+        let total_count: u64 = self.counts.iter().sum();
+        let total_time: Duration = self.times.iter().sum();
+        let regions = Self::label_regions(program);
+
+        let line_of = |i: usize| match program.spans.get(i) {
+            Some(span) => format!("line {}", span.line),
+            None => "line ?".to_string(),
+        };
+
+        let tables = [
+            (
+                "Hot tokens",
+                self.aggregate(|i| format!("#{} {} ({})", i, program.tokens[i], line_of(i))),
+            ),
+            ("Hot lines", self.aggregate(line_of)),
+            (
+                "Label regions",
+                self.aggregate(|i| format!("label {}", regions[i])),
+            ),
+        ];
+
+        let mut s = String::new();
+        writeln!(
+            s,
+            "Profile: {} tokens executed in {}μs",
+            total_count,
+            total_time.as_micros()
+        )
+        .unwrap();
+
+        for (title, rows) in tables {
+            writeln!(s, "\n{}:", title).unwrap();
+            writeln!(
+                s,
+                "{:>12} {:>12} {:>7}  name",
+                "count", "time (μs)", "time %"
+            )
+            .unwrap();
+            for row in rows.iter().take(top) {
+                let percent = if total_time.is_zero() {
+                    0.0
+                } else {
+                    row.time.as_secs_f64() / total_time.as_secs_f64() * 100.0
+                };
+                writeln!(
+                    s,
+                    "{:>12} {:>12} {:>6.2}%  {}",
+                    row.count,
+                    row.time.as_micros(),
+                    percent,
+                    row.name
+                )
+                .unwrap();
+            }
+        }
+
+        s
+    }
+
+    ///Writes the profile as collapsed stacks, one `main;<label region>;line <N> <nanoseconds>` line per source line.
+    /// StaqLang has no subroutines, so label regions stand in for the call stack
+    pub fn write_collapsed(&self, program: &Program, writer: &mut dyn Write) -> io::Result<()> {
+        let regions = Self::label_regions(program);
+        let mut rows = self.aggregate(|i| {
+            let line = match program.spans.get(i) {
+                Some(span) => span.line.to_string(),
+                None => "?".to_string(),
+            };
+            format!("main;{};line {}", regions[i], line)
+        });
+        rows.sort_by(|a, b| a.name.cmp(&b.name));
+
+        for row in rows {
+            writeln!(writer, "{} {}", row.name, row.time.as_nanos())?;
+        }
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Profiler;
+    use crate::{interpreter::tokenize, optimize::resolve_jumps};
+
+    #[test]
+    fn report_and_collapsed() {
+        let mut program = tokenize("push:1\nlabel:loop\npush:2 push:3");
+        resolve_jumps(&mut program.tokens);
+
+        let mut profiler = Profiler::new(program.tokens.len());
+        for (i, micros) in [(0, 5), (4, 30), (4, 30), (5, 10)] {
+            profiler.record(i, Duration::from_micros(micros));
+        }
+
+        let report = profiler.report(&program, 10);
+        assert!(report.starts_with("Profile: 4 tokens executed in 75μs"));
+        let hot_token = report.lines().nth(4).unwrap();
+        assert!(hot_token.contains("#4 Push { arg: 2 } (line 3)"));
+        assert!(report.contains("label loop"));
+
+        let mut collapsed = Vec::new();
+        profiler.write_collapsed(&program, &mut collapsed).unwrap();
+        assert_eq!(
+            String::from_utf8(collapsed).unwrap(),
+            "main;<start>;line 1 5000\nmain;loop;line 3 70000\n"
+        );
+    }
+}
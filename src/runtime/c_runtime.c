@@ -0,0 +1,409 @@
+/* The three stacks, I/O and wrap64 arithmetic. Files are relative to the working directory, like the interpreter's files are relative to the program.
+   The functions are inline so that unused ones don't cause warnings */
+
+/* Directories need POSIX, which strict C99 hides */
+#define _POSIX_C_SOURCE 200809L
+
+#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <inttypes.h>
+#include <sys/stat.h>
+#include <unistd.h>
+#include <dirent.h>
+
+#define ROOT "./"
+
+typedef struct {
+    int64_t *data;
+    size_t len;
+    size_t cap;
+} Stack;
+
+static Stack stacks[3];
+/* The open file streams, indexed by handle. Handles 0 and 1 are what the handle-less commands read from and write to */
+static FILE **streams;
+static size_t stream_count;
+
+/* Closes a stream once no handle holds it, since a read-write stream can be both handle 0 and handle 1 */
+static inline void release_stream(FILE *f) {
+    size_t i;
+    if (!f) return;
+    for (i = 0; i < stream_count; i++) {
+        if (streams[i] == f) return;
+    }
+    fclose(f);
+}
+
+static inline void finish(void) {
+    size_t i;
+    fflush(stdout);
+    for (i = 0; i < stream_count; i++) {
+        FILE *f = streams[i];
+        streams[i] = NULL;
+        release_stream(f);
+    }
+}
+
+static inline void fail(size_t index, const char *message) {
+    finish();
+    fprintf(stderr, "runtime error at index %zu: %s\n", index, message);
+    exit(1);
+}
+
+static inline void push(int s, int64_t n) {
+    Stack *st = &stacks[s];
+    if (st->len == st->cap) {
+        st->cap = st->cap ? st->cap * 2 : 64;
+        st->data = realloc(st->data, st->cap * sizeof(int64_t));
+        if (!st->data) {
+            fprintf(stderr, "out of memory\n");
+            exit(1);
+        }
+    }
+    st->data[st->len++] = n;
+}
+
+static inline int64_t pop(int s) {
+    Stack *st = &stacks[s];
+    return st->len ? st->data[--st->len] : 0;
+}
+
+static inline void copy(int from, int to) {
+    int64_t n = pop(from);
+    push(from, n);
+    push(to, n);
+}
+
+static inline void push_status(int ok) {
+    push(2, ok ? 1 : -1);
+}
+
+/* Bytes are written as characters, so values of 128 and up become two byte UTF-8 sequences like they do in the interpreter */
+static inline size_t put_char(char *out, int64_t c) {
+    if (c < 0x80) {
+        out[0] = (char)c;
+        return 1;
+    }
+    out[0] = (char)(0xC0 | (c >> 6));
+    out[1] = (char)(0x80 | (c & 0x3F));
+    return 2;
+}
+
+/* Pops every value of stack C from the top down, or stops after a 0 if until_zero is set. The result must be freed.
+   Values are written as characters when printing, but a path takes each one as a single byte like the interpreter does */
+static inline char *pop_string(size_t index, const char *prefix, size_t *len, int until_zero, int as_chars) {
+    size_t count = stacks[2].len;
+    size_t prefix_len = strlen(prefix);
+    char *s = malloc(prefix_len + count * 2 + 1);
+    memcpy(s, prefix, prefix_len);
+    *len = prefix_len;
+    while (stacks[2].len) {
+        int64_t c = pop(2);
+        if (c < 0 || c > 255) {
+            free(s);
+            fail(index, "invalid char value");
+        }
+        if (until_zero && c == 0) break;
+        if (as_chars) {
+            *len += put_char(s + *len, c);
+        } else {
+            s[(*len)++] = (char)c;
+        }
+    }
+    s[*len] = '\0';
+    return s;
+}
+
+static inline void print(size_t index) {
+    size_t len;
+    char *s = pop_string(index, "", &len, 0, 1);
+    fwrite(s, 1, len, stdout);
+    free(s);
+}
+
+static inline void print_num(void) {
+    while (stacks[2].len) printf("%" PRId64, pop(2));
+}
+
+static inline void get_next_in(void) {
+    int c;
+    fflush(stdout);
+    c = getchar();
+    if (c != EOF) push(2, c);
+}
+
+/* Uses the given path, or reads it from stack C if there isn't one. The result must be freed */
+static inline char *path(size_t index, const char *arg, int until_zero) {
+    size_t len;
+    if (arg) {
+        char *s = malloc(strlen(ROOT) + strlen(arg) + 1);
+        strcpy(s, ROOT);
+        strcat(s, arg);
+        return s;
+    }
+    return pop_string(index, ROOT, &len, until_zero, 0);
+}
+
+static inline void create_file(size_t index, const char *arg) {
+    char *p = path(index, arg, 0);
+    FILE *f = fopen(p, "wb");
+    free(p);
+    if (f) fclose(f);
+    push_status(f != NULL);
+}
+
+/* The stream with a handle, or NULL if it isn't open */
+static inline FILE *stream_at(int64_t handle) {
+    return handle >= 0 && (uint64_t)handle < stream_count ? streams[handle] : NULL;
+}
+
+/* Stores a newly opened stream and pushes the status, after its handle if it gets a new one.
+   Otherwise it replaces handles `first` to `last`, which share it so that reads and writes share one position */
+static inline void store_stream(FILE *f, int new_handle, size_t first, size_t last) {
+    size_t h;
+    if (!f) {
+        push_status(0);
+        return;
+    }
+    if (new_handle) {
+        /* Like file descriptors, the lowest free handle is used */
+        for (h = 2; h < stream_count && streams[h]; h++) {
+        }
+        if (h == stream_count) {
+            streams = realloc(streams, (stream_count + 1) * sizeof(FILE *));
+            if (!streams) {
+                fprintf(stderr, "out of memory\n");
+                exit(1);
+            }
+            streams[stream_count++] = NULL;
+        }
+        streams[h] = f;
+        push(2, (int64_t)h);
+    } else {
+        for (h = first; h <= last; h++) {
+            FILE *old = streams[h];
+            streams[h] = f;
+            release_stream(old);
+        }
+    }
+    push_status(1);
+}
+
+static inline void create_file_stream(size_t index, const char *arg, int new_handle) {
+    char *p = path(index, arg, 0);
+    FILE *f = fopen(p, "wb");
+    free(p);
+    store_stream(f, new_handle, 1, 1);
+}
+
+static inline void open_file_stream(size_t index, const char *arg, int new_handle) {
+    char *p = path(index, arg, 0);
+    FILE *f = fopen(p, "rb");
+    free(p);
+    store_stream(f, new_handle, 0, 0);
+}
+
+/* "ab" would create a missing file, which the other streams don't do */
+static inline void append_file_stream(size_t index, const char *arg, int new_handle) {
+    char *p = path(index, arg, 0);
+    struct stat st;
+    FILE *f = stat(p, &st) == 0 && S_ISREG(st.st_mode) ? fopen(p, "ab") : NULL;
+    free(p);
+    store_stream(f, new_handle, 1, 1);
+}
+
+static inline void read_write_file_stream(size_t index, const char *arg, int new_handle) {
+    char *p = path(index, arg, 0);
+    FILE *f = fopen(p, "r+b");
+    free(p);
+    store_stream(f, new_handle, 0, 1);
+}
+
+/* The handle is popped (as the argument) before the offset */
+static inline void seek_file_stream(FILE *f, int whence) {
+    int64_t offset = pop(2);
+    int ok = f && (whence != SEEK_SET || offset >= 0) && fseek(f, (long)offset, whence) == 0;
+    push_status(ok);
+}
+
+static inline void tell_file_stream(FILE *f) {
+    long pos = f ? ftell(f) : -1;
+    if (pos < 0) {
+        push_status(0);
+        return;
+    }
+    push(2, pos);
+    push_status(1);
+}
+
+static inline void close_file_stream(int64_t handle) {
+    FILE *f = stream_at(handle);
+    if (f) {
+        streams[handle] = NULL;
+        release_stream(f);
+    }
+    push_status(f != NULL);
+}
+
+static inline void read_file_stream(FILE *f) {
+    int c = f ? fgetc(f) : EOF;
+    if (!f || (c == EOF && ferror(f))) {
+        if (f) clearerr(f);
+        push_status(0);
+        return;
+    }
+    push(2, c == EOF ? -1 : c);
+    push_status(1);
+}
+
+static inline void write_file_stream(size_t index, FILE *f) {
+    size_t count = stacks[2].len;
+    unsigned char *bytes = malloc(count + 1);
+    size_t i;
+    int ok;
+    for (i = 0; i < count; i++) {
+        int64_t c = pop(2);
+        if (c < 0 || c > 255) {
+            free(bytes);
+            fail(index, "invalid char value");
+        }
+        bytes[i] = (unsigned char)c;
+    }
+    /* A stream which was just read from has to be positioned before it's written to */
+    if (f) fseek(f, 0, SEEK_CUR);
+    /* Flushed straight away, so the file can be read back by another stream */
+    ok = f && fwrite(bytes, 1, count, f) == count && fflush(f) == 0;
+    free(bytes);
+    push_status(ok);
+}
+
+static inline void make_dir(size_t index, const char *arg) {
+    char *p = path(index, arg, 0);
+    int ok = mkdir(p, 0777) == 0;
+    free(p);
+    push_status(ok);
+}
+
+static inline void remove_dir(size_t index, const char *arg) {
+    char *p = path(index, arg, 0);
+    int ok = rmdir(p) == 0;
+    free(p);
+    push_status(ok);
+}
+
+static inline void rename_path(size_t index, const char *from, const char *to) {
+    /* When both paths come from stack C, the first one ends at a 0 */
+    char *f = path(index, from, to == NULL);
+    char *t = path(index, to, 0);
+    int ok = rename(f, t) == 0;
+    free(f);
+    free(t);
+    push_status(ok);
+}
+
+/* unlink rather than remove, which would also remove empty directories */
+static inline void remove_file(size_t index, const char *arg) {
+    char *p = path(index, arg, 0);
+    int ok = unlink(p) == 0;
+    free(p);
+    push_status(ok);
+}
+
+static inline void exists(size_t index, const char *arg) {
+    struct stat st;
+    char *p = path(index, arg, 0);
+    int ok = stat(p, &st) == 0;
+    free(p);
+    push_status(ok);
+}
+
+/* Directories don't have a size, so they count as a failure */
+static inline void file_size(size_t index, const char *arg) {
+    struct stat st;
+    char *p = path(index, arg, 0);
+    int ok = stat(p, &st) == 0 && !S_ISDIR(st.st_mode);
+    free(p);
+    if (ok) push(2, (int64_t)st.st_size);
+    push_status(ok);
+}
+
+static int compare_names(const void *a, const void *b) {
+    return strcmp(*(char *const *)a, *(char *const *)b);
+}
+
+static inline void list_dir(size_t index, const char *arg) {
+    char *p = path(index, arg, 0);
+    DIR *dir = opendir(p);
+    struct dirent *entry;
+    char **names = NULL;
+    size_t count = 0, cap = 0, n;
+    free(p);
+    if (!dir) {
+        push_status(0);
+        return;
+    }
+    while ((entry = readdir(dir))) {
+        if (!strcmp(entry->d_name, ".") || !strcmp(entry->d_name, "..")) continue;
+        if (count == cap) {
+            cap = cap ? cap * 2 : 16;
+            names = realloc(names, cap * sizeof(char *));
+        }
+        names[count++] = strdup(entry->d_name);
+    }
+    closedir(dir);
+    qsort(names, count, sizeof(char *), compare_names);
+
+    /* The first name ends up nearest the top, with its first byte uppermost. Like paths, names are pushed as bytes */
+    for (n = count; n-- > 0;) {
+        size_t len = strlen(names[n]);
+        push(2, 0);
+        while (len) push(2, (unsigned char)names[n][--len]);
+        free(names[n]);
+    }
+    free(names);
+    push(2, (int64_t)count);
+    push_status(1);
+}
+
+/* wrap64 arithmetic. Signed overflow is undefined in C, so wrapping operations go through uint64_t */
+
+static inline int64_t op_add(int64_t a, int64_t b) { return (int64_t)((uint64_t)a + (uint64_t)b); }
+static inline int64_t op_sub(int64_t a, int64_t b) { return (int64_t)((uint64_t)a - (uint64_t)b); }
+static inline int64_t op_mul(int64_t a, int64_t b) { return (int64_t)((uint64_t)a * (uint64_t)b); }
+
+static inline int64_t op_div(size_t index, int64_t a, int64_t b) {
+    if (b == 0) fail(index, "division by zero");
+    if (a == INT64_MIN && b == -1) return INT64_MIN;
+    return a / b;
+}
+
+static inline int64_t op_rem(size_t index, int64_t a, int64_t b) {
+    if (b == 0) fail(index, "division by zero");
+    if (b == -1) return 0;
+    return a % b;
+}
+
+static inline int64_t op_shl(int64_t a, int64_t b) { return (int64_t)((uint64_t)a << (b & 63)); }
+
+/* Right shifts of negative numbers are implementation defined, so the arithmetic shift is spelled out */
+static inline int64_t op_shr(int64_t a, int64_t b) {
+    int n = (int)(b & 63);
+    return a < 0 ? ~(~a >> n) : a >> n;
+}
+
+static void run(void);
+
+int main(void) {
+    /* Like in the interpreter, handles 0 and 1 aren't open until the program opens them */
+    stream_count = 2;
+    streams = calloc(stream_count, sizeof(FILE *));
+    if (!streams) {
+        fprintf(stderr, "out of memory\n");
+        return 1;
+    }
+    run();
+    finish();
+    return 0;
+}
@@ -0,0 +1,335 @@
+//Numbers for the bigint model: a sign and a little endian base 2^32 magnitude.
+//Zero is never negative, and the magnitude never ends with a zero limb
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Num {
+    neg: bool,
+    mag: Vec<u32>,
+}
+
+fn mag_trim(mut m: Vec<u32>) -> Vec<u32> {
+    while m.last() == Some(&0) {
+        m.pop();
+    }
+    m
+}
+
+fn mag_cmp(a: &[u32], b: &[u32]) -> std::cmp::Ordering {
+    a.len()
+        .cmp(&b.len())
+        .then_with(|| a.iter().rev().cmp(b.iter().rev()))
+}
+
+fn mag_add(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let (a, b) = if a.len() >= b.len() { (a, b) } else { (b, a) };
+    let mut out = Vec::with_capacity(a.len() + 1);
+    let mut carry = 0u64;
+    for i in 0..a.len() {
+        let sum = a[i] as u64 + *b.get(i).unwrap_or(&0) as u64 + carry;
+        out.push(sum as u32);
+        carry = sum >> 32;
+    }
+    if carry != 0 {
+        out.push(carry as u32);
+    }
+    out
+}
+
+//Requires a >= b
+fn mag_sub(a: &[u32], b: &[u32]) -> Vec<u32> {
+    let mut out = Vec::with_capacity(a.len());
+    let mut borrow = 0i64;
+    for i in 0..a.len() {
+        let mut diff = a[i] as i64 - *b.get(i).unwrap_or(&0) as i64 - borrow;
+        borrow = 0;
+        if diff < 0 {
+            diff += 1 << 32;
+            borrow = 1;
+        }
+        out.push(diff as u32);
+    }
+    mag_trim(out)
+}
+
+fn mag_mul(a: &[u32], b: &[u32]) -> Vec<u32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let mut out = vec![0u32; a.len() + b.len()];
+    for i in 0..a.len() {
+        let mut carry = 0u64;
+        for j in 0..b.len() {
+            let n = a[i] as u64 * b[j] as u64 + out[i + j] as u64 + carry;
+            out[i + j] = n as u32;
+            carry = n >> 32;
+        }
+        out[i + b.len()] = carry as u32;
+    }
+    mag_trim(out)
+}
+
+fn mag_shl(a: &[u32], n: usize) -> Vec<u32> {
+    if a.is_empty() {
+        return Vec::new();
+    }
+    let (limbs, bits) = (n / 32, n % 32);
+    let mut out = vec![0u32; limbs];
+    let mut carry = 0u32;
+    for &x in a {
+        if bits == 0 {
+            out.push(x);
+        } else {
+            out.push((x << bits) | carry);
+            carry = x >> (32 - bits);
+        }
+    }
+    out.push(carry);
+    mag_trim(out)
+}
+
+fn mag_shr(a: &[u32], n: usize) -> Vec<u32> {
+    let (limbs, bits) = (n / 32, n % 32);
+    if limbs >= a.len() {
+        return Vec::new();
+    }
+    let a = &a[limbs..];
+    let mut out = Vec::with_capacity(a.len());
+    for i in 0..a.len() {
+        let high = if bits == 0 { 0 } else { a.get(i + 1).map_or(0, |x| x << (32 - bits)) };
+        out.push((a[i] >> bits) | high);
+    }
+    mag_trim(out)
+}
+
+fn mag_divmod_small(a: &[u32], d: u32) -> (Vec<u32>, u32) {
+    let mut out = vec![0u32; a.len()];
+    let mut rem = 0u64;
+    for i in (0..a.len()).rev() {
+        let n = (rem << 32) | a[i] as u64;
+        out[i] = (n / d as u64) as u32;
+        rem = n % d as u64;
+    }
+    (mag_trim(out), rem as u32)
+}
+
+//Binary long division. Requires b to be non-zero
+fn mag_divmod(a: &[u32], b: &[u32]) -> (Vec<u32>, Vec<u32>) {
+    if b.len() == 1 {
+        let (q, r) = mag_divmod_small(a, b[0]);
+        return (q, mag_trim(vec![r]));
+    }
+    if mag_cmp(a, b) == std::cmp::Ordering::Less {
+        return (Vec::new(), a.to_vec());
+    }
+    let mut q = vec![0u32; a.len()];
+    let mut r: Vec<u32> = Vec::new();
+    for bit in (0..a.len() * 32).rev() {
+        r = mag_shl(&r, 1);
+        if (a[bit / 32] >> (bit % 32)) & 1 == 1 {
+            if r.is_empty() {
+                r.push(1);
+            } else {
+                r[0] |= 1;
+            }
+        }
+        if mag_cmp(&r, b) != std::cmp::Ordering::Less {
+            r = mag_sub(&r, b);
+            q[bit / 32] |= 1 << (bit % 32);
+        }
+    }
+    (mag_trim(q), r)
+}
+
+impl Num {
+    fn new(neg: bool, mag: Vec<u32>) -> Num {
+        let mag = mag_trim(mag);
+        Num {
+            neg: neg && !mag.is_empty(),
+            mag,
+        }
+    }
+
+    fn from_i64(n: i64) -> Num {
+        let m = n.unsigned_abs();
+        Num::new(n < 0, vec![m as u32, (m >> 32) as u32])
+    }
+
+    fn parse(s: &str) -> Num {
+        let (neg, digits) = match s.strip_prefix('-') {
+            Some(d) => (true, d),
+            None => (false, s),
+        };
+        let mut mag: Vec<u32> = Vec::new();
+        for c in digits.chars() {
+            mag = mag_add(&mag_mul(&mag, &[10]), &[c.to_digit(10).expect("invalid digit")]);
+        }
+        Num::new(neg, mag)
+    }
+
+    fn is_positive(&self) -> bool {
+        !self.neg && !self.mag.is_empty()
+    }
+
+    fn to_u64(&self) -> Option<u64> {
+        if self.neg || self.mag.len() > 2 {
+            return None;
+        }
+        Some(self.mag.first().map_or(0, |&x| x as u64) | (self.mag.get(1).map_or(0, |&x| x as u64) << 32))
+    }
+
+    fn to_u8(&self) -> Option<u8> {
+        self.to_u64().and_then(|n| u8::try_from(n).ok())
+    }
+
+    //Two's complement, sign extended to len limbs
+    fn twos(&self, len: usize) -> Vec<u32> {
+        let mut v = self.mag.clone();
+        v.resize(len, 0);
+        if self.neg {
+            let mut carry = 1u64;
+            for x in v.iter_mut() {
+                let n = (!*x) as u64 + carry;
+                *x = n as u32;
+                carry = n >> 32;
+            }
+        }
+        v
+    }
+
+    fn from_twos(mut v: Vec<u32>) -> Num {
+        let neg = v.last().map_or(false, |x| x >> 31 == 1);
+        if neg {
+            let mut carry = 1u64;
+            for x in v.iter_mut() {
+                let n = (!*x) as u64 + carry;
+                *x = n as u32;
+                carry = n >> 32;
+            }
+        }
+        Num::new(neg, v)
+    }
+}
+
+impl std::fmt::Display for Num {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.mag.is_empty() {
+            return write!(f, "0");
+        }
+        let mut chunks: Vec<u32> = Vec::new();
+        let mut m = self.mag.clone();
+        while !m.is_empty() {
+            let (q, r) = mag_divmod_small(&m, 1_000_000_000);
+            chunks.push(r);
+            m = q;
+        }
+        let mut s = if self.neg { "-".to_string() } else { String::new() };
+        s += &chunks.pop().unwrap().to_string();
+        for c in chunks.iter().rev() {
+            s += &format!("{:09}", c);
+        }
+        write!(f, "{}", s)
+    }
+}
+
+fn num_from_i64(n: i64) -> Num {
+    Num::from_i64(n)
+}
+
+fn num_is_positive(n: &Num) -> bool {
+    n.is_positive()
+}
+
+fn num_to_u8(n: &Num) -> Option<u8> {
+    n.to_u8()
+}
+
+fn num_to_i64(n: &Num) -> Option<i64> {
+    let m = Num::new(false, n.mag.clone()).to_u64()?;
+    if n.neg {
+        0i64.checked_sub_unsigned(m)
+    } else {
+        i64::try_from(m).ok()
+    }
+}
+
+fn num_cmp(a: &Num, b: &Num) -> std::cmp::Ordering {
+    match (a.neg, b.neg) {
+        (false, true) => std::cmp::Ordering::Greater,
+        (true, false) => std::cmp::Ordering::Less,
+        (false, false) => mag_cmp(&a.mag, &b.mag),
+        (true, true) => mag_cmp(&b.mag, &a.mag),
+    }
+}
+
+fn num_add(a: Num, b: Num) -> Result<Num, &'static str> {
+    if a.neg == b.neg {
+        return Ok(Num::new(a.neg, mag_add(&a.mag, &b.mag)));
+    }
+    Ok(match mag_cmp(&a.mag, &b.mag) {
+        std::cmp::Ordering::Less => Num::new(b.neg, mag_sub(&b.mag, &a.mag)),
+        _ => Num::new(a.neg, mag_sub(&a.mag, &b.mag)),
+    })
+}
+
+fn num_sub(a: Num, b: Num) -> Result<Num, &'static str> {
+    let neg = !b.neg;
+    num_add(a, Num::new(neg, b.mag))
+}
+
+fn num_mul(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(Num::new(a.neg != b.neg, mag_mul(&a.mag, &b.mag)))
+}
+
+//Division truncates towards zero, and the remainder has the sign of the dividend
+fn num_div(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b.mag.is_empty() {
+        return Err("division by zero");
+    }
+    Ok(Num::new(a.neg != b.neg, mag_divmod(&a.mag, &b.mag).0))
+}
+
+fn num_rem(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b.mag.is_empty() {
+        return Err("division by zero");
+    }
+    Ok(Num::new(a.neg, mag_divmod(&a.mag, &b.mag).1))
+}
+
+fn num_bitwise(a: Num, b: Num, op: fn(u32, u32) -> u32) -> Result<Num, &'static str> {
+    let len = a.mag.len().max(b.mag.len()) + 1;
+    let (a, b) = (a.twos(len), b.twos(len));
+    Ok(Num::from_twos(a.iter().zip(&b).map(|(x, y)| op(*x, *y)).collect()))
+}
+
+fn num_and(a: Num, b: Num) -> Result<Num, &'static str> {
+    num_bitwise(a, b, |x, y| x & y)
+}
+
+fn num_or(a: Num, b: Num) -> Result<Num, &'static str> {
+    num_bitwise(a, b, |x, y| x | y)
+}
+
+fn num_xor(a: Num, b: Num) -> Result<Num, &'static str> {
+    num_bitwise(a, b, |x, y| x ^ y)
+}
+
+fn shift_amount(b: &Num) -> Result<usize, &'static str> {
+    match b.to_u64() {
+        Some(n) if n <= i64::MAX as u64 => Ok(n as usize),
+        _ => Err("shift amount out of range"),
+    }
+}
+
+fn num_shl(a: Num, b: Num) -> Result<Num, &'static str> {
+    let n = shift_amount(&b)?;
+    Ok(Num::new(a.neg, mag_shl(&a.mag, n)))
+}
+
+//Right shifts round towards negative infinity, like an arithmetic shift
+fn num_shr(a: Num, b: Num) -> Result<Num, &'static str> {
+    let n = shift_amount(&b)?;
+    if !a.neg {
+        return Ok(Num::new(false, mag_shr(&a.mag, n)));
+    }
+    let shifted = mag_shr(&mag_sub(&a.mag, &[1]), n);
+    Ok(Num::new(true, mag_add(&shifted, &[1])))
+}
@@ -0,0 +1,78 @@
+//Numbers for the checked64 model: any overflow, or a shift amount outside of 0-63, is a runtime error
+type Num = i64;
+
+fn num_from_i64(n: i64) -> Num {
+    n
+}
+
+fn num_is_positive(n: &Num) -> bool {
+    *n > 0
+}
+
+fn num_to_u8(n: &Num) -> Option<u8> {
+    u8::try_from(*n).ok()
+}
+
+fn num_to_i64(n: &Num) -> Option<i64> {
+    Some(*n)
+}
+
+fn num_cmp(a: &Num, b: &Num) -> std::cmp::Ordering {
+    a.cmp(b)
+}
+
+fn num_add(a: Num, b: Num) -> Result<Num, &'static str> {
+    a.checked_add(b).ok_or("integer overflow in Add")
+}
+
+fn num_sub(a: Num, b: Num) -> Result<Num, &'static str> {
+    a.checked_sub(b).ok_or("integer overflow in Subtract")
+}
+
+fn num_mul(a: Num, b: Num) -> Result<Num, &'static str> {
+    a.checked_mul(b).ok_or("integer overflow in Multiply")
+}
+
+fn num_div(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b == 0 {
+        return Err("division by zero");
+    }
+    a.checked_div(b).ok_or("integer overflow in Divide")
+}
+
+fn num_rem(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b == 0 {
+        return Err("division by zero");
+    }
+    a.checked_rem(b).ok_or("integer overflow in Modulo")
+}
+
+fn num_and(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a & b)
+}
+
+fn num_or(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a | b)
+}
+
+fn num_xor(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a ^ b)
+}
+
+fn num_shl(a: Num, b: Num) -> Result<Num, &'static str> {
+    if !(0..64).contains(&b) {
+        return Err("shift amount out of range");
+    }
+    let shifted = a << b;
+    if shifted >> b != a {
+        return Err("integer overflow in BitLeftShift");
+    }
+    Ok(shifted)
+}
+
+fn num_shr(a: Num, b: Num) -> Result<Num, &'static str> {
+    if !(0..64).contains(&b) {
+        return Err("shift amount out of range");
+    }
+    Ok(a >> b)
+}
@@ -0,0 +1,359 @@
+//The three stacks and I/O. Files are relative to the working directory, like the interpreter's files are relative to the program
+
+use std::{
+    convert::TryFrom,
+    fs::{File, OpenOptions},
+    io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Stdout, Write},
+    path::{Path, PathBuf},
+};
+
+const ROOT: &str = "./";
+
+//Turns a path into a real path under ROOT the way the interpreter does. `..` has to come from a directory which exists and stops at the root,
+//and a symlink can't lead outside of the root. With `below_root`, the root itself is refused too, since it can't be removed or moved
+fn resolve(path: &str, below_root: bool) -> std::io::Result<PathBuf> {
+    let root = Path::new(ROOT);
+    let mut names: Vec<&str> = Vec::new();
+    for name in path.split('/').filter(|s| !s.is_empty() && *s != ".") {
+        if name == ".." {
+            if !names.iter().fold(root.to_path_buf(), |p, n| p.join(n)).is_dir() {
+                return Err(ErrorKind::NotFound.into());
+            }
+            names.pop();
+        } else {
+            names.push(name);
+        }
+    }
+
+    //Only the part of the path which already exists can be a symlink
+    let mut existing = root.to_path_buf();
+    let mut rest = names.as_slice();
+    while let Some((first, tail)) = rest.split_first() {
+        let next = existing.join(first);
+        if next.symlink_metadata().is_err() {
+            break;
+        }
+        existing = next;
+        rest = tail;
+    }
+
+    let root = root.canonicalize()?;
+    let existing = existing.canonicalize()?;
+    if !existing.starts_with(&root) || (below_root && rest.is_empty() && existing == root) {
+        return Err(ErrorKind::PermissionDenied.into());
+    }
+    Ok(rest.iter().fold(existing, |p, n| p.join(n)))
+}
+
+struct Runtime {
+    stacks: [Vec<Num>; 3],
+    out: BufWriter<Stdout>,
+    //The open file streams, indexed by handle. Handles 0 and 1 are what the handle-less commands read from and write to
+    streams: Vec<Option<File>>,
+}
+
+impl Runtime {
+    fn new() -> Runtime {
+        Runtime {
+            stacks: [Vec::new(), Vec::new(), Vec::new()],
+            out: BufWriter::new(std::io::stdout()),
+            //Like in the interpreter, handles 0 and 1 aren't open until the program opens them
+            streams: vec![None, None],
+        }
+    }
+
+    fn finish(&mut self) {
+        self.out.flush().expect("Failed to write output");
+    }
+
+    fn push(&mut self, s: usize, n: Num) {
+        self.stacks[s].push(n);
+    }
+
+    fn pop(&mut self, s: usize) -> Num {
+        self.stacks[s].pop().unwrap_or_else(|| num_from_i64(0))
+    }
+
+    fn copy(&mut self, from: usize, to: usize) {
+        let n = self.pop(from);
+        self.push(from, n.clone());
+        self.push(to, n);
+    }
+
+    fn push_status(&mut self, ok: bool) {
+        self.push(2, num_from_i64(if ok { 1 } else { -1 }));
+    }
+
+    //Pops every value of stack C as a byte, from the top down
+    fn pop_bytes(&mut self, what: &str) -> Result<Vec<u8>, String> {
+        let mut bytes = Vec::with_capacity(self.stacks[2].len());
+        while let Some(n) = self.stacks[2].pop() {
+            match num_to_u8(&n) {
+                Some(b) => bytes.push(b),
+                None => return Err(format!("invalid char value {} in {}", n, what)),
+            }
+        }
+        Ok(bytes)
+    }
+
+    fn print(&mut self) -> Result<(), String> {
+        let s: String = self.pop_bytes("print")?.into_iter().map(|b| b as char).collect();
+        write!(self.out, "{}", s).expect("Failed to write output");
+        Ok(())
+    }
+
+    fn print_num(&mut self) {
+        while let Some(n) = self.stacks[2].pop() {
+            write!(self.out, "{}", n).expect("Failed to write output");
+        }
+    }
+
+    fn get_next_in(&mut self) {
+        self.out.flush().expect("Failed to write output");
+        let mut arr = [0];
+        if std::io::stdin().read_exact(&mut arr).is_ok() {
+            self.push(2, num_from_i64(arr[0] as i64));
+        }
+    }
+
+    //Uses the given path, or reads it from stack C if there isn't one. With `until_zero`, reading stops after popping a 0.
+    //It still has to be resolved
+    fn path(&mut self, path: Option<&str>, until_zero: bool) -> Result<String, String> {
+        Ok(match path {
+            Some(p) => p.to_string(),
+            None => {
+                let mut bytes = Vec::new();
+                while let Some(n) = self.stacks[2].pop() {
+                    match num_to_u8(&n) {
+                        Some(0) if until_zero => break,
+                        Some(b) => bytes.push(b),
+                        None => return Err(format!("invalid char value {} in a path", n)),
+                    }
+                }
+                String::from_utf8_lossy(&bytes).to_string()
+            }
+        })
+    }
+
+    fn create_file(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let ok = resolve(&path, false).and_then(File::create).is_ok();
+        self.push_status(ok);
+        Ok(())
+    }
+
+    //Stores a newly opened stream and pushes the status, after its handle if it gets a new one.
+    //Otherwise it replaces each of the `aliases`, which share its position since cloned handles refer to the same open file
+    fn store_stream(&mut self, f: std::io::Result<File>, new_handle: bool, aliases: &[usize]) {
+        let f = match f {
+            Ok(f) => f,
+            Err(_) => return self.push_status(false),
+        };
+        if new_handle {
+            //Like file descriptors, the lowest free handle is used
+            let free = (2..self.streams.len()).find(|&h| self.streams[h].is_none());
+            let handle = free.unwrap_or(self.streams.len());
+            if handle == self.streams.len() {
+                self.streams.push(None);
+            }
+            self.streams[handle] = Some(f);
+            self.push(2, num_from_i64(handle as i64));
+        } else {
+            for &h in &aliases[1..] {
+                self.streams[h] = f.try_clone().ok();
+            }
+            self.streams[aliases[0]] = Some(f);
+        }
+        self.push_status(true);
+    }
+
+    //The handle of a stream command, which is popped from stack C when it's `None`
+    fn handle(&mut self, handle: Option<u32>) -> Option<usize> {
+        match handle {
+            Some(h) => Some(h as usize),
+            None => {
+                let n = self.pop(2);
+                num_to_i64(&n).and_then(|n| usize::try_from(n).ok())
+            }
+        }
+    }
+
+    fn stream(&mut self, handle: Option<u32>) -> Option<&mut File> {
+        let handle = self.handle(handle)?;
+        self.streams.get_mut(handle)?.as_mut()
+    }
+
+    fn create_file_stream(&mut self, path: Option<&str>, new_handle: bool) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        self.store_stream(resolve(&path, false).and_then(File::create), new_handle, &[1]);
+        Ok(())
+    }
+
+    fn open_file_stream(&mut self, path: Option<&str>, new_handle: bool) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        self.store_stream(resolve(&path, false).and_then(File::open), new_handle, &[0]);
+        Ok(())
+    }
+
+    fn append_file_stream(&mut self, path: Option<&str>, new_handle: bool) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let f = resolve(&path, false).and_then(|p| OpenOptions::new().append(true).open(p));
+        self.store_stream(f, new_handle, &[1]);
+        Ok(())
+    }
+
+    fn read_write_file_stream(&mut self, path: Option<&str>, new_handle: bool) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let f = resolve(&path, false).and_then(|p| OpenOptions::new().read(true).write(true).open(p));
+        self.store_stream(f, new_handle, &[0, 1]);
+        Ok(())
+    }
+
+    //The handle is popped before the offset
+    fn seek_file_stream(&mut self, whence: &str, handle: Option<u32>) {
+        let handle = self.handle(handle);
+        let offset = self.pop(2);
+        let target = match (whence, num_to_i64(&offset)) {
+            ("start", Some(n)) if n >= 0 => Some(SeekFrom::Start(n as u64)),
+            ("current", Some(n)) => Some(SeekFrom::Current(n)),
+            ("end", Some(n)) => Some(SeekFrom::End(n)),
+            _ => None,
+        };
+        let stream = handle.and_then(|h| self.streams.get_mut(h)?.as_mut());
+        let ok = match (stream, target) {
+            (Some(f), Some(t)) => f.seek(t).is_ok(),
+            _ => false,
+        };
+        self.push_status(ok);
+    }
+
+    fn tell_file_stream(&mut self, handle: Option<u32>) {
+        match self.stream(handle).map(|f| f.stream_position()) {
+            Some(Ok(pos)) => {
+                self.push(2, num_from_i64(pos as i64));
+                self.push_status(true);
+            }
+            _ => self.push_status(false),
+        }
+    }
+
+    fn close_file_stream(&mut self, handle: Option<u32>) {
+        let closed = self.handle(handle).and_then(|h| self.streams.get_mut(h)?.take());
+        self.push_status(closed.is_some());
+    }
+
+    fn read_file_stream(&mut self, handle: Option<u32>) {
+        let mut arr = [0u8];
+        match self.stream(handle).map(|f| f.read(&mut arr)) {
+            Some(Ok(0)) => {
+                self.push(2, num_from_i64(-1));
+                self.push_status(true);
+            }
+            Some(Ok(_)) => {
+                self.push(2, num_from_i64(arr[0] as i64));
+                self.push_status(true);
+            }
+            _ => self.push_status(false),
+        }
+    }
+
+    //The handle is popped before the bytes to write
+    fn write_file_stream(&mut self, handle: Option<u32>) -> Result<(), String> {
+        let handle = self.handle(handle);
+        let bytes = self.pop_bytes("writefilestream")?;
+        let stream = handle.and_then(|h| self.streams.get_mut(h)?.as_mut());
+        let ok = stream.is_some_and(|f| f.write(&bytes).is_ok());
+        self.push_status(ok);
+        Ok(())
+    }
+
+    fn make_dir(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let ok = resolve(&path, false).and_then(std::fs::create_dir).is_ok();
+        self.push_status(ok);
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let ok = resolve(&path, true).and_then(std::fs::remove_dir).is_ok();
+        self.push_status(ok);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: Option<&str>, to: Option<&str>) -> Result<(), String> {
+        //When both paths come from stack C, the first one ends at a 0
+        let from = self.path(from, to.is_none())?;
+        let to = self.path(to, false)?;
+        let ok = resolve(&from, true)
+            .and_then(|from| resolve(&to, false).and_then(|to| std::fs::rename(from, to)))
+            .is_ok();
+        self.push_status(ok);
+        Ok(())
+    }
+
+    fn remove_file(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let ok = resolve(&path, false).and_then(std::fs::remove_file).is_ok();
+        self.push_status(ok);
+        Ok(())
+    }
+
+    fn exists(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let ok = resolve(&path, false).map_or(false, |p| p.exists());
+        self.push_status(ok);
+        Ok(())
+    }
+
+    //Directories don't have a size, so they count as a failure
+    fn file_size(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        match resolve(&path, false).and_then(std::fs::metadata) {
+            Ok(m) if !m.is_dir() => {
+                self.push(2, num_from_i64(m.len() as i64));
+                self.push_status(true);
+            }
+            _ => self.push_status(false),
+        }
+        Ok(())
+    }
+
+    fn list_dir(&mut self, path: Option<&str>) -> Result<(), String> {
+        let path = self.path(path, false)?;
+        let names: std::io::Result<Vec<String>> = resolve(&path, false).and_then(std::fs::read_dir).and_then(|dir| {
+            dir.map(|entry| entry.map(|e| e.file_name().to_string_lossy().to_string()))
+                .collect()
+        });
+        match names {
+            Ok(mut names) => {
+                names.sort();
+                //The first name ends up nearest the top, with its first byte uppermost
+                for name in names.iter().rev() {
+                    self.push(2, num_from_i64(0));
+                    for b in name.bytes().rev() {
+                        self.push(2, num_from_i64(b as i64));
+                    }
+                }
+                self.push(2, num_from_i64(names.len() as i64));
+                self.push_status(true);
+            }
+            Err(_) => self.push_status(false),
+        }
+        Ok(())
+    }
+}
+
+fn error(index: usize, e: impl std::fmt::Display) -> String {
+    format!("runtime error at index {}: {}", index, e)
+}
+
+fn main() {
+    let mut rt = Runtime::new();
+    let result = run(&mut rt);
+    rt.finish();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
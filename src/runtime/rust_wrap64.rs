@@ -0,0 +1,68 @@
+//Numbers for the wrap64 model: two's complement wrapping, with shift amounts masked to their low 6 bits
+type Num = i64;
+
+fn num_from_i64(n: i64) -> Num {
+    n
+}
+
+fn num_is_positive(n: &Num) -> bool {
+    *n > 0
+}
+
+fn num_to_u8(n: &Num) -> Option<u8> {
+    u8::try_from(*n).ok()
+}
+
+fn num_to_i64(n: &Num) -> Option<i64> {
+    Some(*n)
+}
+
+fn num_cmp(a: &Num, b: &Num) -> std::cmp::Ordering {
+    a.cmp(b)
+}
+
+fn num_add(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a.wrapping_add(b))
+}
+
+fn num_sub(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a.wrapping_sub(b))
+}
+
+fn num_mul(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a.wrapping_mul(b))
+}
+
+fn num_div(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b == 0 {
+        return Err("division by zero");
+    }
+    Ok(a.wrapping_div(b))
+}
+
+fn num_rem(a: Num, b: Num) -> Result<Num, &'static str> {
+    if b == 0 {
+        return Err("division by zero");
+    }
+    Ok(a.wrapping_rem(b))
+}
+
+fn num_and(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a & b)
+}
+
+fn num_or(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a | b)
+}
+
+fn num_xor(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a ^ b)
+}
+
+fn num_shl(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a << (b & 63))
+}
+
+fn num_shr(a: Num, b: Num) -> Result<Num, &'static str> {
+    Ok(a >> (b & 63))
+}
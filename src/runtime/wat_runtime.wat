@@ -0,0 +1,245 @@
+  ;; The runtime. Memory starts with a descriptor for each stack (at 12 * stack): the address of its values, its length and its capacity.
+  ;; Stacks and the scratch buffer are allocated from $heap, and are copied when they grow
+
+  (global $scratch (mut i32) (i32.const 0))
+  (global $scratch_cap (mut i32) (i32.const 0))
+  (global $path_len (mut i32) (i32.const 0))
+
+  (func $alloc (param $bytes i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $heap))
+    (global.set $heap (i32.add (global.get $heap) (local.get $bytes)))
+    (block $done
+      (loop $grow
+        (br_if $done
+          (i32.le_u (global.get $heap) (i32.mul (memory.size) (i32.const 65536))))
+        (if (i32.eq (memory.grow (i32.const 1)) (i32.const -1))
+          (then unreachable))
+        (br $grow)))
+    (local.get $ptr))
+
+  ;; Makes sure the scratch buffer can hold $bytes bytes, returning its address
+  (func $scratch_for (param $bytes i32) (result i32)
+    (if (i32.gt_u (local.get $bytes) (global.get $scratch_cap))
+      (then
+        (global.set $scratch (call $alloc (local.get $bytes)))
+        (global.set $scratch_cap (local.get $bytes))))
+    (global.get $scratch))
+
+  (func $fail (param $index i32) (param $code i32)
+    (call $host_runtime_error (local.get $index) (local.get $code))
+    unreachable)
+
+  (func $len (param $s i32) (result i32)
+    (i32.load offset=4 (i32.mul (local.get $s) (i32.const 12))))
+
+  (func $push (param $s i32) (param $v i64)
+    (local $d i32)
+    (local $len i32)
+    (local $cap i32)
+    (local $base i32)
+    (local $new i32)
+    (local.set $d (i32.mul (local.get $s) (i32.const 12)))
+    (local.set $base (i32.load (local.get $d)))
+    (local.set $len (i32.load offset=4 (local.get $d)))
+    (local.set $cap (i32.load offset=8 (local.get $d)))
+    (if (i32.eq (local.get $len) (local.get $cap))
+      (then
+        (local.set $cap
+          (select (i32.shl (local.get $cap) (i32.const 1)) (i32.const 64) (local.get $cap)))
+        (local.set $new (call $alloc (i32.shl (local.get $cap) (i32.const 3))))
+        (memory.copy (local.get $new) (local.get $base) (i32.shl (local.get $len) (i32.const 3)))
+        (local.set $base (local.get $new))
+        (i32.store (local.get $d) (local.get $base))
+        (i32.store offset=8 (local.get $d) (local.get $cap))))
+    (i64.store
+      (i32.add (local.get $base) (i32.shl (local.get $len) (i32.const 3)))
+      (local.get $v))
+    (i32.store offset=4 (local.get $d) (i32.add (local.get $len) (i32.const 1))))
+
+  ;; Popping an empty stack gives 0
+  (func $pop (param $s i32) (result i64)
+    (local $d i32)
+    (local $len i32)
+    (local.set $d (i32.mul (local.get $s) (i32.const 12)))
+    (local.set $len (i32.load offset=4 (local.get $d)))
+    (if (result i64) (i32.eqz (local.get $len))
+      (then (i64.const 0))
+      (else
+        (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+        (i32.store offset=4 (local.get $d) (local.get $len))
+        (i64.load
+          (i32.add (i32.load (local.get $d)) (i32.shl (local.get $len) (i32.const 3)))))))
+
+  (func $clear_c
+    (i32.store offset=4 (i32.const 24) (i32.const 0)))
+
+  (func $copy (param $from i32) (param $to i32)
+    (local $v i64)
+    (local.set $v (call $pop (local.get $from)))
+    (call $push (local.get $from) (local.get $v))
+    (call $push (local.get $to) (local.get $v)))
+
+  (func $push_status (param $status i32)
+    (call $push (i32.const 2) (i64.extend_i32_s (local.get $status))))
+
+  ;; Pops a value of stack C which must be a byte
+  (func $pop_byte (param $index i32) (result i32)
+    (local $v i64)
+    (local.set $v (call $pop (i32.const 2)))
+    (if (i64.gt_u (local.get $v) (i64.const 255))
+      (then (call $fail (local.get $index) (i32.const 2))))
+    (i32.wrap_i64 (local.get $v)))
+
+  (func $print (param $index i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (call $len (i32.const 2))))
+        (call $host_print_char (call $pop_byte (local.get $index)))
+        (br $next))))
+
+  (func $print_num
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (call $len (i32.const 2))))
+        (call $host_print_num (call $pop (i32.const 2)))
+        (br $next))))
+
+  (func $get_next_in
+    (local $c i32)
+    (local.set $c (call $host_get_next_in))
+    (if (i32.ge_s (local.get $c) (i32.const 0))
+      (then (call $push (i32.const 2) (i64.extend_i32_u (local.get $c))))))
+
+  ;; Pops stack C to $ptr, each value as one byte like the interpreter does, returning the length.
+  ;; If $until_zero is set it stops after popping a 0, which isn't written
+  (func $pop_path (param $index i32) (param $ptr i32) (param $until_zero i32) (result i32)
+    (local $c i32)
+    (local $n i32)
+    (block $done
+      (loop $next
+        (br_if $done (i32.eqz (call $len (i32.const 2))))
+        (local.set $c (call $pop_byte (local.get $index)))
+        (br_if $done (i32.and (local.get $until_zero) (i32.eqz (local.get $c))))
+        (i32.store8 (i32.add (local.get $ptr) (local.get $n)) (local.get $c))
+        (local.set $n (i32.add (local.get $n) (i32.const 1)))
+        (br $next)))
+    (local.get $n))
+
+  ;; Pops all of stack C into the scratch buffer as a path, returning its address. The length is left in $path_len
+  (func $path_from_c (param $index i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (call $scratch_for (call $len (i32.const 2))))
+    (global.set $path_len (call $pop_path (local.get $index) (local.get $ptr) (i32.const 0)))
+    (local.get $ptr))
+
+  ;; Renames with both paths read from stack C, the first of which ends at a 0. They go in the scratch buffer one after the other
+  (func $rename_from_c (param $index i32) (result i32)
+    (local $ptr i32)
+    (local $from_len i32)
+    (local.set $ptr (call $scratch_for (call $len (i32.const 2))))
+    (local.set $from_len (call $pop_path (local.get $index) (local.get $ptr) (i32.const 1)))
+    (call $host_rename
+      (local.get $ptr)
+      (local.get $from_len)
+      (i32.add (local.get $ptr) (local.get $from_len))
+      (call $pop_path (local.get $index) (i32.add (local.get $ptr) (local.get $from_len)) (i32.const 0))))
+
+  ;; Pushes a file's size or a stream's position and then 1, or just -1 if the host gave a negative one
+  (func $push_file_size (param $size i64)
+    (if (i64.lt_s (local.get $size) (i64.const 0))
+      (then (call $push_status (i32.const -1)))
+      (else
+        (call $push (i32.const 2) (local.get $size))
+        (call $push_status (i32.const 1)))))
+
+  ;; Pushes the listing the host just made of $count entries, like the interpreter does, or just -1 if $count is negative
+  (func $push_listing (param $count i32)
+    (local $entry i32)
+    (local $len i32)
+    (if (i32.lt_s (local.get $count) (i32.const 0))
+      (then
+        (call $push_status (i32.const -1))
+        (return)))
+    ;; The first name ends up nearest the top, with its first byte uppermost. Like paths, names are pushed as bytes
+    (local.set $entry (local.get $count))
+    (block $done
+      (loop $next_entry
+        (br_if $done (i32.eqz (local.get $entry)))
+        (local.set $entry (i32.sub (local.get $entry) (i32.const 1)))
+        (call $push (i32.const 2) (i64.const 0))
+        (local.set $len (i32.const 0))
+        (block $measured
+          (loop $measure
+            (br_if $measured
+              (i32.lt_s (call $host_list_dir_char (local.get $entry) (local.get $len)) (i32.const 0)))
+            (local.set $len (i32.add (local.get $len) (i32.const 1)))
+            (br $measure)))
+        (block $pushed
+          (loop $next_char
+            (br_if $pushed (i32.eqz (local.get $len)))
+            (local.set $len (i32.sub (local.get $len) (i32.const 1)))
+            (call $push (i32.const 2)
+              (i64.extend_i32_u (call $host_list_dir_char (local.get $entry) (local.get $len))))
+            (br $next_char)))
+        (br $next_entry)))
+    (call $push (i32.const 2) (i64.extend_i32_u (local.get $count)))
+    (call $push_status (i32.const 1)))
+
+  ;; Pops a stream handle from stack C, giving -1 (which no stream has) if it's out of range
+  (func $pop_handle (result i32)
+    (local $h i64)
+    (local.set $h (call $pop (i32.const 2)))
+    (if (result i32) (i64.gt_u (local.get $h) (i64.const 0x7fffffff))
+      (then (i32.const -1))
+      (else (i32.wrap_i64 (local.get $h)))))
+
+  ;; Pushes the status of an open, after the handle the host gave if it's a new one
+  (func $push_opened (param $handle i32) (param $new i32)
+    (if (i32.lt_s (local.get $handle) (i32.const 0))
+      (then
+        (call $push_status (i32.const -1))
+        (return)))
+    (if (local.get $new)
+      (then (call $push (i32.const 2) (i64.extend_i32_u (local.get $handle)))))
+    (call $push_status (i32.const 1)))
+
+  (func $read_file_stream (param $handle i32)
+    (local $r i32)
+    (local.set $r (call $host_read_file_stream (local.get $handle)))
+    (if (i32.eq (local.get $r) (i32.const -2))
+      (then (call $push_status (i32.const -1)))
+      (else
+        (call $push (i32.const 2) (i64.extend_i32_s (local.get $r)))
+        (call $push_status (i32.const 1)))))
+
+  ;; The handle is popped (as an argument) before the bytes to write
+  (func $write_file_stream (param $index i32) (param $handle i32)
+    (local $ptr i32)
+    (local $i i32)
+    (local $count i32)
+    (local.set $count (call $len (i32.const 2)))
+    (local.set $ptr (call $scratch_for (local.get $count)))
+    (block $done
+      (loop $next
+        (br_if $done (i32.eq (local.get $i) (local.get $count)))
+        (i32.store8 (i32.add (local.get $ptr) (local.get $i)) (call $pop_byte (local.get $index)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (call $push_status (call $host_write_file_stream (local.get $handle) (local.get $ptr) (local.get $count))))
+
+  ;; wrap64 arithmetic. Shifts already use the low 6 bits of the amount, but division needs care since it traps
+  (func $div (param $a i64) (param $b i64) (param $index i32) (result i64)
+    (if (i64.eqz (local.get $b))
+      (then (call $fail (local.get $index) (i32.const 1))))
+    (if (result i64)
+      (i32.and
+        (i64.eq (local.get $a) (i64.const 0x8000000000000000))
+        (i64.eq (local.get $b) (i64.const -1)))
+      (then (local.get $a))
+      (else (i64.div_s (local.get $a) (local.get $b)))))
+
+  (func $rem (param $a i64) (param $b i64) (param $index i32) (result i64)
+    (if (i64.eqz (local.get $b))
+      (then (call $fail (local.get $index) (i32.const 1))))
+    (i64.rem_s (local.get $a) (local.get $b)))
@@ -0,0 +1,210 @@
+use std::io::{self, Read, Write};
+
+//A small reader and writer for ustar archives, enough to store the files and directories of a file system.
+//Every entry is a 512 byte header followed by its data, padded to a multiple of 512 bytes, and the archive ends with two blocks of zeroes
+
+const BLOCK: usize = 512;
+
+///One file or directory in an archive. Paths are relative, separated by `/` and have no trailing `/`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TarEntry {
+    Dir(String),
+    File(String, Vec<u8>),
+}
+
+impl TarEntry {
+    pub fn path(&self) -> &str {
+        match self {
+            TarEntry::Dir(path) | TarEntry::File(path, _) => path,
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+///Reads a field which ends at its first 0, or fills the whole field
+fn read_str(field: &[u8]) -> Result<String, io::Error> {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8(field[..end].to_vec())
+        .map_err(|_| invalid("Tar entry name is not valid UTF-8".to_string()))
+}
+
+///Reads an octal number, which may be padded with spaces or 0s
+fn read_octal(field: &[u8]) -> Result<u64, io::Error> {
+    let s = read_str(field)?;
+    let s = s.trim_matches(|c| c == ' ' || c == '\0');
+    if s.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(s, 8).map_err(|_| invalid(format!("Bad number in tar header: {:?}", s)))
+}
+
+///Writes an octal number followed by a 0, filling the field with leading zeroes
+fn write_octal(field: &mut [u8], n: u64) {
+    let s = format!("{:0width$o}\0", n, width = field.len() - 1);
+    field.copy_from_slice(s.as_bytes());
+}
+
+///The sum of the header's bytes, counting the checksum field itself as spaces
+fn checksum(header: &[u8; BLOCK]) -> u64 {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum()
+}
+
+///Reads every entry until the end of the archive. Extended headers are skipped, and other kinds of entries such as links are refused
+pub fn read_tar(mut reader: impl Read) -> Result<Vec<TarEntry>, io::Error> {
+    let mut entries = Vec::new();
+    let mut header = [0u8; BLOCK];
+    loop {
+        //Some writers leave out the second block of zeroes, or both
+        if let Err(e) = reader.read_exact(&mut header) {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                return Ok(entries);
+            }
+            return Err(e);
+        }
+        if header.iter().all(|&b| b == 0) {
+            return Ok(entries);
+        }
+
+        if read_octal(&header[148..156])? != checksum(&header) {
+            return Err(invalid("Bad tar header checksum".to_string()));
+        }
+        let mut path = read_str(&header[0..100])?;
+        //GNU archives use the prefix field for other things, and mark that with a different magic
+        if &header[257..263] == b"ustar\0" {
+            let prefix = read_str(&header[345..500])?;
+            if !prefix.is_empty() {
+                path = prefix + "/" + &path;
+            }
+        }
+        let size = read_octal(&header[124..136])?;
+
+        let mut data = Vec::new();
+        (&mut reader).take(size).read_to_end(&mut data)?;
+        if (data.len() as u64) < size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        let padding = (BLOCK - data.len() % BLOCK) % BLOCK;
+        reader.read_exact(&mut vec![0; padding])?;
+
+        let path = path.trim_end_matches('/').to_string();
+        match header[156] {
+            b'0' | b'\0' | b'7' => entries.push(TarEntry::File(path, data)),
+            b'5' => entries.push(TarEntry::Dir(path)),
+            b'x' | b'g' => (),
+            kind => {
+                return Err(invalid(format!(
+                    "Unsupported kind of tar entry '{}' for \"{}\"",
+                    kind as char, path
+                )))
+            }
+        }
+    }
+}
+
+///Splits a path into the header's prefix and name fields, which hold 155 and 100 bytes
+fn split_path(path: &str) -> Result<(&str, &str), io::Error> {
+    if path.len() <= 100 {
+        return Ok(("", path));
+    }
+    //The split has to be at a `/`, which is left out of both fields
+    path.match_indices('/')
+        .map(|(i, _)| (&path[..i], &path[i + 1..]))
+        .find(|(prefix, name)| prefix.len() <= 155 && name.len() <= 100 && !name.is_empty())
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("\"{}\" is too long to store in a tar archive", path),
+            )
+        })
+}
+
+///Writes the entries as a ustar archive. Directories get a trailing `/` in their names
+pub fn write_tar(mut writer: impl Write, entries: &[TarEntry]) -> Result<(), io::Error> {
+    for entry in entries {
+        let (path, mode, kind, data): (String, u64, u8, &[u8]) = match entry {
+            TarEntry::Dir(path) => (path.clone() + "/", 0o755, b'5', &[]),
+            TarEntry::File(path, data) => (path.clone(), 0o644, b'0', data),
+        };
+        let (prefix, name) = split_path(&path)?;
+
+        let mut header = [0u8; BLOCK];
+        header[0..name.len()].copy_from_slice(name.as_bytes());
+        write_octal(&mut header[100..108], mode);
+        write_octal(&mut header[108..116], 0);
+        write_octal(&mut header[116..124], 0);
+        write_octal(&mut header[124..136], data.len() as u64);
+        write_octal(&mut header[136..148], 0);
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+        //The checksum is 6 digits, a 0 and a space
+        let sum = format!("{:06o}\0 ", checksum(&header));
+        header[148..156].copy_from_slice(sum.as_bytes());
+
+        writer.write_all(&header)?;
+        writer.write_all(data)?;
+        writer.write_all(&vec![0; (BLOCK - data.len() % BLOCK) % BLOCK])?;
+    }
+    writer.write_all(&[0; BLOCK * 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_tar, write_tar, TarEntry};
+
+    #[test]
+    fn round_trip() {
+        let long_dir = format!("{}/{}", "d".repeat(60), "e".repeat(60));
+        let entries = vec![
+            TarEntry::Dir("sub".to_string()),
+            TarEntry::File("sub/a.txt".to_string(), b"a".to_vec()),
+            TarEntry::File("empty".to_string(), Vec::new()),
+            TarEntry::File("block".to_string(), vec![7; 512]),
+            TarEntry::Dir(long_dir.clone()),
+            TarEntry::File(long_dir + "/" + &"f".repeat(90), b"long".to_vec()),
+        ];
+        let mut archive = Vec::new();
+        write_tar(&mut archive, &entries).unwrap();
+        //Headers, data blocks and the two blocks at the end
+        assert_eq!(archive.len(), 512 * (6 + 3 + 2));
+        assert_eq!(read_tar(archive.as_slice()).unwrap(), entries);
+
+        //A name over 100 bytes can't be split
+        assert!(write_tar(Vec::new(), &[TarEntry::Dir("x".repeat(101))]).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_archives() {
+        let mut archive = Vec::new();
+        write_tar(
+            &mut archive,
+            &[TarEntry::File("a".to_string(), vec![1; 10])],
+        )
+        .unwrap();
+
+        let mut bad = archive.clone();
+        bad[0] = b'b';
+        assert!(read_tar(bad.as_slice()).is_err());
+
+        //Cut off in the middle of the data
+        assert!(read_tar(&archive[..515]).is_err());
+
+        //A symlink
+        let mut link = archive.clone();
+        link[156] = b'2';
+        let sum = format!(
+            "{:06o}\0 ",
+            super::checksum(link[..512].try_into().unwrap())
+        );
+        link[148..156].copy_from_slice(sum.as_bytes());
+        assert!(read_tar(link.as_slice()).is_err());
+    }
+}
@@ -2,7 +2,9 @@ use std::fmt::Display;
 
 use num::BigInt;
 
-#[derive(Debug)]
+use crate::value::BinaryOp;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum TokenType {
     Exit,
 
@@ -11,10 +13,27 @@ pub enum TokenType {
     GetNextIn,
 
     CreateFile { arg: String },
-    CreateFileStream { arg: String },
-    OpenFileStream { arg: String },
-    ReadFileStream,
-    WriteFileStream,
+    //The streams opened without `new_handle` replace handle 1 when they write and handle 0 when they read,
+    //while those with it get a new handle which is pushed to stack C
+    CreateFileStream { arg: String, new_handle: bool },
+    OpenFileStream { arg: String, new_handle: bool },
+    ReadFileStream { handle: Handle },
+    WriteFileStream { handle: Handle },
+    //`openfilestream:PATH:append`
+    AppendFileStream { arg: String, new_handle: bool },
+    //`openfilestream:PATH:readwrite`. Without `new_handle` it becomes both handle 0 and handle 1
+    ReadWriteFileStream { arg: String, new_handle: bool },
+    SeekFileStream { whence: Whence, handle: Handle },
+    TellFileStream { handle: Handle },
+    CloseFileStream { handle: Handle },
+    MakeDir { arg: String },
+    RemoveDir { arg: String },
+    //Either path may be empty, in which case it's read from stack C
+    Rename { from: String, to: String },
+    RemoveFile { arg: String },
+    Exists { arg: String },
+    FileSize { arg: String },
+    ListDir { arg: String },
 
     Clear,
     Push { arg: BigInt },
@@ -44,6 +63,159 @@ pub enum TokenType {
     BitXor,
     BitRightShift,
     BitLeftShift,
+
+    //Superinstructions, which only come from fusing common sequences of tokens when optimizing
+    //`push:N move:C:S`
+    PushTo { stack: u8, value: BigInt },
+    //`push:1 move:C:B - move:C:A`
+    DecA,
+    //`copy:S:C jump:X`, which jumps if the top of S is positive without changing C
+    JumpIfTopPositive { stack: u8, target: usize },
+}
+
+///The file stream a stream command uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Handle {
+    Number(u32),
+    ///Popped from the top of stack C when the command runs
+    Popped,
+}
+
+impl Handle {
+    ///Handle 0 is what the handle-less commands read from
+    pub const READ: Handle = Handle::Number(0);
+    ///Handle 1 is what the handle-less commands write to
+    pub const WRITE: Handle = Handle::Number(1);
+
+    ///Parses a handle argument: a number, or `C` to pop it from stack C
+    pub fn from_name(s: &str) -> Option<Handle> {
+        match s {
+            "C" => Some(Handle::Popped),
+            _ => s.parse().ok().map(Handle::Number),
+        }
+    }
+}
+
+impl Display for Handle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Handle::Number(n) => write!(f, "{}", n),
+            Handle::Popped => write!(f, "C"),
+        }
+    }
+}
+
+///Where `seekfilestream` measures its offset from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Whence {
+    Start,
+    Current,
+    End,
+}
+
+impl Whence {
+    pub fn from_name(s: &str) -> Option<Whence> {
+        match s {
+            "start" => Some(Whence::Start),
+            "current" => Some(Whence::Current),
+            "end" => Some(Whence::End),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Whence::Start => "start",
+            Whence::Current => "current",
+            Whence::End => "end",
+        }
+    }
+}
+
+impl TokenType {
+    ///The operator of a token which pops A and B and pushes the result to C
+    pub fn binary_op(&self) -> Option<BinaryOp> {
+        Some(match self {
+            TokenType::Add => BinaryOp::Add,
+            TokenType::Subtract => BinaryOp::Subtract,
+            TokenType::Multiply => BinaryOp::Multiply,
+            TokenType::Divide => BinaryOp::Divide,
+            TokenType::Modulo => BinaryOp::Modulo,
+            TokenType::Equal => BinaryOp::Equal,
+            TokenType::LessThan => BinaryOp::LessThan,
+            TokenType::LessThanOrEqual => BinaryOp::LessThanOrEqual,
+            TokenType::GreaterThan => BinaryOp::GreaterThan,
+            TokenType::GreaterThanOrEqual => BinaryOp::GreaterThanOrEqual,
+            TokenType::BitAnd => BinaryOp::BitAnd,
+            TokenType::BitOr => BinaryOp::BitOr,
+            TokenType::BitXor => BinaryOp::BitXor,
+            TokenType::BitRightShift => BinaryOp::BitRightShift,
+            TokenType::BitLeftShift => BinaryOp::BitLeftShift,
+            _ => return None,
+        })
+    }
+
+    ///The index a resolved jump goes to (before skipping the target, like every jump)
+    pub fn jump_target(&self) -> Option<usize> {
+        match self {
+            TokenType::Jump { arg } => Some(*arg),
+            TokenType::JumpIfTopPositive { target, .. } => Some(*target),
+            _ => None,
+        }
+    }
+
+    ///The name of the token's variant, without its argument
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TokenType::Exit => "Exit",
+            TokenType::Print => "Print",
+            TokenType::PrintNum => "PrintNum",
+            TokenType::GetNextIn => "GetNextIn",
+            TokenType::CreateFile { .. } => "CreateFile",
+            TokenType::CreateFileStream { .. } => "CreateFileStream",
+            TokenType::OpenFileStream { .. } => "OpenFileStream",
+            TokenType::ReadFileStream { .. } => "ReadFileStream",
+            TokenType::WriteFileStream { .. } => "WriteFileStream",
+            TokenType::AppendFileStream { .. } => "AppendFileStream",
+            TokenType::ReadWriteFileStream { .. } => "ReadWriteFileStream",
+            TokenType::SeekFileStream { .. } => "SeekFileStream",
+            TokenType::TellFileStream { .. } => "TellFileStream",
+            TokenType::CloseFileStream { .. } => "CloseFileStream",
+            TokenType::MakeDir { .. } => "MakeDir",
+            TokenType::RemoveDir { .. } => "RemoveDir",
+            TokenType::Rename { .. } => "Rename",
+            TokenType::RemoveFile { .. } => "RemoveFile",
+            TokenType::Exists { .. } => "Exists",
+            TokenType::FileSize { .. } => "FileSize",
+            TokenType::ListDir { .. } => "ListDir",
+            TokenType::Clear => "Clear",
+            TokenType::Push { .. } => "Push",
+            TokenType::Pop { .. } => "Pop",
+            TokenType::Add => "Add",
+            TokenType::Subtract => "Subtract",
+            TokenType::Multiply => "Multiply",
+            TokenType::Divide => "Divide",
+            TokenType::Modulo => "Modulo",
+            TokenType::Move { .. } => "Move",
+            TokenType::Copy { .. } => "Copy",
+            TokenType::PreComputeJump { .. } => "PreComputeJump",
+            TokenType::Jump { .. } => "Jump",
+            TokenType::Label { .. } => "Label",
+            TokenType::Equal => "Equal",
+            TokenType::LessThan => "LessThan",
+            TokenType::LessThanOrEqual => "LessThanOrEqual",
+            TokenType::GreaterThan => "GreaterThan",
+            TokenType::GreaterThanOrEqual => "GreaterThanOrEqual",
+            TokenType::BitAnd => "BitAnd",
+            TokenType::BitOr => "BitOr",
+            TokenType::BitXor => "BitXor",
+            TokenType::BitRightShift => "BitRightShift",
+            TokenType::BitLeftShift => "BitLeftShift",
+            TokenType::PushTo { .. } => "PushTo",
+            TokenType::DecA => "DecA",
+            TokenType::JumpIfTopPositive { .. } => "JumpIfTopPositive",
+        }
+    }
 }
 
 impl Display for TokenType {
@@ -51,3 +223,38 @@ impl Display for TokenType {
         write!(f, "{:?}", self)
     }
 }
+
+///The location of a token in the source text. Lines and columns both start at 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line: usize,
+    pub col: usize,
+    pub len: usize,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+///A token stream along with the span of each token. `spans[i]` is the span of `tokens[i]`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Program {
+    pub tokens: Vec<TokenType>,
+    pub spans: Vec<Span>,
+}
+
+impl Program {
+    pub fn new() -> Program {
+        Program {
+            tokens: Vec::new(),
+            spans: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, token: TokenType, span: Span) {
+        self.tokens.push(token);
+        self.spans.push(span);
+    }
+}
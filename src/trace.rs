@@ -0,0 +1,239 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+};
+
+use crate::{
+    analysis::STACK_NAMES,
+    token::{Program, Span, TokenType},
+    value::Value,
+};
+
+///Decides which executed tokens get written to the trace
+#[derive(Debug, Clone, Default)]
+pub struct TraceFilter {
+    ///Only tokens of these kinds (ex. "Add", "Jump") are traced. If empty, every kind is traced
+    pub kinds: Vec<String>,
+    ///Only tokens from the first label with the first name up to (but not including) the first label with the second name are traced.
+    /// If there is no second name, tracing continues to the end of the program
+    pub label_range: Option<(String, Option<String>)>,
+}
+
+///Where and what to trace while interpreting. Every executed token which passes the filter is written as one JSON Lines record:
+/// `{"step":0,"index":2,"span":{"line":1,"col":1,"len":6},"kind":"Push","popped":{},"pushed":{"C":[1]},"heights":[0,0,1]}`
+pub struct TraceConfig {
+    pub writer: Box<dyn Write>,
+    pub filter: TraceFilter,
+    ///The maximum number of records to write
+    pub limit: Option<usize>,
+}
+
+impl TraceConfig {
+    pub fn new(writer: Box<dyn Write>) -> TraceConfig {
+        TraceConfig {
+            writer,
+            filter: TraceFilter::default(),
+            limit: None,
+        }
+    }
+
+    ///Creates (or truncates) the file at `path` and traces to it
+    pub fn to_file(path: &str) -> Result<TraceConfig, io::Error> {
+        Ok(TraceConfig::new(Box::new(BufWriter::new(File::create(
+            path,
+        )?))))
+    }
+}
+
+///The values popped from and pushed to one stack during a single step
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    pub popped: Vec<Value>,
+    pub pushed: Vec<Value>,
+}
+
+///Writes trace records for the interpreter
+pub struct Tracer {
+    config: TraceConfig,
+    ///The range of token indices which may be traced
+    index_range: (usize, usize),
+    written: usize,
+}
+
+impl Tracer {
+    pub fn new(config: TraceConfig, program: &Program) -> Tracer {
+        let find_label = |name: &str| {
+            program
+                .tokens
+                .iter()
+                .position(|t| matches!(t, TokenType::Label { arg } if arg == name))
+        };
+
+        let index_range = match &config.filter.label_range {
+            //A missing start label means nothing is traced
+            Some((start, end)) => match find_label(start) {
+                Some(start) => (
+                    start,
+                    end.as_deref()
+                        .and_then(find_label)
+                        .unwrap_or(program.tokens.len()),
+                ),
+                None => (0, 0),
+            },
+            None => (0, program.tokens.len()),
+        };
+
+        Tracer {
+            config,
+            index_range,
+            written: 0,
+        }
+    }
+
+    ///Whether the token at `index` should be traced. Checked before the token executes so the stacks only keep journals when needed
+    pub fn wants(&self, index: usize, token: &TokenType) -> bool {
+        if let Some(limit) = self.config.limit {
+            if self.written >= limit {
+                return false;
+            }
+        }
+        if index < self.index_range.0 || index >= self.index_range.1 {
+            return false;
+        }
+        let kinds = &self.config.filter.kinds;
+        kinds.is_empty() || kinds.iter().any(|k| k == token.kind())
+    }
+
+    ///Writes the record of a single executed token
+    pub fn record(
+        &mut self,
+        step: usize,
+        index: usize,
+        span: Option<Span>,
+        token: &TokenType,
+        journals: &[Journal; 3],
+        heights: [usize; 3],
+    ) -> Result<(), io::Error> {
+        let span = match span {
+            Some(s) => format!(
+                "{{\"line\":{},\"col\":{},\"len\":{}}}",
+                s.line, s.col, s.len
+            ),
+            None => "null".to_string(),
+        };
+
+        //Only stacks which were changed are included
+        let values = |get: fn(&Journal) -> &Vec<Value>| {
+            let entries: Vec<String> = journals
+                .iter()
+                .enumerate()
+                .filter(|(_, j)| !get(j).is_empty())
+                .map(|(stack, j)| {
+                    let values: Vec<String> = get(j).iter().map(|v| v.to_string()).collect();
+                    format!("\"{}\":[{}]", STACK_NAMES[stack], values.join(","))
+                })
+                .collect();
+            format!("{{{}}}", entries.join(","))
+        };
+
+        writeln!(
+            self.config.writer,
+            "{{\"step\":{},\"index\":{},\"span\":{},\"kind\":\"{}\",\"popped\":{},\"pushed\":{},\"heights\":[{},{},{}]}}",
+            step,
+            index,
+            span,
+            token.kind(),
+            values(|j| &j.popped),
+            values(|j| &j.pushed),
+            heights[0],
+            heights[1],
+            heights[2]
+        )?;
+        self.written += 1;
+
+        Ok(())
+    }
+
+    pub fn finish(&mut self) -> Result<(), io::Error> {
+        self.config.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, io, rc::Rc};
+
+    use super::TraceConfig;
+    use crate::{
+        interpreter::{run_from_string, RunOptions},
+        vfs::VirtualFileSystem,
+    };
+
+    ///A writer whose contents can still be read after it has been given away
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn trace(source: &str, opt_level: usize, config: impl FnOnce(&mut TraceConfig)) -> Vec<String> {
+        let buffer = SharedBuffer::default();
+        let mut trace = TraceConfig::new(Box::new(buffer.clone()));
+        config(&mut trace);
+
+        run_from_string(
+            source.to_string(),
+            Box::new(VirtualFileSystem::new()),
+            RunOptions {
+                trace: Some(trace),
+                opt_level,
+                ..Default::default()
+            },
+        );
+
+        let out = String::from_utf8(buffer.0.borrow().clone()).unwrap();
+        out.lines().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn records() {
+        let records = trace("push:2 move:C:A push:3 move:C:B +", 0, |_| ());
+        assert_eq!(records.len(), 6);
+        assert_eq!(
+            records[4],
+            "{\"step\":4,\"index\":4,\"span\":{\"line\":1,\"col\":33,\"len\":1},\"kind\":\"Add\",\"popped\":{\"A\":[2],\"B\":[3]},\"pushed\":{\"C\":[5]},\"heights\":[0,0,1]}"
+        );
+    }
+
+    #[test]
+    fn filters() {
+        let source = "push:3 move:C:A\nlabel:loop\npush:1 move:C:B - move:C:A\ncopy:A:C jump:loop\nlabel:after\nexit";
+
+        let records = trace(source, 0, |t| t.filter.kinds = vec!["Jump".to_string()]);
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|r| r.contains("\"kind\":\"Jump\"")));
+
+        let records = trace(source, 0, |t| {
+            t.filter.label_range = Some(("after".to_string(), None));
+        });
+        assert_eq!(records.len(), 3);
+        assert!(records[2].contains("\"kind\":\"Exit\""));
+
+        //Optimizing keeps the labels while they're needed to find the range
+        let records = trace(source, 1, |t| {
+            t.filter.label_range = Some(("loop".to_string(), Some("after".to_string())));
+        });
+        assert_eq!(records.len(), 12);
+        assert!(records[0].contains("\"kind\":\"Label\""));
+
+        let records = trace(source, 0, |t| t.limit = Some(4));
+        assert_eq!(records.len(), 4);
+    }
+}
@@ -0,0 +1,549 @@
+use std::{
+    cmp::Ordering,
+    fmt::Display,
+    ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Rem, Shl, Shr, Sub},
+};
+
+use num::{bigint::BigInt, ToPrimitive, Zero};
+
+///A stack value. Values which fit in an `i64` are stored inline, and only promoted to a `BigInt` when a result overflows.
+/// A `Big` never holds a value which would fit in a `Small`, so results are demoted as soon as they fit again
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Small(i64),
+    Big(BigInt),
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Value {
+        Value::Small(n)
+    }
+}
+
+impl From<BigInt> for Value {
+    fn from(n: BigInt) -> Value {
+        match n.to_i64() {
+            Some(n) => Value::Small(n),
+            None => Value::Big(n),
+        }
+    }
+}
+
+impl From<&BigInt> for Value {
+    fn from(n: &BigInt) -> Value {
+        match n.to_i64() {
+            Some(n) => Value::Small(n),
+            None => Value::Big(n.clone()),
+        }
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Small(n) => write!(f, "{}", n),
+            Value::Big(n) => write!(f, "{}", n),
+        }
+    }
+}
+
+///Implements an operator with a fast path for two small values, falling back to `BigInt` when `$checked` returns `None`
+macro_rules! binary_op {
+    ($trait:ident, $name:ident, $checked:expr, $big:expr) => {
+        impl $trait for Value {
+            type Output = Value;
+
+            fn $name(self, rhs: Value) -> Value {
+                if let (Value::Small(a), Value::Small(b)) = (&self, &rhs) {
+                    let checked: fn(i64, i64) -> Option<i64> = $checked;
+                    if let Some(n) = checked(*a, *b) {
+                        return Value::Small(n);
+                    }
+                }
+                let big: fn(BigInt, BigInt) -> BigInt = $big;
+                Value::from(big(self.into_bigint(), rhs.into_bigint()))
+            }
+        }
+    };
+}
+
+impl Value {
+    pub fn zero() -> Value {
+        Value::Small(0)
+    }
+
+    pub fn into_bigint(self) -> BigInt {
+        match self {
+            Value::Small(n) => BigInt::from(n),
+            Value::Big(n) => n,
+        }
+    }
+
+    pub fn to_bigint(&self) -> BigInt {
+        match self {
+            Value::Small(n) => BigInt::from(*n),
+            Value::Big(n) => n.clone(),
+        }
+    }
+
+    pub fn to_u8(&self) -> Option<u8> {
+        match self {
+            Value::Small(n) => u8::try_from(*n).ok(),
+            Value::Big(_) => None,
+        }
+    }
+
+    pub fn to_i64(&self) -> Option<i64> {
+        match self {
+            Value::Small(n) => Some(*n),
+            Value::Big(_) => None,
+        }
+    }
+
+    pub fn is_positive(&self) -> bool {
+        match self {
+            Value::Small(n) => *n > 0,
+            Value::Big(n) => *n > BigInt::zero(),
+        }
+    }
+}
+
+binary_op!(Add, add, |a, b| a.checked_add(b), |a, b| a + b);
+binary_op!(Sub, sub, |a, b| a.checked_sub(b), |a, b| a - b);
+binary_op!(Mul, mul, |a, b| a.checked_mul(b), |a, b| a * b);
+//Division by zero falls back to BigInt, which panics just like before
+binary_op!(Div, div, |a, b| a.checked_div(b), |a, b| a / b);
+binary_op!(Rem, rem, |a, b| a.checked_rem(b), |a, b| a % b);
+binary_op!(BitAnd, bitand, |a, b| Some(a & b), |a, b| a & b);
+binary_op!(BitOr, bitor, |a, b| Some(a | b), |a, b| a | b);
+binary_op!(BitXor, bitxor, |a, b| Some(a ^ b), |a, b| a ^ b);
+binary_op!(
+    Shr,
+    shr,
+    |a, b| if (0..64).contains(&b) {
+        Some(a >> b)
+    } else {
+        None
+    },
+    |a, b| a >> b.to_i128().expect("shift value too high")
+);
+binary_op!(
+    Shl,
+    shl,
+    |a, b| {
+        if !(0..64).contains(&b) {
+            return None;
+        }
+        //The shift overflowed if shifting back doesn't give the original value
+        let shifted = a << b;
+        if shifted >> b == a {
+            Some(shifted)
+        } else {
+            None
+        }
+    },
+    |a, b| a << b.to_i128().expect("shift value too high")
+);
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Value) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Value) -> Ordering {
+        match (self, other) {
+            (Value::Small(a), Value::Small(b)) => a.cmp(b),
+            _ => self.to_bigint().cmp(&other.to_bigint()),
+        }
+    }
+}
+
+///How the interpreter treats integers. `BigInt` never overflows, while the 64 bit models keep every value in an `i64`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NumericModel {
+    ///Arbitrary precision
+    #[default]
+    BigInt,
+    ///Two's complement wrapping. Shift amounts are masked to their low 6 bits
+    Wrap64,
+    ///A runtime error on any overflow, or on a shift amount outside of 0-63
+    Checked64,
+}
+
+impl NumericModel {
+    pub fn from_name(s: &str) -> Option<NumericModel> {
+        match s {
+            "bigint" => Some(NumericModel::BigInt),
+            "wrap64" => Some(NumericModel::Wrap64),
+            "checked64" => Some(NumericModel::Checked64),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NumericModel::BigInt => "bigint",
+            NumericModel::Wrap64 => "wrap64",
+            NumericModel::Checked64 => "checked64",
+        }
+    }
+
+    ///Converts a constant from the source (the argument of `push`) to a value in this model
+    pub fn constant(&self, n: &BigInt) -> Result<Value, ArithmeticError> {
+        let value = Value::from(n);
+        match self {
+            NumericModel::BigInt => Ok(value),
+            NumericModel::Wrap64 => Ok(Value::Small(wrap(&value))),
+            NumericModel::Checked64 => match value {
+                Value::Small(_) => Ok(value),
+                Value::Big(_) => Err(ArithmeticError::ConstantOutOfRange),
+            },
+        }
+    }
+
+    ///Applies a binary operator to `a` (from stack A) and `b` (from stack B)
+    pub fn apply(&self, op: BinaryOp, a: Value, b: Value) -> Result<Value, ArithmeticError> {
+        //Comparisons can't overflow, and behave the same in every model
+        if let Some(ordering) = op.comparison() {
+            return Ok(Value::from(ordering.contains(&a.cmp(&b)) as i64));
+        }
+        match self {
+            NumericModel::BigInt => apply_bigint(op, a, b),
+            NumericModel::Wrap64 => Ok(Value::Small(apply_wrap64(op, wrap(&a), wrap(&b))?)),
+            NumericModel::Checked64 => {
+                let (a, b) = match (a.to_i64(), b.to_i64()) {
+                    (Some(a), Some(b)) => (a, b),
+                    _ => return Err(ArithmeticError::Overflow(op)),
+                };
+                Ok(Value::Small(apply_checked64(op, a, b)?))
+            }
+        }
+    }
+}
+
+///The binary operators, which pop A and B and push their result to C
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Equal,
+    LessThan,
+    LessThanOrEqual,
+    GreaterThan,
+    GreaterThanOrEqual,
+    BitAnd,
+    BitOr,
+    BitXor,
+    BitRightShift,
+    BitLeftShift,
+}
+
+impl BinaryOp {
+    ///The orderings of `a.cmp(&b)` for which a comparison operator gives 1
+    fn comparison(&self) -> Option<&'static [Ordering]> {
+        match self {
+            BinaryOp::Equal => Some(&[Ordering::Equal]),
+            BinaryOp::LessThan => Some(&[Ordering::Less]),
+            BinaryOp::LessThanOrEqual => Some(&[Ordering::Less, Ordering::Equal]),
+            BinaryOp::GreaterThan => Some(&[Ordering::Greater]),
+            BinaryOp::GreaterThanOrEqual => Some(&[Ordering::Greater, Ordering::Equal]),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticError {
+    Overflow(BinaryOp),
+    DivideByZero,
+    ShiftOutOfRange,
+    ///A `push` constant which doesn't fit in the numeric model
+    ConstantOutOfRange,
+}
+
+impl Display for ArithmeticError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ArithmeticError::Overflow(op) => write!(f, "integer overflow in {:?}", op),
+            ArithmeticError::DivideByZero => write!(f, "division by zero"),
+            ArithmeticError::ShiftOutOfRange => write!(f, "shift amount out of range"),
+            ArithmeticError::ConstantOutOfRange => write!(f, "constant does not fit in 64 bits"),
+        }
+    }
+}
+
+///Truncates a value to its low 64 bits, as a two's complement `i64`
+fn wrap(n: &Value) -> i64 {
+    match n {
+        Value::Small(n) => *n,
+        Value::Big(n) => {
+            let low: BigInt = n & BigInt::from(u64::MAX);
+            low.to_u64().expect("masked value fits in a u64") as i64
+        }
+    }
+}
+
+fn apply_bigint(op: BinaryOp, a: Value, b: Value) -> Result<Value, ArithmeticError> {
+    Ok(match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide | BinaryOp::Modulo if b == Value::zero() => {
+            return Err(ArithmeticError::DivideByZero)
+        }
+        BinaryOp::Divide => a / b,
+        BinaryOp::Modulo => a % b,
+        BinaryOp::BitAnd => a & b,
+        BinaryOp::BitOr => a | b,
+        BinaryOp::BitXor => a ^ b,
+        //Negative shifts have no meaning for an unbounded integer
+        BinaryOp::BitRightShift | BinaryOp::BitLeftShift
+            if b < Value::zero() || b.to_i64().is_none() =>
+        {
+            return Err(ArithmeticError::ShiftOutOfRange)
+        }
+        BinaryOp::BitRightShift => a >> b,
+        BinaryOp::BitLeftShift => a << b,
+        _ => unreachable!("comparisons are handled by NumericModel::apply"),
+    })
+}
+
+fn apply_wrap64(op: BinaryOp, a: i64, b: i64) -> Result<i64, ArithmeticError> {
+    Ok(match op {
+        BinaryOp::Add => a.wrapping_add(b),
+        BinaryOp::Subtract => a.wrapping_sub(b),
+        BinaryOp::Multiply => a.wrapping_mul(b),
+        BinaryOp::Divide | BinaryOp::Modulo if b == 0 => return Err(ArithmeticError::DivideByZero),
+        BinaryOp::Divide => a.wrapping_div(b),
+        BinaryOp::Modulo => a.wrapping_rem(b),
+        BinaryOp::BitAnd => a & b,
+        BinaryOp::BitOr => a | b,
+        BinaryOp::BitXor => a ^ b,
+        BinaryOp::BitRightShift => a >> (b & 63),
+        BinaryOp::BitLeftShift => a << (b & 63),
+        _ => unreachable!("comparisons are handled by NumericModel::apply"),
+    })
+}
+
+fn apply_checked64(op: BinaryOp, a: i64, b: i64) -> Result<i64, ArithmeticError> {
+    let overflow = ArithmeticError::Overflow(op);
+    match op {
+        BinaryOp::Add => a.checked_add(b).ok_or(overflow),
+        BinaryOp::Subtract => a.checked_sub(b).ok_or(overflow),
+        BinaryOp::Multiply => a.checked_mul(b).ok_or(overflow),
+        BinaryOp::Divide | BinaryOp::Modulo if b == 0 => Err(ArithmeticError::DivideByZero),
+        BinaryOp::Divide => a.checked_div(b).ok_or(overflow),
+        BinaryOp::Modulo => a.checked_rem(b).ok_or(overflow),
+        BinaryOp::BitAnd => Ok(a & b),
+        BinaryOp::BitOr => Ok(a | b),
+        BinaryOp::BitXor => Ok(a ^ b),
+        BinaryOp::BitRightShift | BinaryOp::BitLeftShift if !(0..64).contains(&b) => {
+            Err(ArithmeticError::ShiftOutOfRange)
+        }
+        BinaryOp::BitRightShift => Ok(a >> b),
+        BinaryOp::BitLeftShift => {
+            //Bits (including the sign) were lost if shifting back doesn't give the original value
+            let shifted = a << b;
+            if shifted >> b == a {
+                Ok(shifted)
+            } else {
+                Err(overflow)
+            }
+        }
+        _ => unreachable!("comparisons are handled by NumericModel::apply"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use num::{bigint::BigInt, ToPrimitive};
+
+    use super::{ArithmeticError, BinaryOp, NumericModel, Value};
+
+    ///A small xorshift generator, so the property tests are reproducible without extra dependencies
+    struct Rng(u64);
+
+    impl Rng {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        ///Generates values clustered around the edges of the i64 range, as well as small and huge ones
+        fn value(&mut self) -> BigInt {
+            let n = self.next();
+            match n % 6 {
+                0 => BigInt::from(i64::MAX) - BigInt::from(n % 4),
+                1 => BigInt::from(i64::MIN) + BigInt::from(n % 4),
+                2 => BigInt::from((n % 200) as i64 - 100),
+                3 => BigInt::from(self.next() as i64),
+                4 => BigInt::from(self.next() as i64) * BigInt::from(self.next() as i64),
+                _ => -(BigInt::from(u64::MAX) + BigInt::from(self.next())),
+            }
+        }
+    }
+
+    fn assert_normalized(v: &Value) {
+        if let Value::Big(n) = v {
+            assert!(n.to_i64().is_none(), "{} should have been demoted", n);
+        }
+    }
+
+    #[test]
+    fn matches_bigint() {
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+
+        for _ in 0..20000 {
+            let a = rng.value();
+            let b = rng.value();
+            let (va, vb) = (Value::from(&a), Value::from(&b));
+            assert_normalized(&va);
+
+            let results = [
+                (va.clone() + vb.clone(), &a + &b),
+                (va.clone() - vb.clone(), &a - &b),
+                (va.clone() * vb.clone(), &a * &b),
+                (va.clone() & vb.clone(), &a & &b),
+                (va.clone() | vb.clone(), &a | &b),
+                (va.clone() ^ vb.clone(), &a ^ &b),
+            ];
+            for (v, n) in results {
+                assert_normalized(&v);
+                assert_eq!(v.into_bigint(), n, "{} and {}", a, b);
+            }
+
+            if b != BigInt::from(0) {
+                let div = va.clone() / vb.clone();
+                let rem = va.clone() % vb.clone();
+                assert_normalized(&div);
+                assert_normalized(&rem);
+                assert_eq!(div.into_bigint(), &a / &b);
+                assert_eq!(rem.into_bigint(), &a % &b);
+            }
+
+            let shift = BigInt::from(rng.next() % 80);
+            let vshift = Value::from(&shift);
+            let shl = va.clone() << vshift.clone();
+            let shr = va.clone() >> vshift;
+            assert_normalized(&shl);
+            assert_eq!(shl.into_bigint(), &a << shift.to_i128().unwrap());
+            assert_eq!(shr.into_bigint(), &a >> shift.to_i128().unwrap());
+
+            assert_eq!(va.cmp(&vb), a.cmp(&b));
+            assert_eq!(va.to_string(), a.to_string());
+            assert_eq!(va.is_positive(), a > BigInt::from(0));
+        }
+    }
+
+    const MODELS: [NumericModel; 3] = [
+        NumericModel::BigInt,
+        NumericModel::Wrap64,
+        NumericModel::Checked64,
+    ];
+    const MAX: &str = "9223372036854775807";
+    const MIN: &str = "-9223372036854775808";
+
+    fn value(s: &str) -> Value {
+        Value::from(BigInt::from_str(s).unwrap())
+    }
+
+    ///The conformance suite: each case gives the expected result under bigint, wrap64 and checked64. `None` is a runtime error
+    #[test]
+    fn numeric_models() {
+        use BinaryOp::*;
+
+        #[rustfmt::skip]
+        let cases: &[(BinaryOp, &str, &str, [Option<&str>; 3])] = &[
+            (Add, "2", "3", [Some("5"), Some("5"), Some("5")]),
+            (Add, MAX, "1", [Some("9223372036854775808"), Some(MIN), None]),
+            (Subtract, MIN, "1", [Some("-9223372036854775809"), Some(MAX), None]),
+            (Multiply, "4294967296", "4294967296", [Some("18446744073709551616"), Some("0"), None]),
+            (Multiply, "-3", "7", [Some("-21"), Some("-21"), Some("-21")]),
+            (Divide, "-7", "2", [Some("-3"), Some("-3"), Some("-3")]),
+            (Divide, MIN, "-1", [Some("9223372036854775808"), Some(MIN), None]),
+            (Divide, "1", "0", [None, None, None]),
+            (Modulo, "-7", "2", [Some("-1"), Some("-1"), Some("-1")]),
+            (Modulo, MIN, "-1", [Some("0"), Some("0"), None]),
+            (Modulo, "1", "0", [None, None, None]),
+            (BitAnd, "-1", "255", [Some("255"), Some("255"), Some("255")]),
+            (BitOr, MIN, "1", [Some("-9223372036854775807"), Some("-9223372036854775807"), Some("-9223372036854775807")]),
+            (BitXor, "-1", MAX, [Some(MIN), Some(MIN), Some(MIN)]),
+            (BitLeftShift, "1", "3", [Some("8"), Some("8"), Some("8")]),
+            (BitLeftShift, "1", "63", [Some("9223372036854775808"), Some(MIN), None]),
+            (BitLeftShift, "1", "64", [Some("18446744073709551616"), Some("1"), None]),
+            (BitLeftShift, "-1", "63", [Some(MIN), Some(MIN), Some(MIN)]),
+            (BitLeftShift, "1", "-1", [None, Some(MIN), None]),
+            (BitRightShift, "-16", "2", [Some("-4"), Some("-4"), Some("-4")]),
+            (BitRightShift, MIN, "64", [Some("-1"), Some(MIN), None]),
+            (BitRightShift, "5", "65", [Some("0"), Some("2"), None]),
+            (Equal, "3", "3", [Some("1"), Some("1"), Some("1")]),
+            (LessThan, MIN, MAX, [Some("1"), Some("1"), Some("1")]),
+            (LessThanOrEqual, "4", "3", [Some("0"), Some("0"), Some("0")]),
+            (GreaterThan, "-1", "0", [Some("0"), Some("0"), Some("0")]),
+            (GreaterThanOrEqual, "0", "0", [Some("1"), Some("1"), Some("1")]),
+        ];
+
+        for (op, a, b, expected) in cases {
+            for (model, expected) in MODELS.iter().zip(expected) {
+                let result = model.apply(*op, value(a), value(b));
+                assert_eq!(
+                    result.ok(),
+                    expected.map(value),
+                    "{:?} {} {} under {}",
+                    op,
+                    a,
+                    b,
+                    model.name()
+                );
+            }
+        }
+
+        let huge = BigInt::from_str("18446744073709551615").unwrap();
+        assert_eq!(NumericModel::BigInt.constant(&huge), Ok(Value::from(&huge)));
+        assert_eq!(NumericModel::Wrap64.constant(&huge), Ok(Value::from(-1)));
+        assert_eq!(
+            NumericModel::Checked64.constant(&huge),
+            Err(ArithmeticError::ConstantOutOfRange)
+        );
+    }
+
+    ///Wrapping results must match BigInt results truncated to 64 bits
+    #[test]
+    fn wrap64_matches_truncated_bigint() {
+        let mut rng = Rng(0x9E3779B97F4A7C15);
+        let truncate =
+            |n: BigInt| Value::from(((n & BigInt::from(u64::MAX)).to_u64().unwrap()) as i64);
+
+        for _ in 0..5000 {
+            let a = Value::from(rng.next() as i64);
+            let b = Value::from(rng.next() as i64);
+            for op in [BinaryOp::Add, BinaryOp::Subtract, BinaryOp::Multiply] {
+                let big = NumericModel::BigInt
+                    .apply(op, a.clone(), b.clone())
+                    .unwrap();
+                let wrapped = NumericModel::Wrap64
+                    .apply(op, a.clone(), b.clone())
+                    .unwrap();
+                assert_eq!(wrapped, truncate(big.clone().into_bigint()));
+
+                //Checked results either match exactly or report the overflow
+                match NumericModel::Checked64.apply(op, a.clone(), b.clone()) {
+                    Ok(n) => assert_eq!(n, big),
+                    Err(e) => {
+                        assert_eq!(e, ArithmeticError::Overflow(op));
+                        assert!(big.to_i64().is_none());
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,13 +1,17 @@
 use std::{
     cell::RefCell,
-    fmt::Debug,
-    fs::{read_dir, File},
-    io,
+    collections::BTreeSet,
+    fmt::{Debug, Display},
+    fs::{read_dir, File, OpenOptions},
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
     rc::Rc,
 };
 
+use crate::tar::{read_tar, write_tar, TarEntry};
+
 pub trait FileSystem {
-    ///Lists all files and subdirectories in the given path.
+    ///Lists all files and subdirectories in the given path, sorted by name.
     /// In other words, lists the children of path
     fn ls(&self, path: &str) -> Result<Vec<String>, io::Error>;
 
@@ -16,22 +20,208 @@ pub trait FileSystem {
     ///Opens a file stream using the given path
     fn open_file_stream(&mut self, path: &str) -> Result<Box<dyn FileStream>, io::Error>;
 
+    ///Opens a file stream on a file which already exists. Unlike `create_file_stream`, the file is never emptied
+    fn open_file_stream_with(
+        &mut self,
+        path: &str,
+        mode: FileStreamMode,
+    ) -> Result<Box<dyn FileStream>, io::Error>;
+
     fn remove_file(&mut self, path: &str) -> Result<(), io::Error>;
+
+    ///Creates an empty directory. The directory it goes in must already exist
+    fn create_dir(&mut self, path: &str) -> Result<(), io::Error>;
+
+    ///Removes a directory. Unless `recursive` is set, the directory must be empty
+    fn remove_dir(&mut self, path: &str, recursive: bool) -> Result<(), io::Error>;
+
+    ///Moves a file or directory, replacing whatever is at `to` if it's a file or an empty directory of the same kind
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), io::Error>;
+
+    fn exists(&self, path: &str) -> bool;
+
+    fn metadata(&self, path: &str) -> Result<Metadata, io::Error>;
+}
+
+pub trait FileStream: io::Write + io::Read + io::Seek {}
+
+///What a file stream can do with its file. Streams start at the beginning of the file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileStreamMode {
+    ///Writes at the stream's position, overwriting what is there
+    WriteOnly,
+    ReadOnly,
+    ///Reads and writes at the same position
+    ReadWrite,
+    ///Writes always go to the end of the file, wherever the stream has been moved to
+    Append,
+}
+
+impl FileStreamMode {
+    fn reads(self) -> bool {
+        matches!(self, FileStreamMode::ReadOnly | FileStreamMode::ReadWrite)
+    }
+
+    fn writes(self) -> bool {
+        self != FileStreamMode::ReadOnly
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    ///The length of a file in bytes. Directories have a size of 0
+    pub size: u64,
+    pub is_dir: bool,
+}
+
+//Paths
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    ///`..`, which goes up to the parent directory. Going up from the root stays at the root
+    Parent,
+    Name(String),
+}
+
+///A path within a `FileSystem`. Every implementation parses paths with this, so a path means the same thing whichever one is used.
+/// Segments are separated by `/`, and empty segments and `.` are ignored. Paths always start at the root, so a leading `/` changes nothing
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VfsPath {
+    pub segments: Vec<PathSegment>,
+}
+
+impl VfsPath {
+    pub fn parse(path: &str) -> VfsPath {
+        let segments = path
+            .split('/')
+            .filter(|s| !s.is_empty() && *s != ".")
+            .map(|s| match s {
+                ".." => PathSegment::Parent,
+                _ => PathSegment::Name(s.to_string()),
+            })
+            .collect();
+        VfsPath { segments }
+    }
+
+    ///The name of the file or directory the path points at, unless it ends with `..` or is empty
+    pub fn file_name(&self) -> Option<&str> {
+        match self.segments.last() {
+            Some(PathSegment::Name(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    ///The path without its final segment
+    pub fn parent(&self) -> VfsPath {
+        let mut segments = self.segments.clone();
+        segments.pop();
+        VfsPath { segments }
+    }
+}
+
+impl Display for VfsPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for segment in &self.segments {
+            match segment {
+                PathSegment::Parent => write!(f, "/..")?,
+                PathSegment::Name(name) => write!(f, "/{}", name)?,
+            }
+        }
+        Ok(())
+    }
 }
 
-pub trait FileStream: io::Write + io::Read {}
+///The error for a path which names no file, such as one ending with `..`
+fn no_file_name(path: &VfsPath) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("\"{}\" doesn't name a file", path),
+    )
+}
 
 //Real Local File System
 
-///Acts as a virtual file system performing actions relative to the root directory
+///Acts as a virtual file system performing actions relative to the root directory.
+/// Paths can't reach anything outside of the root: `..` stops at the root, and symlinks which lead out of it are refused
+#[derive(Clone)]
 pub struct RealLocalFileSystem {
     pub root: String,
 }
 
+impl RealLocalFileSystem {
+    ///Turns a path into a real path under the root, failing with `PermissionDenied` if a symlink would take it outside of the root
+    fn resolve(&self, path: &str) -> Result<PathBuf, io::Error> {
+        let root = Path::new(&self.root);
+
+        let vfs_path = VfsPath::parse(path);
+        let mut names: Vec<&str> = Vec::new();
+        for segment in &vfs_path.segments {
+            match segment {
+                PathSegment::Parent => {
+                    //Like in the virtual file system, `..` has to come from a directory which exists
+                    if !names
+                        .iter()
+                        .fold(root.to_path_buf(), |p, n| p.join(n))
+                        .is_dir()
+                    {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "Directory not found",
+                        ));
+                    }
+                    names.pop();
+                }
+                PathSegment::Name(name) => names.push(name),
+            }
+        }
+
+        //Symlinks can point anywhere, so the part of the path which already exists is checked after following them.
+        //Anything after that doesn't exist yet, so it can't be a symlink
+        let mut existing = root.to_path_buf();
+        let mut rest = names.as_slice();
+        while let Some((first, tail)) = rest.split_first() {
+            let next = existing.join(first);
+            if next.symlink_metadata().is_err() {
+                break;
+            }
+            existing = next;
+            rest = tail;
+        }
+
+        //A dangling symlink can't be canonicalized, and creating a file through one could write anywhere
+        let denied = || {
+            io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!(
+                    "Permission denied. \"{}\" leads outside of the file system's root",
+                    path
+                ),
+            )
+        };
+        let existing = existing.canonicalize().map_err(|_| denied())?;
+        if !existing.starts_with(root.canonicalize()?) {
+            return Err(denied());
+        }
+        Ok(rest.iter().fold(existing, |p, s| p.join(s)))
+    }
+
+    ///Like `resolve`, but refuses the root itself, which can't be removed or moved
+    fn resolve_below_root(&self, path: &str) -> Result<PathBuf, io::Error> {
+        let resolved = self.resolve(path)?;
+        if resolved == Path::new(&self.root).canonicalize()? {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot remove or move the root directory",
+            ));
+        }
+        Ok(resolved)
+    }
+}
+
 impl FileSystem for RealLocalFileSystem {
     fn ls(&self, path: &str) -> Result<Vec<String>, io::Error> {
         //Make the absolute path
-        let path = self.root.clone() + &path;
+        let path = self.resolve(path)?;
 
         //Read the directory
         let dir = match read_dir(path) {
@@ -44,13 +234,17 @@ impl FileSystem for RealLocalFileSystem {
         for d in dir {
             v.push(d.unwrap().file_name().to_string_lossy().to_string());
         }
+        v.sort();
 
         Ok(v)
     }
 
     fn create_file_stream(&self, path: &str) -> Result<Box<dyn FileStream>, io::Error> {
-        let path = self.root.clone() + &path;
-        println!("create: {}", path);
+        let vfs_path = VfsPath::parse(path);
+        if vfs_path.file_name().is_none() {
+            return Err(no_file_name(&vfs_path));
+        }
+        let path = self.resolve(path)?;
         match File::create(path) {
             Ok(f) => Ok(Box::new(RealLocalFileStream::from_file(f))),
             Err(e) => Err(e),
@@ -58,18 +252,76 @@ impl FileSystem for RealLocalFileSystem {
     }
 
     fn open_file_stream(&mut self, path: &str) -> Result<Box<dyn FileStream>, io::Error> {
-        println!("open: {}", &(self.root.clone() + path));
-        match RealLocalFileStream::new(&(self.root.clone() + path)) {
-            Ok(fs) => Ok(Box::new(fs)),
-            Err(e) => Err(e),
+        self.open_file_stream_with(path, FileStreamMode::ReadOnly)
+    }
+
+    fn open_file_stream_with(
+        &mut self,
+        path: &str,
+        mode: FileStreamMode,
+    ) -> Result<Box<dyn FileStream>, io::Error> {
+        let path = self.resolve(path)?;
+        //Directories can be opened as files on some platforms, but not in the virtual file system
+        if path.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Expected a file path, found a directory path",
+            ));
         }
+        let file = OpenOptions::new()
+            .read(mode.reads())
+            .write(mode.writes() && mode != FileStreamMode::Append)
+            .append(mode == FileStreamMode::Append)
+            .open(path)?;
+        Ok(Box::new(RealLocalFileStream::from_file(file)))
     }
 
     fn remove_file(&mut self, path: &str) -> Result<(), io::Error> {
-        let path = self.root.clone() + &path;
+        let path = self.resolve(path)?;
 
         std::fs::remove_file(path)
     }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), io::Error> {
+        let vfs_path = VfsPath::parse(path);
+        if vfs_path.file_name().is_none() {
+            return Err(no_file_name(&vfs_path));
+        }
+        std::fs::create_dir(self.resolve(path)?)
+    }
+
+    fn remove_dir(&mut self, path: &str, recursive: bool) -> Result<(), io::Error> {
+        let path = self.resolve_below_root(path)?;
+        if recursive {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_dir(path)
+        }
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), io::Error> {
+        let from = self.resolve_below_root(from)?;
+        let to_path = VfsPath::parse(to);
+        if to_path.file_name().is_none() {
+            return Err(no_file_name(&to_path));
+        }
+        std::fs::rename(from, self.resolve(to)?)
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.resolve(path) {
+            Ok(p) => p.exists(),
+            Err(_) => false,
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, io::Error> {
+        let metadata = std::fs::metadata(self.resolve(path)?)?;
+        Ok(Metadata {
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            is_dir: metadata.is_dir(),
+        })
+    }
 }
 
 pub struct RealLocalFileStream {
@@ -77,7 +329,7 @@ pub struct RealLocalFileStream {
 }
 
 impl RealLocalFileStream {
-    pub fn new(absolute_path: &str) -> Result<RealLocalFileStream, io::Error> {
+    pub fn new(absolute_path: impl AsRef<Path>) -> Result<RealLocalFileStream, io::Error> {
         let file = match File::open(absolute_path) {
             Ok(f) => f,
             Err(e) => return Err(e),
@@ -106,25 +358,17 @@ impl io::Write for RealLocalFileStream {
     }
 }
 
+impl io::Seek for RealLocalFileStream {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
 impl FileStream for RealLocalFileStream {}
 
 //Virtual File System
 //Note: only accepts '/' as a path delimiter
 
-///Takes an input path str and splits it into its component parts by using '/' as a delimiter
-fn split_path_str(path: &str) -> Vec<String> {
-    let mut v = Vec::new();
-
-    //Only accepts non-empty components
-    for s in path.split('/') {
-        if !s.is_empty() {
-            v.push(s.to_string());
-        }
-    }
-
-    v
-}
-
 ///Gets the parent of the given VirtualPath. A convenience function
 fn get_parent(vp: &Rc<RefCell<VirtualPath>>) -> Option<Rc<RefCell<VirtualPath>>> {
     match &*vp.borrow() {
@@ -140,6 +384,42 @@ fn get_name(vp: &Rc<RefCell<VirtualPath>>) -> String {
     }
 }
 
+///Whether the given VirtualPath is a directory. A convenience function
+fn is_dir(vp: &Rc<RefCell<VirtualPath>>) -> bool {
+    matches!(&*vp.borrow(), VirtualPath::Dir { .. })
+}
+
+///Removes the given VirtualPath from its parent's children. Fails for the root, which has no parent
+fn detach(vp: &Rc<RefCell<VirtualPath>>) -> Result<(), io::Error> {
+    let parent = get_parent(vp).ok_or_else(root_error)?;
+    let result = match &mut *parent.borrow_mut() {
+        VirtualPath::File { .. } => Err(io::Error::new(
+            io::ErrorKind::Other {},
+            "Parent of path was a file",
+        )),
+        VirtualPath::Dir { children, .. } => {
+            match children.iter().position(|c| Rc::ptr_eq(c, vp)) {
+                Some(i) => {
+                    children.remove(i);
+                    Ok(())
+                }
+                None => Err(io::Error::from(io::ErrorKind::NotFound {})),
+            }
+        }
+    };
+    result
+}
+
+fn root_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "Cannot remove or move the root directory",
+    )
+}
+
+///Keeps every file in memory. It can be loaded from and saved to a tar archive or a directory on disk.
+/// Clones share the same files
+#[derive(Clone)]
 pub struct VirtualFileSystem {
     root: Rc<RefCell<VirtualPath>>,
 }
@@ -155,144 +435,227 @@ impl VirtualFileSystem {
         }
     }
 
-    ///Gets either the directory or file at the given path if it is successfully found
-    fn get(&self, path: &str) -> Option<Rc<RefCell<VirtualPath>>> {
-        Self::get_from_head(&self.root, path)
+    ///Loads the files and directories in a tar archive. Directories which hold something don't need their own entries
+    pub fn from_tar(reader: impl Read) -> Result<VirtualFileSystem, io::Error> {
+        VirtualFileSystem::from_entries(read_tar(reader)?)
     }
 
-    fn get_from_head(
-        search_head: &Rc<RefCell<VirtualPath>>,
-        path: &str,
-    ) -> Option<Rc<RefCell<VirtualPath>>> {
-        let path = split_path_str(path);
-        Self::get_from_head_path_segments(search_head, path)
+    ///Saves every file and directory as a tar archive, which `from_tar` loads back
+    pub fn to_tar(&self, writer: impl Write) -> Result<(), io::Error> {
+        write_tar(writer, &self.entries())
     }
 
-    ///Gets either the directory or file at the given path relative to the search head if it is successfully found
-    fn get_from_head_path_segments(
-        search_head: &Rc<RefCell<VirtualPath>>,
-        path: Vec<String>,
-    ) -> Option<Rc<RefCell<VirtualPath>>> {
-        let mut search_head = search_head.clone();
+    ///Loads a copy of everything in a directory on disk. Symlinks are refused
+    pub fn from_dir(path: impl AsRef<Path>) -> Result<VirtualFileSystem, io::Error> {
+        fn visit(dir: &Path, prefix: &str, entries: &mut Vec<TarEntry>) -> Result<(), io::Error> {
+            let mut children: Vec<_> = read_dir(dir)?.collect::<Result<_, _>>()?;
+            children.sort_by_key(|c| c.file_name());
+            for child in children {
+                let path = prefix.to_string() + &child.file_name().to_string_lossy();
+                let kind = child.file_type()?;
+                if kind.is_dir() {
+                    entries.push(TarEntry::Dir(path.clone()));
+                    visit(&child.path(), &(path + "/"), entries)?;
+                } else if kind.is_file() {
+                    entries.push(TarEntry::File(path, std::fs::read(child.path())?));
+                } else {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!("\"{}\" is not a file or directory", path),
+                    ));
+                }
+            }
+            Ok(())
+        }
 
-        //Iterate through each path segment and find the next matching child until reaching the end of all path segments
-        for path_segment in path {
-            match &*(*search_head.clone()).borrow() {
-                //Current search head
+        let mut entries = Vec::new();
+        visit(path.as_ref(), "", &mut entries)?;
+        VirtualFileSystem::from_entries(entries)
+    }
 
-                //If the head reached a file and there is remaining path to search, it failed
-                VirtualPath::File { .. } => return None,
-                VirtualPath::Dir { name, children, .. } => {
-                    //Find the child with the matching name and set it as the search head
-                    let mut matching_child_found: bool = false;
-                    for c in children {
-                        //Next search head
+    ///Writes every file and directory into a directory on disk, which is created if it doesn't exist. Files already there are replaced
+    pub fn to_dir(&self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let path = path.as_ref();
+        std::fs::create_dir_all(path)?;
+        for entry in self.entries() {
+            match entry {
+                TarEntry::Dir(p) => std::fs::create_dir_all(path.join(p))?,
+                TarEntry::File(p, data) => std::fs::write(path.join(p), data)?,
+            }
+        }
+        Ok(())
+    }
 
-                        match &*(**c).borrow() {
-                            VirtualPath::File { name, .. } | VirtualPath::Dir { name, .. } => {
-                                //If the child's name matches the path segment, advance the search head and move on to the next path segment
-                                if *name == path_segment {
-                                    search_head = c.clone();
-                                    matching_child_found = true;
-                                    break;
-                                }
-                            }
-                        }
+    fn from_entries(entries: Vec<TarEntry>) -> Result<VirtualFileSystem, io::Error> {
+        let fs = VirtualFileSystem::new();
+        for entry in entries {
+            let path = VfsPath::parse(entry.path());
+            //Archives can come from anywhere, so they can't place anything by going up
+            if path.segments.contains(&PathSegment::Parent) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("\"{}\" goes up out of the archive", entry.path()),
+                ));
+            }
+            match entry {
+                TarEntry::Dir(_) => fs.create_dirs(&path)?,
+                TarEntry::File(_, data) => {
+                    fs.create_dirs(&path.parent())?;
+                    match &*fs.create_file(&path.to_string())?.borrow() {
+                        VirtualPath::File { data: d, .. } => *d.borrow_mut() = data,
+                        VirtualPath::Dir { .. } => unreachable!(),
                     }
+                }
+            }
+        }
+        Ok(fs)
+    }
 
-                    //If no matching child was found, return None. The search failed
-                    if !matching_child_found {
-                        return None;
+    ///Every file and directory, with each directory right before what is in it. Directories are listed by name
+    fn entries(&self) -> Vec<TarEntry> {
+        fn visit(vp: &Rc<RefCell<VirtualPath>>, prefix: &str, entries: &mut Vec<TarEntry>) {
+            if let VirtualPath::Dir { children, .. } = &*vp.borrow() {
+                let mut children = children.clone();
+                children.sort_by_key(get_name);
+                for child in &children {
+                    let path = prefix.to_string() + &get_name(child);
+                    match &*child.borrow() {
+                        VirtualPath::File { data, .. } => {
+                            entries.push(TarEntry::File(path, data.borrow().clone()))
+                        }
+                        VirtualPath::Dir { .. } => {
+                            entries.push(TarEntry::Dir(path.clone()));
+                            visit(child, &(path + "/"), entries);
+                        }
                     }
                 }
             }
         }
 
-        //If the path_segment search succeeded every single time, then the path was valid. The search succeeded
-        Some(search_head)
+        let mut entries = Vec::new();
+        visit(&self.root, "", &mut entries);
+        entries
     }
 
-    ///Creates a file with the given path and returns a reference to its VirtualPath.
-    /// Will override any existing file at the given path, emptying its data
-    fn create_file(&self, path: &str) -> Result<Rc<RefCell<VirtualPath>>, io::Error> {
-        let path_segments = split_path_str(path);
-
-        if path_segments.len() == 0 {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput {},
-                format!(
-                    "Cannot create file at \"{}\". The given path has no segments",
-                    path
-                ),
-            ));
+    ///Creates the directory at the path along with any of its parents which don't exist
+    fn create_dirs(&self, path: &VfsPath) -> Result<(), io::Error> {
+        for i in 1..=path.segments.len() {
+            let dir = VfsPath {
+                segments: path.segments[..i].to_vec(),
+            };
+            match self.get_path(&dir) {
+                Some(existing) if is_dir(&existing) => (),
+                Some(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("\"{}\" is not a directory", dir),
+                    ))
+                }
+                None => {
+                    self.add_child(&dir, |name, parent| VirtualPath::Dir {
+                        name,
+                        children: Vec::new(),
+                        parent: Some(parent),
+                    })?;
+                }
+            }
         }
+        Ok(())
+    }
 
-        let mut search_head = self.root.clone();
-
-        //Here are the iterative steps:
-        //1- Advance the search head by 1 level
-        //2- If the search head fails to advance, create a subdirectory under search_head to enable its advance and move search_head to that directory
-        //Finally:
-        //1- If the file already exists, clear its data
+    ///Gets either the directory or file at the given path if it is successfully found
+    fn get(&self, path: &str) -> Option<Rc<RefCell<VirtualPath>>> {
+        self.get_path(&VfsPath::parse(path))
+    }
 
-        //NOTE: First, all but the final path segment will be search/created as directories
-        //Then, the final path segment will be created as a file
+    ///Follows each segment of the path from the root, going up through `parent` links for `..`
+    fn get_path(&self, path: &VfsPath) -> Option<Rc<RefCell<VirtualPath>>> {
+        let mut search_head = self.root.clone();
+        for segment in &path.segments {
+            let next = match (segment, &*search_head.borrow()) {
+                //If the head reached a file and there is remaining path to search, it failed
+                (_, VirtualPath::File { .. }) => return None,
+                //The root is its own parent
+                (PathSegment::Parent, VirtualPath::Dir { parent, .. }) => {
+                    parent.clone().unwrap_or_else(|| search_head.clone())
+                }
+                (PathSegment::Name(name), VirtualPath::Dir { children, .. }) => {
+                    children.iter().find(|c| get_name(c) == *name)?.clone()
+                }
+            };
+            search_head = next;
+        }
+        Some(search_head)
+    }
 
-        for i in 0..(path_segments.len() - 1) {
-            let seg = &path_segments[i];
+    ///Adds a new child to the directory at `path`'s parent, named by its last segment.
+    /// The parent directory must already exist, and must not already have a child with that name
+    fn add_child(
+        &self,
+        path: &VfsPath,
+        child: impl FnOnce(String, Rc<RefCell<VirtualPath>>) -> VirtualPath,
+    ) -> Result<Rc<RefCell<VirtualPath>>, io::Error> {
+        let name = path.file_name().ok_or_else(|| no_file_name(path))?;
+        let parent = match self.get_path(&path.parent()) {
+            Some(p) => p,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Directory not found",
+                ))
+            }
+        };
 
-            //Search from the current search_head only advancing by 1 path_segment
-            match Self::get_from_head_path_segments(&search_head, vec![seg.clone()]) {
-                Some(new_head) => search_head = new_head.clone(),
-                //Since there wasn't a matching directory under this one, attempt to add a new subdirectory
-                None => {
-                    let new_dir = Rc::new(RefCell::new(VirtualPath::Dir {
-                        name: seg.clone(),
-                        children: Vec::with_capacity(1),
-                        parent: Some(search_head.clone()),
-                    }));
-                    match &mut *search_head.borrow_mut() {
-                        //Cannot add a subdirectory to a file. Return an error
-                        VirtualPath::File { .. } => {
-                            return Err(io::Error::new(
-                                io::ErrorKind::InvalidInput {},
-                                "One of the directories in the path was actually a file",
-                            ))
-                        }
-                        //Can add a subdirectory to a directory. Proceed as normal
-                        VirtualPath::Dir { children, .. } => children.push(new_dir.clone()),
-                    }
-                    //Set the search head to the new subdirectory
-                    search_head = new_dir;
+        let child = Rc::new(RefCell::new(child(name.to_string(), parent.clone())));
+        let result = match &mut *parent.borrow_mut() {
+            //Cannot add a child to a file. Return an error
+            VirtualPath::File { .. } => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "One of the directories in the path was actually a file",
+            )),
+            VirtualPath::Dir { children, .. } => {
+                if children.iter().any(|c| get_name(c) == name) {
+                    Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("\"{}\" already exists", path),
+                    ))
+                } else {
+                    children.push(child.clone());
+                    Ok(child)
                 }
             }
+        };
+        result
+    }
+
+    ///Creates a file with the given path and returns a reference to its VirtualPath.
+    /// The directory it goes in must already exist.
+    /// Will override any existing file at the given path, emptying its data
+    fn create_file(&self, path: &str) -> Result<Rc<RefCell<VirtualPath>>, io::Error> {
+        let path = VfsPath::parse(path);
+        if path.file_name().is_none() {
+            return Err(no_file_name(&path));
         }
 
-        //Creating the file from the final segment
-        {
-            let search_head_ref = &mut *search_head.borrow_mut();
-            match search_head_ref {
-                VirtualPath::File { .. } => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput {},
-                        "One of the directories in the path was actually a file",
-                    ))
+        if let Some(existing) = self.get_path(&path) {
+            return match &*existing.borrow() {
+                //The data is emptied in place, so streams which are already open on the file see the change
+                VirtualPath::File { data, .. } => {
+                    data.borrow_mut().clear();
+                    Ok(existing.clone())
                 }
-                VirtualPath::Dir { children, .. } => {
-                    //Create the file
-                    let file = VirtualPath::File {
-                        name: path_segments.last().unwrap().clone(),
-                        data: Rc::new(RefCell::new(Vec::new())),
-                        parent: search_head.clone(),
-                    };
-                    let file = Rc::new(RefCell::new(file));
-
-                    //Make the file one of the children and return it
-                    children.push(file.clone());
-                    return Ok(file);
-                }
-            }
+                VirtualPath::Dir { .. } => Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("Cannot create a file at \"{}\". It is a directory", path),
+                )),
+            };
         }
+
+        self.add_child(&path, |name, parent| VirtualPath::File {
+            name,
+            data: Rc::new(RefCell::new(Vec::new())),
+            parent,
+        })
     }
 }
 
@@ -314,6 +677,7 @@ impl FileSystem for VirtualFileSystem {
                             }
                         }
                     }
+                    v.sort();
 
                     Ok(v)
                 }
@@ -337,11 +701,19 @@ impl FileSystem for VirtualFileSystem {
     }
 
     fn open_file_stream(&mut self, path: &str) -> Result<Box<dyn FileStream>, io::Error> {
+        self.open_file_stream_with(path, FileStreamMode::ReadOnly)
+    }
+
+    fn open_file_stream_with(
+        &mut self,
+        path: &str,
+        mode: FileStreamMode,
+    ) -> Result<Box<dyn FileStream>, io::Error> {
         match self.get(path) {
             Some(vp) => match &*(*vp).borrow() {
                 VirtualPath::File { name, data, .. } => Ok(Box::new(VirtualFileStream {
                     file: vp.clone(),
-                    mode: FileStreamMode::ReadOnly,
+                    mode,
                     pointer_pos: 0,
                 })),
                 VirtualPath::Dir { name, children, .. } => Err(io::Error::new(
@@ -356,36 +728,170 @@ impl FileSystem for VirtualFileSystem {
     ///Removes a file from the VirtualFileSystem.
     /// This should never be called if there are active file streams on this file, but it won't stop you
     fn remove_file(&mut self, path: &str) -> Result<(), io::Error> {
-        let path_segments = split_path_str(path);
-
         match self.get(path) {
             Some(file) => {
-                let parent = get_parent(&file).unwrap();
-                let file_name = get_name(&file);
-
-                let parent_ref = &mut *parent.borrow_mut();
-                //Find the index of the file in the parent's list
-                match parent_ref {
-                    VirtualPath::File { .. } => Err(io::Error::new(
-                        io::ErrorKind::Other {},
-                        "Parent of path was a file",
-                    )),
-                    VirtualPath::Dir { children, .. } => {
-                        for i in 0..children.len() {
-                            //There should never be multiple files of the same name in a directory, so stop looking for more after removing one.
-                            if get_name(&children[i]) == file_name {
-                                children.remove(i);
-                                return Ok(());
-                            }
-                        }
-
-                        Err(io::Error::from(io::ErrorKind::NotFound {}))
-                    }
+                if is_dir(&file) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::IsADirectory,
+                        "Expected a file path, found a directory path",
+                    ));
                 }
+                detach(&file)
             }
             None => Err(io::Error::from(io::ErrorKind::NotFound {})),
         }
     }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), io::Error> {
+        self.add_child(&VfsPath::parse(path), |name, parent| VirtualPath::Dir {
+            name,
+            children: Vec::new(),
+            parent: Some(parent),
+        })?;
+        Ok(())
+    }
+
+    fn remove_dir(&mut self, path: &str, recursive: bool) -> Result<(), io::Error> {
+        let dir = match self.get(path) {
+            Some(d) => d,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Directory not found",
+                ))
+            }
+        };
+        if get_parent(&dir).is_none() {
+            return Err(root_error());
+        }
+        match &*dir.borrow() {
+            VirtualPath::File { .. } => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    "Expected a directory path, found a file path",
+                ))
+            }
+            VirtualPath::Dir { children, .. } => {
+                if !recursive && !children.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::DirectoryNotEmpty,
+                        format!("\"{}\" is not empty", path),
+                    ));
+                }
+            }
+        }
+        //The children go with the directory, since nothing else refers to them
+        detach(&dir)
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), io::Error> {
+        let node = match self.get(from) {
+            Some(n) => n,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+        };
+        if get_parent(&node).is_none() {
+            return Err(root_error());
+        }
+
+        let to = VfsPath::parse(to);
+        let name = to.file_name().ok_or_else(|| no_file_name(&to))?.to_string();
+        let parent = match self.get_path(&to.parent()) {
+            Some(p) if is_dir(&p) => p,
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    "One of the directories in the path was actually a file",
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Directory not found",
+                ))
+            }
+        };
+
+        //A directory can't be moved inside of itself
+        let mut ancestor = Some(parent.clone());
+        while let Some(a) = ancestor {
+            if Rc::ptr_eq(&a, &node) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Cannot move \"{}\" inside of itself", from),
+                ));
+            }
+            ancestor = get_parent(&a);
+        }
+
+        //Like on a real file system, a file can replace a file and a directory can replace an empty directory
+        if let Some(existing) = self.get_path(&to) {
+            if Rc::ptr_eq(&existing, &node) {
+                return Ok(());
+            }
+            match (is_dir(&node), &*existing.borrow()) {
+                (false, VirtualPath::Dir { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::IsADirectory,
+                        format!("\"{}\" is a directory", to),
+                    ))
+                }
+                (true, VirtualPath::File { .. }) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotADirectory,
+                        format!("\"{}\" is not a directory", to),
+                    ))
+                }
+                (true, VirtualPath::Dir { children, .. }) if !children.is_empty() => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::DirectoryNotEmpty,
+                        format!("\"{}\" is not empty", to),
+                    ))
+                }
+                _ => (),
+            }
+            detach(&existing)?;
+        }
+
+        detach(&node)?;
+        match &mut *node.borrow_mut() {
+            VirtualPath::File {
+                name: n, parent: p, ..
+            } => {
+                *n = name;
+                *p = parent.clone();
+            }
+            VirtualPath::Dir {
+                name: n, parent: p, ..
+            } => {
+                *n = name;
+                *p = Some(parent.clone());
+            }
+        }
+        if let VirtualPath::Dir { children, .. } = &mut *parent.borrow_mut() {
+            children.push(node.clone());
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.get(path).is_some()
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, io::Error> {
+        match self.get(path) {
+            Some(vp) => Ok(match &*vp.borrow() {
+                VirtualPath::File { data, .. } => Metadata {
+                    size: data.borrow().len() as u64,
+                    is_dir: false,
+                },
+                VirtualPath::Dir { .. } => Metadata {
+                    size: 0,
+                    is_dir: true,
+                },
+            }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+        }
+    }
 }
 
 //A path can either be a directory or a file
@@ -425,12 +931,6 @@ impl Debug for VirtualPath {
     }
 }
 
-#[derive(PartialEq)]
-enum FileStreamMode {
-    WriteOnly,
-    ReadOnly,
-}
-
 pub struct VirtualFileStream {
     file: Rc<RefCell<VirtualPath>>,
     mode: FileStreamMode,
@@ -452,7 +952,7 @@ impl VirtualFileStream {
 impl io::Read for VirtualFileStream {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         //If the stream can't read, return error
-        if self.mode != FileStreamMode::ReadOnly {
+        if !self.mode.reads() {
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
                 "Permission denied. Tried reading from write-only file stream",
@@ -481,11 +981,8 @@ impl io::Read for VirtualFileStream {
 
 impl io::Write for VirtualFileStream {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        //TMP
-        println!("{:#?}", self.file);
-
-        //If the stream can't read, return error
-        if self.mode != FileStreamMode::WriteOnly {
+        //If the stream can't write, return error
+        if !self.mode.writes() {
             return Err(io::Error::new(
                 io::ErrorKind::PermissionDenied,
                 "Permission denied. Tried writing from read-only file stream",
@@ -496,12 +993,18 @@ impl io::Write for VirtualFileStream {
         let data = self.get_data();
         let data = &mut *(*data).borrow_mut();
 
-        for i in 0..buf.len() {
-            data.push(buf[i]);
+        if self.mode == FileStreamMode::Append {
+            self.pointer_pos = data.len();
+        }
+        //Like a real file, writing past the end fills the gap with zeroes
+        let end = self.pointer_pos + buf.len();
+        if end > data.len() {
+            data.resize(end, 0);
         }
+        data[self.pointer_pos..end].copy_from_slice(buf);
 
-        //Move pointer to the current end of file, the pointer is where the next write will take place
-        self.pointer_pos = data.len();
+        //The pointer is where the next write will take place
+        self.pointer_pos = end;
 
         Ok(buf.len())
     }
@@ -512,4 +1015,1100 @@ impl io::Write for VirtualFileStream {
     }
 }
 
+impl io::Seek for VirtualFileStream {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let len = self.get_data().borrow().len() as i128;
+        let new_pos = match pos {
+            io::SeekFrom::Start(n) => n as i128,
+            io::SeekFrom::Current(n) => self.pointer_pos as i128 + n as i128,
+            io::SeekFrom::End(n) => len + n as i128,
+        };
+        self.pointer_pos = usize::try_from(new_pos).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot seek before the start of a file",
+            )
+        })?;
+        Ok(new_pos as u64)
+    }
+}
+
 impl FileStream for VirtualFileStream {}
+
+//Overlay File System
+
+///Layers a writable `VirtualFileSystem` over a directory on disk, which is only ever read from.
+/// Files are copied up into the upper layer before they are written to, and removing something which is in the lower layer records a whiteout hiding it.
+/// Clones share their layers, so a clone kept before running a program can export what it changed with `diff`
+#[derive(Clone)]
+pub struct OverlayFileSystem {
+    lower: RealLocalFileSystem,
+    upper: Rc<RefCell<VirtualFileSystem>>,
+    ///Paths hidden in the lower layer, along with everything under them
+    whiteouts: Rc<RefCell<BTreeSet<String>>>,
+}
+
+///Something an `OverlayFileSystem` changed compared to its lower layer. Paths are relative to the root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OverlayChange {
+    ///A file or directory in the lower layer, along with everything under it, was removed or replaced
+    Removed(String),
+    CreatedDir(String),
+    ///A file was created or its contents changed. Holds the new contents
+    WroteFile(String, Vec<u8>),
+}
+
+impl Display for OverlayChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OverlayChange::Removed(path) => write!(f, "removed {}", path),
+            OverlayChange::CreatedDir(path) => write!(f, "created directory {}", path),
+            OverlayChange::WroteFile(path, data) => {
+                write!(f, "wrote {} ({} bytes)", path, data.len())
+            }
+        }
+    }
+}
+
+impl OverlayFileSystem {
+    pub fn new(lower: RealLocalFileSystem) -> OverlayFileSystem {
+        OverlayFileSystem {
+            lower,
+            upper: Rc::new(RefCell::new(VirtualFileSystem::new())),
+            whiteouts: Rc::new(RefCell::new(BTreeSet::new())),
+        }
+    }
+
+    ///Lists what differs from the lower layer: removals first, then what was created or written, each sorted by path
+    pub fn diff(&self) -> Result<Vec<OverlayChange>, io::Error> {
+        let mut changes: Vec<OverlayChange> = self
+            .whiteouts
+            .borrow()
+            .iter()
+            .map(|path| OverlayChange::Removed(path.clone()))
+            .collect();
+        self.diff_upper(&mut self.upper.borrow_mut(), &[], &mut changes)?;
+        Ok(changes)
+    }
+
+    fn diff_upper(
+        &self,
+        upper: &mut VirtualFileSystem,
+        names: &[String],
+        changes: &mut Vec<OverlayChange>,
+    ) -> Result<(), io::Error> {
+        for name in upper.ls(&names.join("/"))? {
+            let child = [names, &[name]].concat();
+            let path = child.join("/");
+            let lower = self.lower_entry(&child);
+            if upper.metadata(&path)?.is_dir {
+                //Directories copied up from the lower layer only matter for what is in them
+                if !lower.is_some_and(|m| m.is_dir) {
+                    changes.push(OverlayChange::CreatedDir(path));
+                }
+                self.diff_upper(upper, &child, changes)?;
+            } else {
+                let mut data = Vec::new();
+                upper.open_file_stream(&path)?.read_to_end(&mut data)?;
+                let unchanged = match lower {
+                    Some(m) if !m.is_dir => self.read_lower(&path)? == data,
+                    _ => false,
+                };
+                if !unchanged {
+                    changes.push(OverlayChange::WroteFile(path, data));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    ///Turns a path into the names leading to it. Like in the other file systems, `..` has to come from a directory which exists
+    fn normalize(&self, path: &str) -> Result<Vec<String>, io::Error> {
+        let mut names: Vec<String> = Vec::new();
+        for segment in VfsPath::parse(path).segments {
+            match segment {
+                PathSegment::Parent => {
+                    if !self.lookup(&names).is_some_and(|m| m.is_dir) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::NotFound,
+                            "Directory not found",
+                        ));
+                    }
+                    names.pop();
+                }
+                PathSegment::Name(name) => names.push(name),
+            }
+        }
+        Ok(names)
+    }
+
+    ///Whether a whiteout on the path or one of its ancestors hides it in the lower layer
+    fn hidden(&self, names: &[String]) -> bool {
+        let whiteouts = self.whiteouts.borrow();
+        (1..=names.len()).any(|i| whiteouts.contains(&names[..i].join("/")))
+    }
+
+    ///What the lower layer has at the path, unless it is hidden
+    fn lower_entry(&self, names: &[String]) -> Option<Metadata> {
+        if self.hidden(names) {
+            return None;
+        }
+        self.lower.metadata(&names.join("/")).ok()
+    }
+
+    ///What is at the path once the layers are combined. The upper layer comes first
+    fn lookup(&self, names: &[String]) -> Option<Metadata> {
+        match self.upper.borrow().metadata(&names.join("/")) {
+            Ok(m) => Some(m),
+            Err(_) => self.lower_entry(names),
+        }
+    }
+
+    fn in_upper(&self, names: &[String]) -> bool {
+        self.upper.borrow().exists(&names.join("/"))
+    }
+
+    fn read_lower(&self, path: &str) -> Result<Vec<u8>, io::Error> {
+        let mut data = Vec::new();
+        self.lower
+            .clone()
+            .open_file_stream(path)?
+            .read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    ///Hides the path in the lower layer. Whiteouts under it are now covered by this one
+    fn white_out(&self, names: &[String]) {
+        let path = names.join("/");
+        let prefix = path.clone() + "/";
+        let mut whiteouts = self.whiteouts.borrow_mut();
+        whiteouts.retain(|w| !w.starts_with(&prefix));
+        whiteouts.insert(path);
+    }
+
+    ///Fails unless the directory the path goes in exists
+    fn check_parent(&self, names: &[String]) -> Result<(), io::Error> {
+        match self.lookup(&names[..names.len() - 1]) {
+            Some(m) if m.is_dir => Ok(()),
+            Some(_) => Err(io::Error::new(
+                io::ErrorKind::NotADirectory,
+                "One of the directories in the path was actually a file",
+            )),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "Directory not found",
+            )),
+        }
+    }
+
+    ///Creates the directories leading to the path in the upper layer, so that something can be put there
+    fn copy_up_parents(&self, names: &[String]) -> Result<(), io::Error> {
+        for i in 1..names.len() {
+            if !self.in_upper(&names[..i]) {
+                self.upper.borrow_mut().create_dir(&names[..i].join("/"))?;
+            }
+        }
+        Ok(())
+    }
+
+    ///Copies the file or directory at the path, along with everything in it, into the upper layer
+    fn copy_up(&self, names: &[String]) -> Result<(), io::Error> {
+        self.copy_up_parents(names)?;
+        let path = names.join("/");
+        match self.lookup(names) {
+            Some(m) if m.is_dir => {
+                if !self.in_upper(names) {
+                    self.upper.borrow_mut().create_dir(&path)?;
+                }
+                for name in self.ls(&path)? {
+                    self.copy_up(&[names, &[name]].concat())?;
+                }
+                Ok(())
+            }
+            Some(_) if self.in_upper(names) => Ok(()),
+            Some(_) => {
+                let data = self.read_lower(&path)?;
+                self.upper
+                    .borrow()
+                    .create_file_stream(&path)?
+                    .write_all(&data)
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+        }
+    }
+}
+
+impl FileSystem for OverlayFileSystem {
+    fn ls(&self, path: &str) -> Result<Vec<String>, io::Error> {
+        let names = self.normalize(path)?;
+        match self.lookup(&names) {
+            Some(m) if m.is_dir => (),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Expected a directory path, found a file path",
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Directory not found",
+                ))
+            }
+        }
+
+        let path = names.join("/");
+        let mut v = match self.upper.borrow().metadata(&path) {
+            Ok(m) if m.is_dir => self.upper.borrow().ls(&path)?,
+            _ => Vec::new(),
+        };
+        if self.lower_entry(&names).is_some_and(|m| m.is_dir) {
+            let whiteouts = self.whiteouts.borrow();
+            for name in self.lower.ls(&path)? {
+                let child = [names.as_slice(), std::slice::from_ref(&name)]
+                    .concat()
+                    .join("/");
+                if !whiteouts.contains(&child) && !v.contains(&name) {
+                    v.push(name);
+                }
+            }
+        }
+        v.sort();
+
+        Ok(v)
+    }
+
+    fn create_file_stream(&self, path: &str) -> Result<Box<dyn FileStream>, io::Error> {
+        let vfs_path = VfsPath::parse(path);
+        if vfs_path.file_name().is_none() {
+            return Err(no_file_name(&vfs_path));
+        }
+        let names = self.normalize(path)?;
+        if self.lookup(&names).is_some_and(|m| m.is_dir) {
+            return Err(io::Error::new(
+                io::ErrorKind::IsADirectory,
+                format!(
+                    "Cannot create a file at \"{}\". It is a directory",
+                    vfs_path
+                ),
+            ));
+        }
+        self.check_parent(&names)?;
+        self.copy_up_parents(&names)?;
+        self.upper.borrow().create_file_stream(&names.join("/"))
+    }
+
+    fn open_file_stream(&mut self, path: &str) -> Result<Box<dyn FileStream>, io::Error> {
+        self.open_file_stream_with(path, FileStreamMode::ReadOnly)
+    }
+
+    ///Reading a file in the lower layer reads it from disk. Any other stream on it copies it up first
+    fn open_file_stream_with(
+        &mut self,
+        path: &str,
+        mode: FileStreamMode,
+    ) -> Result<Box<dyn FileStream>, io::Error> {
+        let names = self.normalize(path)?;
+        let path = names.join("/");
+        if !self.in_upper(&names) {
+            match self.lower_entry(&names) {
+                Some(m) if m.is_dir => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Expected a file path, found a directory path",
+                    ))
+                }
+                Some(_) if !mode.writes() => return self.lower.open_file_stream_with(&path, mode),
+                Some(_) => self.copy_up(&names)?,
+                None => return Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+            }
+        }
+        self.upper.borrow_mut().open_file_stream_with(&path, mode)
+    }
+
+    fn remove_file(&mut self, path: &str) -> Result<(), io::Error> {
+        let names = self.normalize(path)?;
+        match self.lookup(&names) {
+            Some(m) if m.is_dir => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    "Expected a file path, found a directory path",
+                ))
+            }
+            Some(_) => (),
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+        if self.in_upper(&names) {
+            self.upper.borrow_mut().remove_file(&names.join("/"))?;
+        }
+        if self.lower_entry(&names).is_some() {
+            self.white_out(&names);
+        }
+        Ok(())
+    }
+
+    fn create_dir(&mut self, path: &str) -> Result<(), io::Error> {
+        let vfs_path = VfsPath::parse(path);
+        if vfs_path.file_name().is_none() {
+            return Err(no_file_name(&vfs_path));
+        }
+        let names = self.normalize(path)?;
+        if self.lookup(&names).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("\"{}\" already exists", vfs_path),
+            ));
+        }
+        self.check_parent(&names)?;
+        self.copy_up_parents(&names)?;
+        self.upper.borrow_mut().create_dir(&names.join("/"))
+    }
+
+    fn remove_dir(&mut self, path: &str, recursive: bool) -> Result<(), io::Error> {
+        let names = self.normalize(path)?;
+        if names.is_empty() {
+            return Err(root_error());
+        }
+        match self.lookup(&names) {
+            Some(m) if m.is_dir => (),
+            Some(_) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    "Expected a directory path, found a file path",
+                ))
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "Directory not found",
+                ))
+            }
+        }
+        if !recursive && !self.ls(path)?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::DirectoryNotEmpty,
+                format!("\"{}\" is not empty", path),
+            ));
+        }
+        if self.in_upper(&names) {
+            self.upper.borrow_mut().remove_dir(&names.join("/"), true)?;
+        }
+        if self.lower_entry(&names).is_some() {
+            self.white_out(&names);
+        }
+        Ok(())
+    }
+
+    ///Copies what is being moved into the upper layer and moves it there, hiding it in the lower layer
+    fn rename(&mut self, from: &str, to: &str) -> Result<(), io::Error> {
+        let from_names = self.normalize(from)?;
+        let node = match self.lookup(&from_names) {
+            Some(n) => n,
+            None => return Err(io::Error::new(io::ErrorKind::NotFound, "File not found")),
+        };
+        if from_names.is_empty() {
+            return Err(root_error());
+        }
+
+        let to_path = VfsPath::parse(to);
+        if to_path.file_name().is_none() {
+            return Err(no_file_name(&to_path));
+        }
+        let to_names = self.normalize(to)?;
+        self.check_parent(&to_names)?;
+        if to_names == from_names {
+            return Ok(());
+        }
+        //A directory can't be moved inside of itself
+        if to_names.starts_with(&from_names) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Cannot move \"{}\" inside of itself", from),
+            ));
+        }
+
+        //Like on a real file system, a file can replace a file and a directory can replace an empty directory
+        match (node.is_dir, self.lookup(&to_names)) {
+            (false, Some(existing)) if existing.is_dir => {
+                return Err(io::Error::new(
+                    io::ErrorKind::IsADirectory,
+                    format!("\"{}\" is a directory", to_path),
+                ))
+            }
+            (true, Some(existing)) if !existing.is_dir => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotADirectory,
+                    format!("\"{}\" is not a directory", to_path),
+                ))
+            }
+            (true, Some(_)) if !self.ls(to)?.is_empty() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::DirectoryNotEmpty,
+                    format!("\"{}\" is not empty", to_path),
+                ))
+            }
+            _ => (),
+        }
+
+        self.copy_up(&from_names)?;
+        self.copy_up_parents(&to_names)?;
+        self.upper
+            .borrow_mut()
+            .rename(&from_names.join("/"), &to_names.join("/"))?;
+        if self.lower_entry(&from_names).is_some() {
+            self.white_out(&from_names);
+        }
+        Ok(())
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        match self.normalize(path) {
+            Ok(names) => self.lookup(&names).is_some(),
+            Err(_) => false,
+        }
+    }
+
+    fn metadata(&self, path: &str) -> Result<Metadata, io::Error> {
+        let names = self.normalize(path)?;
+        self.lookup(&names)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "File not found"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{self, SeekFrom},
+        path::PathBuf,
+    };
+
+    use super::{
+        FileStreamMode, FileSystem, Metadata, OverlayChange, OverlayFileSystem,
+        RealLocalFileSystem, VfsPath, VirtualFileSystem,
+    };
+    use crate::{
+        emit::tests::{build_and_run, tool_available, SharedBuffer},
+        emit_c::emit_c,
+        interpreter::{run_program, tokenize, RunOptions},
+        optimize::optimize,
+        tar::{write_tar, TarEntry},
+        value::NumericModel,
+    };
+
+    ///Makes a fresh directory holding `root/inside.txt`, `root/sub/` and `outside/secret.txt`, returning it and a file system rooted at `root`
+    fn sandbox(name: &str) -> (PathBuf, RealLocalFileSystem) {
+        let dir = std::env::temp_dir().join(format!("staq-vfs-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("root/sub")).unwrap();
+        fs::create_dir_all(dir.join("outside")).unwrap();
+        fs::write(dir.join("root/inside.txt"), "inside").unwrap();
+        fs::write(dir.join("outside/secret.txt"), "secret").unwrap();
+
+        let root = dir.join("root").to_string_lossy().to_string() + "/";
+        (dir, RealLocalFileSystem { root })
+    }
+
+    fn read(fs: &mut dyn FileSystem, path: &str) -> io::Result<String> {
+        let mut s = String::new();
+        fs.open_file_stream(path)?.read_to_string(&mut s)?;
+        Ok(s)
+    }
+
+    #[test]
+    fn traversal_stays_in_root() {
+        let (dir, mut fs) = sandbox("traversal");
+
+        assert_eq!(read(&mut fs, "./sub/../inside.txt").unwrap(), "inside");
+        //`..` stops at the root, and absolute paths start at it
+        assert_eq!(read(&mut fs, "../../inside.txt").unwrap(), "inside");
+        assert_eq!(read(&mut fs, "/inside.txt").unwrap(), "inside");
+        for path in ["../outside/secret.txt", "sub/../../outside/secret.txt"] {
+            let not_found = |e: io::Error| e.kind() == io::ErrorKind::NotFound;
+            assert!(read(&mut fs, path).is_err_and(not_found), "{}", path);
+            assert!(fs.remove_file(path).is_err_and(not_found), "{}", path);
+        }
+        assert!(fs
+            .ls("sub/../..")
+            .unwrap()
+            .contains(&"inside.txt".to_string()));
+
+        fs.create_file_stream("../escaped.txt").unwrap();
+        assert!(dir.join("root/escaped.txt").exists());
+        assert!(!dir.join("escaped.txt").exists());
+        assert!(dir.join("outside/secret.txt").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escapes() {
+        use std::os::unix::fs::symlink;
+
+        let (dir, mut fs) = sandbox("symlinks");
+        symlink(dir.join("outside"), dir.join("root/out")).unwrap();
+        symlink(dir.join("outside/secret.txt"), dir.join("root/secret.txt")).unwrap();
+        symlink(dir.join("outside/new.txt"), dir.join("root/dangling.txt")).unwrap();
+        symlink(dir.join("root/inside.txt"), dir.join("root/sub/link.txt")).unwrap();
+
+        let denied = |e: io::Error| e.kind() == io::ErrorKind::PermissionDenied;
+        assert!(read(&mut fs, "out/secret.txt").is_err_and(denied));
+        assert!(read(&mut fs, "secret.txt").is_err_and(denied));
+        assert!(fs.ls("out").is_err_and(denied));
+        assert!(fs.create_file_stream("out/new.txt").is_err_and(denied));
+        assert!(fs.create_file_stream("dangling.txt").is_err_and(denied));
+        assert!(!dir.join("outside/new.txt").exists());
+
+        //Symlinks which stay inside the root still work
+        assert_eq!(read(&mut fs, "sub/link.txt").unwrap(), "inside");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn interpreter_gets_failure_status() {
+        let (dir, fs) = sandbox("interpreter");
+
+        let output = SharedBuffer::default();
+        run_program(
+            tokenize(
+                "openfilestream:../outside/secret.txt printnum\nopenfilestream:inside.txt printnum",
+            ),
+            Box::new(fs),
+            RunOptions {
+                output: Some(Box::new(output.clone())),
+                ..Default::default()
+            },
+        );
+        assert_eq!(output.0.borrow().as_slice(), b"-11");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parses_paths() {
+        assert_eq!(VfsPath::parse("/a/./b//c/"), VfsPath::parse("a/b/c"));
+        assert_eq!(VfsPath::parse("a/../b").to_string(), "/a/../b");
+        assert_eq!(VfsPath::parse("a/..").file_name(), None);
+        assert_eq!(VfsPath::parse(".").file_name(), None);
+        assert_eq!(VfsPath::parse("a/b").parent(), VfsPath::parse("a"));
+    }
+
+    ///A real, a virtual and an overlay file system, each holding "top.txt" and "sub/a.txt".
+    /// The real one is rooted at "real" in the returned directory, and the overlay's files are in the lower layer at "lower"
+    fn backends(name: &str) -> (PathBuf, Vec<Box<dyn FileSystem>>) {
+        let dir = std::env::temp_dir().join(format!("staq-vfs-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let local = |name: &str| {
+            fs::create_dir_all(dir.join(name).join("sub")).unwrap();
+            RealLocalFileSystem {
+                root: dir.join(name).to_string_lossy().to_string() + "/",
+            }
+        };
+        let real = local("real");
+        let lower = local("lower");
+
+        let mut virt = VirtualFileSystem::new();
+        virt.create_dir("sub").unwrap();
+
+        let seeded: [&dyn FileSystem; 3] = [&real, &virt, &lower];
+        for fs in seeded {
+            for (path, contents) in [("top.txt", "top"), ("sub/a.txt", "a")] {
+                fs.create_file_stream(path)
+                    .unwrap()
+                    .write_all(contents.as_bytes())
+                    .unwrap();
+            }
+        }
+        let overlay = OverlayFileSystem::new(lower);
+        (dir, vec![Box::new(real), Box::new(virt), Box::new(overlay)])
+    }
+
+    ///Runs a script on the file system, returning what it printed
+    fn run_on(fs: Box<dyn FileSystem>, source: &str) -> String {
+        let output = SharedBuffer::default();
+        run_program(
+            tokenize(source),
+            fs,
+            RunOptions {
+                output: Some(Box::new(output.clone())),
+                ..Default::default()
+            },
+        );
+        let out = output.0.borrow().clone();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn backends_agree_on_paths() {
+        let (dir, mut backends) = backends("conformance");
+
+        let reads = [
+            ("top.txt", Some("top")),
+            ("/top.txt", Some("top")),
+            ("./top.txt", Some("top")),
+            ("sub/./a.txt", Some("a")),
+            ("sub//a.txt", Some("a")),
+            ("sub/../top.txt", Some("top")),
+            ("../top.txt", Some("top")),
+            ("sub/../../sub/a.txt", Some("a")),
+            ("missing/../top.txt", None),
+            ("sub/a.txt/../a.txt", None),
+            ("sub", None),
+            ("sub/..", None),
+            ("missing.txt", None),
+        ];
+        let listings = [
+            ("", Some(vec!["sub", "top.txt"])),
+            ("/..", Some(vec!["sub", "top.txt"])),
+            ("sub/.", Some(vec!["a.txt"])),
+            ("top.txt", None),
+            ("missing", None),
+        ];
+
+        for (i, fs) in backends.iter_mut().enumerate() {
+            let mut read = |path: &str| -> Option<String> {
+                let mut s = String::new();
+                fs.open_file_stream(path)
+                    .ok()?
+                    .read_to_string(&mut s)
+                    .ok()?;
+                Some(s)
+            };
+            for (path, expected) in reads {
+                assert_eq!(read(path).as_deref(), expected, "backend {} {}", i, path);
+            }
+
+            for (path, expected) in &listings {
+                let mut names = fs.ls(path).ok();
+                if let Some(names) = &mut names {
+                    names.sort();
+                }
+                let expected: Option<Vec<String>> = expected
+                    .as_ref()
+                    .map(|e| e.iter().map(|s| s.to_string()).collect());
+                assert_eq!(names, expected, "backend {} {}", i, path);
+            }
+
+            //Files can only be created in directories which exist, and need a name
+            assert!(fs.create_file_stream("sub/../new.txt").is_ok());
+            assert!(fs.ls("").unwrap().contains(&"new.txt".to_string()));
+            for path in ["missing/new.txt", "top.txt/new.txt", "sub/..", "", "sub"] {
+                assert!(
+                    fs.create_file_stream(path).is_err(),
+                    "backend {} {}",
+                    i,
+                    path
+                );
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_run_scripts_alike() {
+        let source = "createfilestream:sub/../out.txt printnum push:33 push:105 push:104 writefilestream printnum\nopenfilestream:/sub/../../out.txt printnum readfilestream printnum readfilestream printnum\nopenfilestream:sub/a.txt/.. printnum\ncreatefilestream:missing/x.txt printnum";
+        let (dir, backends) = backends("scripts");
+
+        let outputs: Vec<String> = backends.into_iter().map(|fs| run_on(fs, source)).collect();
+        assert_eq!(outputs[0], "11111041105-1-1");
+        assert_eq!(outputs[0], outputs[1]);
+        assert_eq!(outputs[0], outputs[2]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn create_truncates_in_place() {
+        //A file which is being read from is created again
+        let mut fs = VirtualFileSystem::new();
+        let mut writer = fs.create_file_stream("data.txt").unwrap();
+        let mut reader = fs.open_file_stream("data.txt").unwrap();
+        writer.write_all(b"old data").unwrap();
+
+        let mut rewriter = fs.create_file_stream("data.txt").unwrap();
+        assert_eq!(fs.ls("").unwrap(), vec!["data.txt"]);
+        let mut s = String::new();
+        reader.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "");
+
+        //The stream opened before sees what is written after the truncation
+        rewriter.write_all(b"new").unwrap();
+        reader.read_to_string(&mut s).unwrap();
+        assert_eq!(s, "new");
+        assert_eq!(read(&mut fs, "data.txt").unwrap(), "new");
+    }
+
+    #[test]
+    fn create_refuses_directories() {
+        let mut fs = VirtualFileSystem::new();
+        fs.create_dir("sub").unwrap();
+        fs.create_file_stream("sub/a.txt").unwrap();
+
+        assert!(fs
+            .create_file_stream("sub")
+            .is_err_and(|e| e.kind() == io::ErrorKind::IsADirectory));
+        assert!(fs
+            .create_dir("sub")
+            .is_err_and(|e| e.kind() == io::ErrorKind::AlreadyExists));
+        assert_eq!(fs.ls("").unwrap(), vec!["sub"]);
+        assert_eq!(fs.ls("sub").unwrap(), vec!["a.txt"]);
+    }
+
+    #[test]
+    fn backends_recreate_files_alike() {
+        let source = "createfilestream:data.txt pop:C push:97 writefilestream\ncreatefilestream:data.txt pop:C push:98 writefilestream\nopenfilestream:data.txt pop:C readfilestream pop:C printnum readfilestream pop:C printnum\ncreatefilestream:sub printnum";
+        let (dir, backends) = backends("recreate");
+
+        for fs in backends {
+            assert_eq!(run_on(fs, source), "98-1-1");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_start_without_streams() {
+        let source = "readfilestream printnum writefilestream printnum tellfilestream printnum";
+        let (dir, backends) = backends("unopened");
+
+        for fs in backends {
+            assert_eq!(run_on(fs, source), "-1-1-1");
+        }
+
+        //Running leaves nothing behind in the real directories
+        for root in ["real", "lower"] {
+            let mut names: Vec<_> = fs::read_dir(dir.join(root))
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            assert_eq!(names, ["sub", "top.txt"]);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn overlay_keeps_lower_layer_and_exports_diff() {
+        let (dir, lower) = sandbox("overlay");
+        fs::write(dir.join("root/top.txt"), "top").unwrap();
+        fs::write(dir.join("root/sub/a.txt"), "a").unwrap();
+        fs::create_dir_all(dir.join("root/old/deep")).unwrap();
+        fs::write(dir.join("root/old/deep/x.txt"), "x").unwrap();
+        let overlay = OverlayFileSystem::new(lower);
+        let mut fs = overlay.clone();
+
+        let mut f = fs
+            .open_file_stream_with("top.txt", FileStreamMode::ReadWrite)
+            .unwrap();
+        f.write_all(b"T").unwrap();
+        drop(f);
+        //Copied up, but left as it was
+        fs.open_file_stream_with("inside.txt", FileStreamMode::ReadWrite)
+            .unwrap();
+        fs.remove_file("sub/a.txt").unwrap();
+        fs.remove_file("old/deep/x.txt").unwrap();
+        fs.remove_dir("old", true).unwrap();
+        fs.create_dir("new").unwrap();
+        fs.create_file_stream("new/b.txt")
+            .unwrap()
+            .write_all(b"b")
+            .unwrap();
+
+        assert_eq!(read(&mut fs, "top.txt").unwrap(), "Top");
+        assert_eq!(fs.ls("").unwrap(), ["inside.txt", "new", "sub", "top.txt"]);
+        assert!(fs.ls("sub").unwrap().is_empty());
+        assert!(!fs.exists("old/deep"));
+
+        //Nothing on disk changed
+        assert_eq!(fs::read_to_string(dir.join("root/top.txt")).unwrap(), "top");
+        assert!(dir.join("root/sub/a.txt").exists());
+        assert!(dir.join("root/old/deep/x.txt").exists());
+        assert!(!dir.join("root/new").exists());
+
+        assert_eq!(
+            overlay.diff().unwrap(),
+            [
+                OverlayChange::Removed("old".to_string()),
+                OverlayChange::Removed("sub/a.txt".to_string()),
+                OverlayChange::CreatedDir("new".to_string()),
+                OverlayChange::WroteFile("new/b.txt".to_string(), b"b".to_vec()),
+                OverlayChange::WroteFile("top.txt".to_string(), b"Top".to_vec()),
+            ]
+        );
+
+        //Something removed and made again is both removed and written
+        fs.create_file_stream("sub/a.txt").unwrap();
+        assert!(overlay.diff().unwrap().contains(&OverlayChange::WroteFile(
+            "sub/a.txt".to_string(),
+            Vec::new()
+        )));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let (dir, _) = sandbox("snapshots");
+        let mut fs = VirtualFileSystem::from_dir(dir.join("root")).unwrap();
+        assert_eq!(fs.ls("").unwrap(), ["inside.txt", "sub"]);
+        assert_eq!(read(&mut fs, "inside.txt").unwrap(), "inside");
+
+        fs.create_dir("sub/deeper").unwrap();
+        fs.create_file_stream("sub/deeper/z.txt")
+            .unwrap()
+            .write_all(b"z")
+            .unwrap();
+        let mut archive = Vec::new();
+        fs.to_tar(&mut archive).unwrap();
+
+        let mut loaded = VirtualFileSystem::from_tar(archive.as_slice()).unwrap();
+        assert_eq!(loaded.ls("sub").unwrap(), ["deeper"]);
+        assert_eq!(read(&mut loaded, "sub/deeper/z.txt").unwrap(), "z");
+        let mut again = Vec::new();
+        loaded.to_tar(&mut again).unwrap();
+        assert_eq!(archive, again);
+
+        loaded.to_dir(dir.join("out")).unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("out/sub/deeper/z.txt")).unwrap(),
+            "z"
+        );
+        assert_eq!(
+            fs::read_to_string(dir.join("out/inside.txt")).unwrap(),
+            "inside"
+        );
+
+        //Directories are made for files which need them, but nothing can go up out of the archive
+        let entries = [TarEntry::File("a/b/c.txt".to_string(), b"c".to_vec())];
+        let mut archive = Vec::new();
+        write_tar(&mut archive, &entries).unwrap();
+        let mut loaded = VirtualFileSystem::from_tar(archive.as_slice()).unwrap();
+        assert_eq!(read(&mut loaded, "a/b/c.txt").unwrap(), "c");
+        for entries in [
+            [TarEntry::File("../escape.txt".to_string(), Vec::new())],
+            [TarEntry::Dir("a/../../b".to_string())],
+        ] {
+            let mut archive = Vec::new();
+            write_tar(&mut archive, &entries).unwrap();
+            assert!(VirtualFileSystem::from_tar(archive.as_slice()).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_manage_directories_alike() {
+        let (dir, mut backends) = backends("directories");
+
+        for (i, fs) in backends.iter_mut().enumerate() {
+            let check = |result: io::Result<()>, expected: bool, what: &str| {
+                assert_eq!(result.is_ok(), expected, "backend {} {}", i, what);
+            };
+
+            check(fs.create_dir("new"), true, "create");
+            check(fs.create_dir("new"), false, "create again");
+            check(fs.create_dir("missing/new"), false, "create in missing");
+            check(fs.create_dir("top.txt/new"), false, "create in a file");
+            check(fs.create_dir("sub/.."), false, "create without a name");
+            assert!(fs.exists("new") && fs.exists("sub/../new") && fs.exists(""));
+            assert!(!fs.exists("missing") && !fs.exists("top.txt/a.txt"));
+
+            assert_eq!(
+                fs.metadata("top.txt").ok(),
+                Some(Metadata {
+                    size: 3,
+                    is_dir: false
+                }),
+                "backend {}",
+                i
+            );
+            assert_eq!(
+                fs.metadata("sub").ok(),
+                Some(Metadata {
+                    size: 0,
+                    is_dir: true
+                }),
+                "backend {}",
+                i
+            );
+            assert!(fs.metadata("missing").is_err());
+
+            //Files replace files, and directories replace empty directories, but nothing else is replaced
+            fs.create_file_stream("sub/b.txt").unwrap();
+            check(fs.rename("sub/b.txt", "sub/a.txt"), true, "file over file");
+            assert_eq!(read(&mut **fs, "sub/a.txt").unwrap(), "", "backend {}", i);
+            check(fs.rename("top.txt", "new"), false, "file over directory");
+            check(fs.rename("new", "top.txt"), false, "directory over file");
+            check(fs.rename("new", "sub"), false, "over a full directory");
+            check(fs.rename("sub", "sub/inner"), false, "into itself");
+            check(fs.rename("", "elsewhere"), false, "the root");
+            check(fs.rename("missing", "elsewhere"), false, "missing");
+            check(
+                fs.rename("top.txt", "missing/top.txt"),
+                false,
+                "into missing",
+            );
+            check(fs.rename("sub", "new/sub"), true, "directory");
+            assert_eq!(
+                read(&mut **fs, "new/sub/a.txt").unwrap(),
+                "",
+                "backend {}",
+                i
+            );
+            check(fs.rename("new/sub", "sub"), true, "directory back");
+            check(fs.rename("top.txt", "sub/../top.txt"), true, "onto itself");
+
+            check(fs.remove_file("sub"), false, "remove a directory as a file");
+            check(
+                fs.remove_dir("top.txt", false),
+                false,
+                "remove a file as a directory",
+            );
+            check(
+                fs.remove_dir("sub", false),
+                false,
+                "remove a full directory",
+            );
+            check(fs.remove_dir("sub/..", true), false, "remove the root");
+            check(fs.remove_dir("sub", true), true, "remove recursively");
+            check(
+                fs.remove_dir("new", false),
+                true,
+                "remove an empty directory",
+            );
+            check(fs.remove_dir("new", false), false, "remove again");
+
+            let mut names = fs.ls("").unwrap();
+            names.sort();
+            assert_eq!(names, vec!["top.txt"], "backend {}", i);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_run_file_examples_alike() {
+        let examples = [
+            (
+                "directories",
+                include_str!("../examples/directories.stq"),
+                "1-1\n12-1\n1-11\n-1-1\n1\n11-1\n12970980\n12970980-1\n",
+            ),
+            (
+                "records",
+                include_str!("../examples/records.stq"),
+                "111\n12\n-11d\n11\naXcde\n-1-1\n",
+            ),
+            (
+                "handles",
+                include_str!("../examples/handles.stq"),
+                "121314\n1-1-11\n12\naxby\n1112b\n111h\n",
+            ),
+        ];
+
+        //Each example gets fresh file systems, since they leave files behind
+        for (name, source, expected) in examples {
+            let (dir, backends) = backends(&format!("{}-example", name));
+            for fs in backends {
+                assert_eq!(run_on(fs, source), expected, "{}", name);
+            }
+            fs::remove_dir_all(&dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn backends_list_names_as_bytes() {
+        //"ž" is 2 bytes. Paths are popped as bytes, so it can be moved to "out" by the name listdir pushed
+        let source = "mkdir:in createfilestream:in/ž push:122 writefilestream closefilestream:1\npush:116 push:117 push:111 listdir:in pop:C pop:C push:47 push:110 push:105 rename printnum\nexists:out printnum openfilestream:out pop:C readfilestream printnum";
+        let (dir, backends) = backends("bytes");
+
+        for fs in backends {
+            assert_eq!(run_on(fs, source), "111122");
+        }
+
+        //Generated C reads paths the same way
+        if tool_available("cc") {
+            let mut program = tokenize(source);
+            optimize(&mut program, 0, NumericModel::Wrap64);
+            let (output, ok) = build_and_run(
+                "c-list-bytes",
+                "main.c",
+                &emit_c(&program),
+                &["cc", "-O2", "-std=c99", "main.c", "-o", "main"],
+                "",
+            );
+            assert!(ok);
+            assert_eq!(output, "111122");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_create_files_from_bytes() {
+        //The bytes of "ž", popped first byte first, so `createfile` names the file like every other file command
+        let source = "push:190 push:197 createfile printnum\nexists:ž printnum";
+        let (dir, backends) = backends("create-bytes");
+
+        for fs in backends {
+            assert_eq!(run_on(fs, source), "11");
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn backends_seek_and_share_streams_alike() {
+        let (dir, mut backends) = backends("streams");
+
+        for (i, fs) in backends.iter_mut().enumerate() {
+            let fs = fs.as_mut();
+
+            //Overwrite in place, then write past the end, which fills the gap with zeroes
+            let mut f = fs
+                .open_file_stream_with("top.txt", FileStreamMode::ReadWrite)
+                .unwrap();
+            assert_eq!(f.seek(SeekFrom::Start(1)).unwrap(), 1);
+            f.write_all(b"a").unwrap();
+            let mut rest = String::new();
+            f.read_to_string(&mut rest).unwrap();
+            assert_eq!(rest, "p", "backend {}", i);
+            assert_eq!(f.seek(SeekFrom::End(2)).unwrap(), 5);
+            f.write_all(b"!").unwrap();
+            assert_eq!(f.stream_position().unwrap(), 6);
+            assert!(f.seek(SeekFrom::Current(-7)).is_err(), "backend {}", i);
+            drop(f);
+            assert_eq!(read(fs, "top.txt").unwrap(), "tap\0\0!");
+
+            //Appending ignores where the stream was moved to
+            let mut f = fs
+                .open_file_stream_with("sub/a.txt", FileStreamMode::Append)
+                .unwrap();
+            f.seek(SeekFrom::Start(0)).unwrap();
+            f.write_all(b"bc").unwrap();
+            drop(f);
+            assert_eq!(read(fs, "sub/a.txt").unwrap(), "abc");
+
+            //Streams on existing files neither create nor truncate, and keep to their mode
+            for mode in [FileStreamMode::Append, FileStreamMode::ReadWrite] {
+                assert!(fs.open_file_stream_with("missing.txt", mode).is_err());
+                assert!(fs.open_file_stream_with("sub", mode).is_err());
+            }
+            let mut f = fs
+                .open_file_stream_with("sub/a.txt", FileStreamMode::Append)
+                .unwrap();
+            assert!(f.read(&mut [0]).is_err(), "backend {}", i);
+            assert!(fs
+                .open_file_stream("sub/a.txt")
+                .unwrap()
+                .write(b"x")
+                .is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}